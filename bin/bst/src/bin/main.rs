@@ -27,8 +27,13 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Stat try to list stacks under dir
-    Stat { path: Option<String> },
+    /// Stat try to list stacks under dir, or show a record with index_id
+    Stat {
+        path: Option<String>,
+        /// index_id of the record to show, like 1,a90007cc79976
+        #[arg(short = 'i', long = "index_id")]
+        index_id: Option<String>,
+    },
 
     /// LS try to list all file in a stack
     LS { path: Option<String> },
@@ -38,6 +43,9 @@ enum Commands {
         /// index_id is given by ls, the unique way to access data, like 1,a90007cc79976
         #[arg(short = 'i', long = "index_id")]
         index_id: Option<String>,
+        /// name is the filename given by put, used if index_id is not given
+        #[arg(short = 'n', long = "name")]
+        name: Option<String>,
         /// path: where to find stacks
         #[arg(long = "path")]
         path: Option<String>,
@@ -47,6 +55,46 @@ enum Commands {
         /// consistency issues are commonly solved by underlying storage, this check is closed usually
         #[arg(short = 'c', long = "check_crc", default_value = "false")]
         check_crc: Option<bool>,
+        /// range of data to fetch like 0-1024 (end excluded), only works with index_id
+        #[arg(short = 'r', long = "range")]
+        range: Option<String>,
+    },
+
+    /// Rm mark a record as deleted, data is kept in the stack until space is reclaimed
+    Rm {
+        /// index_id is given by ls, like 1,a90007cc79976
+        #[arg(short = 'i', long = "index_id")]
+        index_id: Option<String>,
+        /// path: where to find stacks
+        #[arg(long = "path")]
+        path: Option<String>,
+    },
+
+    /// Verify check stacks under path and print every problem found, exit with 1 if any stack is unhealthy
+    Verify {
+        /// path: where to find stacks
+        #[arg(long = "path")]
+        path: Option<String>,
+        /// stack_id to verify, all stacks under path are verified if it is not given
+        #[arg(long = "stack-id")]
+        stack_id: Option<u64>,
+        /// level is one of headers, records and full, full reads the whole data file to check checksum
+        #[arg(short = 'l', long = "level", default_value = "records")]
+        level: Option<String>,
+    },
+
+    /// Repair rebuild index, meta and filename index of a stack from its data file, skipping corrupted
+    /// regions. It only prints what would be rebuilt unless apply is given
+    Repair {
+        /// path: where to find stacks
+        #[arg(long = "path")]
+        path: Option<String>,
+        /// stack_id to repair
+        #[arg(long = "stack-id")]
+        stack_id: Option<u64>,
+        /// apply writes rebuilt files, otherwise repair is a dry run
+        #[arg(long = "apply", default_value = "false")]
+        apply: Option<bool>,
     },
 
     /// Bind stack-id to some source
@@ -83,7 +131,7 @@ async fn main() {
 
     let mut handler = bytestack::sdk::Handler::new(cfg).await;
     match &cli.command {
-        Commands::Stat { path } => {
+        Commands::Stat { path, index_id } => {
             let path = match path {
                 Some(p) => p,
                 None => {
@@ -91,6 +139,17 @@ async fn main() {
                     exit(1);
                 }
             };
+            if let Some(index_id) = index_id {
+                let reader = handler.open_reader(path).unwrap();
+                match reader.stat(index_id).await {
+                    Ok((ir, mr)) => println!("{:?}\n{:?}", ir, mr),
+                    Err(e) => {
+                        error!("stat {} error {:?}", index_id, e);
+                        exit(1);
+                    }
+                }
+                return;
+            }
             info!("run stat on {path:?}");
             let reader = handler.open_reader(path).unwrap();
             let out = match reader.list_al().await {
@@ -135,16 +194,11 @@ async fn main() {
         Commands::Get {
             path,
             index_id,
+            name,
             target,
             check_crc,
+            range,
         } => {
-            let index_id = match index_id {
-                Some(id) => id,
-                None => {
-                    error!("index_id is needed");
-                    exit(1);
-                }
-            };
             let path = match path {
                 Some(p) => p,
                 None => {
@@ -153,10 +207,42 @@ async fn main() {
                 }
             };
             let reader = handler.open_reader(path).unwrap();
-            let data = match reader.fetch(index_id, check_crc.unwrap()).await {
+            let range = range.as_ref().map(|range| {
+                match range.split_once('-').and_then(|(start, end)| {
+                    Some(start.parse::<u64>().ok()?..end.parse::<u64>().ok()?)
+                }) {
+                    Some(range) => range,
+                    None => {
+                        error!("invalid range {}, it should be like 0-1024", range);
+                        exit(1);
+                    }
+                }
+            });
+            let res = match (index_id, name, range) {
+                (Some(index_id), _, Some(range)) => {
+                    reader
+                        .fetch_range(index_id, range, check_crc.unwrap())
+                        .await
+                }
+                (None, _, Some(_)) => {
+                    error!("range needs index_id");
+                    exit(1);
+                }
+                (Some(index_id), _, None) => reader.fetch(index_id, check_crc.unwrap()).await,
+                (None, Some(name), None) => reader.fetch_by_name(name, check_crc.unwrap()).await,
+                (None, None, None) => {
+                    error!("index_id or name is needed");
+                    exit(1);
+                }
+            };
+            let data = match res {
                 Ok(res) => res,
                 Err(e) => {
-                    error!("fetch {} error {:?}", index_id, e);
+                    error!(
+                        "fetch {} error {:?}",
+                        index_id.as_ref().or(name.as_ref()).unwrap(),
+                        e
+                    );
                     exit(1);
                 }
             };
@@ -173,6 +259,132 @@ async fn main() {
                 let _ = fd.write(&data);
             }
         }
+        Commands::Rm { path, index_id } => {
+            let index_id = match index_id {
+                Some(id) => id,
+                None => {
+                    error!("index_id is needed");
+                    exit(1);
+                }
+            };
+            let path = match path {
+                Some(p) => p,
+                None => {
+                    error!("path is needed");
+                    exit(1);
+                }
+            };
+            match handler.delete(path, index_id).await {
+                Ok(()) => info!("{} deleted", index_id),
+                Err(e) => {
+                    error!("delete {} error {:?}", index_id, e);
+                    exit(1);
+                }
+            };
+        }
+        Commands::Verify {
+            path,
+            stack_id,
+            level,
+        } => {
+            let path = match path {
+                Some(p) => p,
+                None => {
+                    error!("path is needed");
+                    exit(1);
+                }
+            };
+            let level = match level.as_deref() {
+                Some("headers") => bytestack::sdk::VerifyLevel::Headers,
+                Some("records") => bytestack::sdk::VerifyLevel::Records,
+                Some("full") => bytestack::sdk::VerifyLevel::Full,
+                _ => {
+                    error!("level should be one of headers, records and full");
+                    exit(1);
+                }
+            };
+            let reader = handler.open_reader(path).unwrap();
+            let stack_ids = match stack_id {
+                Some(stack_id) => vec![*stack_id],
+                None => match reader.list().await {
+                    Ok(stack_ids) => stack_ids,
+                    Err(e) => {
+                        error!("list stack error: {}", e);
+                        exit(1);
+                    }
+                },
+            };
+            let mut unhealthy = 0;
+            for stack_id in stack_ids {
+                let report = match reader.verify_stack(stack_id, level).await {
+                    Ok(report) => report,
+                    Err(e) => {
+                        error!("verify stack {} error {:?}", stack_id, e);
+                        exit(1);
+                    }
+                };
+                if report.is_healthy() {
+                    info!(
+                        "stack {} is healthy, {} records checksummed by {}",
+                        stack_id,
+                        report.records,
+                        report.checksum.name()
+                    );
+                    continue;
+                }
+                unhealthy += 1;
+                for problem in report.problems {
+                    println!("{}\t{}\t{}", problem.file, problem.offset, problem.message);
+                }
+            }
+            if unhealthy > 0 {
+                error!("{} stacks are unhealthy", unhealthy);
+                exit(1);
+            }
+        }
+        Commands::Repair {
+            path,
+            stack_id,
+            apply,
+        } => {
+            let path = match path {
+                Some(p) => p,
+                None => {
+                    error!("path is needed");
+                    exit(1);
+                }
+            };
+            let stack_id = match stack_id {
+                Some(id) => *id,
+                None => {
+                    error!("stack_id is needed");
+                    exit(1);
+                }
+            };
+            let dry_run = !apply.unwrap_or(false);
+            let report = match handler.repair_stack(path, stack_id, dry_run).await {
+                Ok(report) => report,
+                Err(e) => {
+                    error!("repair stack {} error {:?}", stack_id, e);
+                    exit(1);
+                }
+            };
+            for region in &report.skipped_regions {
+                println!("skipped\t{}\t{}", region.start, region.end);
+            }
+            println!("recovered_records\t{}", report.recovered_records);
+            println!("meta_placeholders\t{}", report.meta_placeholders);
+            println!("data_sealed\t{}", report.data_sealed);
+            println!("data_bytes\t{}", report.data_bytes);
+            println!("index_changed\t{}", report.index_changed);
+            println!("meta_changed\t{}", report.meta_changed);
+            println!("names_rebuilt\t{}", report.names_rebuilt);
+            if dry_run {
+                info!("dry run, nothing is written, repair again with --apply true to write");
+            } else {
+                info!("stack {} is repaired", stack_id);
+            }
+        }
         Commands::Bind {
             stack_id,
             path,
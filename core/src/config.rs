@@ -1,6 +1,7 @@
 use super::sdk::err::{CustomError, ErrorKind};
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct S3 {
@@ -18,3 +19,34 @@ impl S3 {
         }
     }
 }
+
+/// Encryption configures encryption at rest, keys are looked up by the key id saved in every stack.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Encryption {
+    /// cipher encrypts new stacks, "aes-256-gcm" or "chacha20-poly1305", empty means new stacks are not encrypted
+    #[serde(default)]
+    pub cipher: String,
+    /// key_id of the key which encrypts new stacks
+    #[serde(default)]
+    pub key_id: u32,
+    /// encrypt_meta encrypts filename and extra of MetaRecords too
+    #[serde(default)]
+    pub encrypt_meta: bool,
+    /// keys are hex encoded 32 bytes keys by key id
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    /// key_file is a local file which holds keys, one `<key_id> <hex encoded key>` per line
+    #[serde(default)]
+    pub key_file: String,
+}
+
+/// IndexCache configures the cache of parsed index files shared by readers of a handler.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct IndexCache {
+    /// memory_bytes is the memory budget of cached index files, 0 means index files are not kept in memory
+    #[serde(default)]
+    pub memory_bytes: u64,
+    /// disk_dir is a local directory where index files are persisted, empty means no persistence
+    #[serde(default)]
+    pub disk_dir: String,
+}
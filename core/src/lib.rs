@@ -5,6 +5,7 @@
 //! # Quick Start
 //! ```rust,ignore
 //! use bytestack::sdk;
+//! use futures::StreamExt;
 //!
 //! #[tokio::main]
 //! async fn main() {
@@ -16,10 +17,13 @@
 //!             endpoint: "http://localhost:9000".to_string(),
 //!             region: "default".to_string(),
 //!         },
+//!         ..Default::default()
 //!     };
 //!     let handler = sdk::Handler::new(config).await;
 //!
-//!     let mut bw = handler.open_writer("s3://test/dadadad.bs/").unwrap();
+//!     let bw = handler
+//!         .open_writer("s3://test/dadadad.bs/", sdk::WriterOptions::default())
+//!         .unwrap();
 //!     let mut idx: i32 = 0;
 //!     while idx < 2000 {
 //!         let content = vec![(idx % 124) as u8; 4096];
@@ -41,7 +45,8 @@
 //!     }
 //!     for s in &stack_list {
 //!         let mut iter = br.list_stack_al_iter(s.stack_id).await.unwrap();
-//!         while let Some((ir, _mr)) = iter.next().await {
+//!         while let Some(res) = iter.next().await {
+//!             let (ir, _mr) = res.expect("read meta");
 //!             let _data = match br
 //!                 .fetch(format!("{},{}", s.stack_id, ir.index_id()), true)
 //!                 .await
@@ -57,7 +62,8 @@
 //!
 //!     for s in &stack_list {
 //!         let mut iter = br.list_stack_al_with_data_iter(s.stack_id).await.unwrap();
-//!         while let Some((ir, _mr, data)) = iter.next().await {
+//!         while let Some(res) = iter.next().await {
+//!             let (ir, _mr, data) = res.expect("read data");
 //!             assert!(ir.size_data as usize == data.len())
 //!         }
 //!     }
@@ -1,9 +1,15 @@
 use std::str::FromStr;
+use std::sync::Arc;
 
+use super::bs_opendal_deletion;
+use super::bs_opendal_recovery::{self, RecoveryReport, RepairReport};
 use super::err::{CustomError, ErrorKind};
 use super::BytestackOpendalReader;
 use super::BytestackOpendalWriter;
 use super::Config;
+use super::WriterOptions;
+use super::{EncryptionOptions, IndexCache, Keyring, ReaderOptions};
+use super::{LocalCounterAllocator, RandomAllocator, StackIdAllocator};
 use log::{debug, info};
 use opendal::services::{Fs, S3};
 use opendal::{Builder, Operator};
@@ -17,98 +23,230 @@ use url::Url;
 /// BytestackOpendalHandler is entrance of sdk
 pub struct BytestackOpendalHandler {
     cfg: Config,
-    controller_cli: ControllerClient<Channel>,
+    controller_cli: Option<ControllerClient<Channel>>,
+    index_cache: Option<Arc<IndexCache>>,
 }
 
 impl BytestackOpendalHandler {
-    /// new BytestackOpendalHandler
+    /// new BytestackOpendalHandler, controller is optional: without it stack_id is allocated
+    /// as Config::stack_id_allocator says and bind_stack, unbind_stack and preload return ControllerError.
+    /// readers opened by the handler share the index cache configured in Config::index_cache.
     pub async fn new(cfg: Config) -> Self {
+        let index_cache = IndexCache::new_from_config(&cfg.index_cache);
+        if cfg.controller.is_empty() {
+            debug!(target: "BytestackOpendalHandler", "no controller specified");
+            return BytestackOpendalHandler {
+                cfg,
+                controller_cli: None,
+                index_cache,
+            };
+        }
         debug!(
             target: "BytestackOpendalHandler",
             "connect to controller: {}", &cfg.controller
         );
-        let channel = {
-            if cfg.controller.is_empty() {
-                panic!("no controller addr specified")
-            }
+        let channel =
             match ControllerClient::connect(Endpoint::from_str(&cfg.controller).unwrap()).await {
                 Ok(res) => res,
                 Err(err) => {
                     panic!("connect to {} error: {}", &cfg.controller, err);
                 }
-            }
-        };
+            };
         BytestackOpendalHandler {
             cfg,
-            controller_cli: channel,
+            controller_cli: Some(channel),
+            index_cache,
         }
     }
 
-    fn get_operator_by_path(&self, path: &str) -> Operator {
-        let url = Url::parse(path).expect(format!("Failed to parse URL {}", path).as_str());
+    /// get_operator_by_path return operator and prefix of stacks for path like
+    /// `s3://bucket/prefix/` or `file:///dir/`.
+    fn get_operator_by_path(&self, path: &str) -> Result<(Operator, String), ErrorKind> {
+        let url = match Url::parse(path) {
+            Ok(url) => url,
+            Err(e) => {
+                return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                    "failed to parse url {}: {}",
+                    path, e
+                ))));
+            }
+        };
         match url.scheme() {
             "s3" => {
-                let (bucket, _) = parse_s3_url(path).unwrap();
-                return init_s3_operator_via_builder(
+                let (bucket, prefix) = parse_s3_url(path)?;
+                let op = init_s3_operator_via_builder(
                     &bucket,
                     &self.cfg.s3.region,
                     &self.cfg.s3.aws_access_key_id,
                     &self.cfg.s3.aws_secret_access_key,
                     &self.cfg.s3.endpoint,
                 );
+                Ok((op, prefix))
             }
             "file" => {
+                // operator works on "/", so that prefix is the directory just like the one in s3 url.
                 let mut builder = Fs::default();
-                builder.root(&path);
+                builder.root("/");
                 let op = Operator::new(builder).unwrap().finish();
-                op
-            }
-            _ => {
-                panic!("unknown scheme: {}, url: {}", url.scheme(), path)
+                Ok((op, url.path().trim_start_matches('/').to_string()))
             }
+            _ => Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "unknown scheme: {}, url: {}",
+                url.scheme(),
+                path
+            )))),
+        }
+    }
+
+    /// controller_cli return client of controller, ControllerError if no controller is configured.
+    fn controller_cli(&self) -> Result<ControllerClient<Channel>, ErrorKind> {
+        match &self.controller_cli {
+            Some(controller_cli) => Ok(controller_cli.clone()),
+            None => Err(ErrorKind::ControllerError(CustomError::new(String::from(
+                "no controller configured",
+            )))),
         }
     }
 
+    /// stack_id_allocator return the allocator Config::stack_id_allocator says, None means controller.
+    fn stack_id_allocator(
+        &self,
+        operator: &Operator,
+        prefix: &str,
+    ) -> Result<Option<Arc<dyn StackIdAllocator>>, ErrorKind> {
+        let allocator: Arc<dyn StackIdAllocator> = match self.cfg.stack_id_allocator.as_str() {
+            "" if self.controller_cli.is_some() => return Ok(None),
+            "controller" => {
+                self.controller_cli()?;
+                return Ok(None);
+            }
+            "" | "local" => Arc::new(LocalCounterAllocator::new(
+                operator.clone(),
+                prefix.to_string(),
+            )),
+            "random" => Arc::new(RandomAllocator::new(
+                operator.clone(),
+                prefix.to_string(),
+                false,
+            )),
+            "time-ordered" => Arc::new(RandomAllocator::new(
+                operator.clone(),
+                prefix.to_string(),
+                true,
+            )),
+            other => {
+                return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                    "unknown stack_id allocator: {}",
+                    other
+                ))));
+            }
+        };
+        Ok(Some(allocator))
+    }
+
     /// open_reader return BytestackOpendalReader for giving path
     pub fn open_reader(&self, path: &str) -> Result<BytestackOpendalReader, ErrorKind> {
         debug!(target: "BytestackOpendalHandler", "open_reader on path: {}", path);
-        let operator = self.get_operator_by_path(path);
-        let (_, prefix) = match parse_s3_url(path) {
-            Ok(a) => a,
-            Err(e) => {
-                return Err(e);
-            }
+        let (operator, prefix) = self.get_operator_by_path(path)?;
+        let options = ReaderOptions {
+            keyring: self.keyring()?,
+            index_cache: self.index_cache.clone(),
+            ..Default::default()
         };
-        Ok(BytestackOpendalReader::new(
+        Ok(BytestackOpendalReader::new_with_options(
             operator,
             prefix,
             self.controller_cli.clone(),
+            options,
         ))
     }
 
-    /// open_writer return BytestackOpendalWriter for giving path
-    pub fn open_writer(&self, path: &str) -> Result<BytestackOpendalWriter, ErrorKind> {
-        let operator = self.get_operator_by_path(path);
-        let (_, prefix) = match parse_s3_url(path) {
-            Ok(a) => a,
-            Err(e) => {
-                return Err(e);
+    /// keyring return keys configured in Config, None if there is no key.
+    fn keyring(&self) -> Result<Option<Arc<Keyring>>, ErrorKind> {
+        let keyring = Keyring::new_from_config(&self.cfg.encryption)?;
+        if keyring.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(keyring)))
+    }
+
+    /// open_writer return BytestackOpendalWriter for giving path,
+    /// the writer fills and rolls over stacks by options.
+    /// New stacks are encrypted as Config says if options.encryption is None, and get stack_id
+    /// from the allocator Config says if options.stack_id_allocator is None.
+    pub fn open_writer(
+        &self,
+        path: &str,
+        mut options: WriterOptions,
+    ) -> Result<BytestackOpendalWriter, ErrorKind> {
+        if options.concurrency == 0 {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(String::from(
+                "concurrency should be greater than 0",
+            ))));
+        }
+        if options.encryption.is_none() && !self.cfg.encryption.cipher.is_empty() {
+            let keyring = self.keyring()?.unwrap_or_default();
+            options.encryption = EncryptionOptions::new_from_config(&self.cfg.encryption, keyring)?;
+        }
+        if let Some(encryption) = &options.encryption {
+            if encryption.keyring.get(encryption.key_id).is_none() {
+                return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                    "key {} not found in keyring",
+                    encryption.key_id
+                ))));
             }
-        };
+        }
+        let (operator, prefix) = self.get_operator_by_path(path)?;
+        if options.stack_id_allocator.is_none() {
+            options.stack_id_allocator = self.stack_id_allocator(&operator, &prefix)?;
+        }
         Ok(BytestackOpendalWriter::new(
             operator,
             prefix,
             self.controller_cli.clone(),
+            options,
         ))
     }
 
+    /// recover_stack rebuild index and meta file of stack_id under path from its data file,
+    /// it is used to salvage a stack whose writer died before close.
+    pub async fn recover_stack(
+        &self,
+        path: &str,
+        stack_id: u64,
+    ) -> Result<RecoveryReport, ErrorKind> {
+        let (operator, prefix) = self.get_operator_by_path(path)?;
+        bs_opendal_recovery::recover_stack(&operator, &prefix, stack_id).await
+    }
+
+    /// repair_stack rebuild index, meta and filename index of stack_id under path from its data file,
+    /// skipping corrupted regions of data file. Nothing is written if dry_run.
+    pub async fn repair_stack(
+        &self,
+        path: &str,
+        stack_id: u64,
+        dry_run: bool,
+    ) -> Result<RepairReport, ErrorKind> {
+        let (operator, prefix) = self.get_operator_by_path(path)?;
+        let keyring = self.keyring()?;
+        bs_opendal_recovery::repair_stack(&operator, &prefix, stack_id, keyring.as_deref(), dry_run)
+            .await
+    }
+
+    /// delete mark the record of index_id under path as deleted, it is not returned by fetch,
+    /// list_stack or iterators any more.
+    pub async fn delete(&self, path: &str, index_id: &str) -> Result<(), ErrorKind> {
+        let (operator, prefix) = self.get_operator_by_path(path)?;
+        bs_opendal_deletion::delete(&operator, &prefix, index_id).await
+    }
+
     /// bind_stack so that stack can be preload by bserver
     pub async fn bind_stack(&mut self, stack_id: u64, path: &str) -> Result<(), ErrorKind> {
         let req = Request::new(StackSourceReq {
             stack_id,
             locations: vec![path.to_string()],
         });
-        let _resp = match self.controller_cli.register_stack_source(req).await {
+        let _resp = match self.controller_cli()?.register_stack_source(req).await {
             Ok(resp) => resp,
             Err(e) => return Err(ErrorKind::ControllerError(CustomError::new(e.to_string()))),
         };
@@ -121,7 +259,7 @@ impl BytestackOpendalHandler {
             stack_id,
             locations: vec![path.to_string()],
         });
-        let _resp = match self.controller_cli.de_register_stack_source(req).await {
+        let _resp = match self.controller_cli()?.de_register_stack_source(req).await {
             Ok(resp) => resp,
             Err(e) => return Err(ErrorKind::ControllerError(CustomError::new(e.to_string()))),
         };
@@ -135,7 +273,7 @@ impl BytestackOpendalHandler {
         replicas: i64,
     ) -> Result<PreLoadAssignments, ErrorKind> {
         let req = Request::new(CallPreLoadReq { stack_id, replicas });
-        let _resp = match self.controller_cli.pre_load(req).await {
+        let _resp = match self.controller_cli()?.pre_load(req).await {
             Ok(resp) => return Ok(resp.into_inner()),
             Err(e) => return Err(ErrorKind::ControllerError(CustomError::new(e.to_string()))),
         };
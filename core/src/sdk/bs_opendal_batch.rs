@@ -0,0 +1,416 @@
+//! bs_opendal_batch provides batch fetch in opendal way.
+//! Records of a batch are grouped by stack and sorted by offset_data, nearby records are coalesced into
+//! one ranged read so that thousands of small records cost a few requests instead of one request each.
+//! Only the head of a large record is in a ranged read, its chunk records are read one by one when it
+//! is decoded, so memory of a fetcher never holds the chunks.
+
+use super::bs_opendal_crypto::StackCrypto;
+use super::bs_opendal_record;
+use super::err::{CustomError, ErrorKind};
+use super::ReaderOptions;
+use crate::types::data::{DataRecordLarge, LARGE_RECORD_SIZE};
+use crate::types::{DataRecordHeader, IndexRecord};
+use crate::utils::IndexID;
+use opendal::Operator;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// BatchItem is a record of batch, position is where the record is in the batch.
+struct BatchItem {
+    position: usize,
+    offset_data: u64,
+    cookie: u32,
+    end: u64,
+    large: bool,
+}
+
+impl BatchItem {
+    /// new BatchItem of the record at offset_data which ends before next, a large record ends after
+    /// its head since chunk records are read when it is decoded.
+    fn new(position: usize, offset_data: u64, cookie: u32, size_data: u32, next: u64) -> Self {
+        let large = size_data == LARGE_RECORD_SIZE;
+        let end = if large {
+            next.min(offset_data + (DataRecordHeader::size() + DataRecordLarge::size()) as u64)
+        } else {
+            next
+        };
+        BatchItem {
+            position,
+            offset_data,
+            cookie,
+            end,
+            large,
+        }
+    }
+}
+
+/// StackSource is what fetchers of one stack share.
+pub(crate) struct StackSource {
+    pub(crate) operator: Operator,
+    pub(crate) data_file_path: String,
+    pub(crate) crypto: Option<Arc<StackCrypto>>,
+    pub(crate) check_crc: bool,
+}
+
+/// OpendalFetcher reads one range of a data file and decodes every record of the batch in it.
+pub struct OpendalFetcher {
+    source: Arc<StackSource>,
+    range: Range<u64>,
+    items: Vec<BatchItem>,
+}
+
+impl OpendalFetcher {
+    /// range return the range of data file read by this fetcher.
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+
+    /// ends_with_large check if the last record of this fetcher is a large record.
+    fn ends_with_large(&self) -> bool {
+        matches!(self.items.last(), Some(item) if item.large)
+    }
+
+    /// do_fetch read the range and decode the records in it, return (position in batch, data) of every record.
+    /// an error of one record does not affect the others, an error of the read is returned for every record.
+    pub async fn do_fetch(&self) -> Vec<(usize, Result<Vec<u8>, ErrorKind>)> {
+        match self.read_range().await {
+            Ok(buf) => self.decode_all(&buf).await,
+            Err(e) => self
+                .items
+                .iter()
+                .map(|item| (item.position, Err(e.clone())))
+                .collect(),
+        }
+    }
+
+    /// read_range read the whole range of data file.
+    pub(crate) async fn read_range(&self) -> Result<Vec<u8>, ErrorKind> {
+        match self
+            .source
+            .operator
+            .range_read(&self.source.data_file_path, self.range.clone())
+            .await
+        {
+            Ok(buf) => Ok(buf),
+            Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        }
+    }
+
+    /// decode_all decode every record of this fetcher from buf in the order of offset_data.
+    pub(crate) async fn decode_all(&self, buf: &[u8]) -> Vec<(usize, Result<Vec<u8>, ErrorKind>)> {
+        let mut out = Vec::with_capacity(self.items.len());
+        for item in &self.items {
+            out.push((item.position, self.decode(buf, item).await));
+        }
+        out
+    }
+
+    /// decode return data of item from buf, the target of a link record is read from buf if it is in range.
+    async fn decode(&self, buf: &[u8], item: &BatchItem) -> Result<Vec<u8>, ErrorKind> {
+        let start = (item.offset_data - self.range.start) as usize;
+        let end = (item.end - self.range.start) as usize;
+        if end > buf.len() {
+            return Err(ErrorKind::IOError(CustomError::new(format!(
+                "record at {} is truncated",
+                item.offset_data
+            ))));
+        }
+        let source = &self.source;
+        let crypto = source.crypto.as_deref();
+        let (drh, body) = bs_opendal_record::parse_record_body(&buf[start..end], item.cookie)?;
+        if drh.is_large() {
+            return bs_opendal_record::read_large(
+                &source.operator,
+                &source.data_file_path,
+                item.offset_data,
+                &drh,
+                &body,
+                source.check_crc,
+            )
+            .await;
+        }
+        if !drh.is_link() {
+            return bs_opendal_record::decode_body(
+                &drh,
+                item.offset_data,
+                body,
+                crypto,
+                source.check_crc,
+            );
+        }
+        let link = bs_opendal_record::parse_link(&body)?;
+        if !self.range.contains(&link.offset_data) {
+            return bs_opendal_record::resolve_link(
+                &source.operator,
+                &source.data_file_path,
+                &drh,
+                &body,
+                crypto,
+                source.check_crc,
+            )
+            .await;
+        }
+        let target_start = (link.offset_data - self.range.start) as usize;
+        let (target, target_body) =
+            match bs_opendal_record::parse_record_body(&buf[target_start..], link.cookie) {
+                Ok(target) => target,
+                // the target is cut by the end of range, read it alone.
+                Err(_) => {
+                    bs_opendal_record::read_record_body(
+                        &source.operator,
+                        &source.data_file_path,
+                        link.offset_data,
+                        link.cookie,
+                    )
+                    .await?
+                }
+            };
+        bs_opendal_record::decode_link_target(
+            &drh,
+            &link,
+            &target,
+            target_body,
+            crypto,
+            source.check_crc,
+        )
+    }
+}
+
+/// plan_stack turn the records of a batch in one stack into fetchers, irs is every record of the stack
+/// in index file and data_end is where records end in data file, a record ends where the next record starts.
+/// records which are not in the stack or deleted are returned as errors instead.
+pub(crate) fn plan_stack(
+    source: Arc<StackSource>,
+    irs: &[IndexRecord],
+    data_end: u64,
+    deleted: &HashSet<(u64, u32)>,
+    batch: Vec<(usize, IndexID)>,
+    options: &ReaderOptions,
+) -> (Vec<OpendalFetcher>, Vec<(usize, ErrorKind)>) {
+    let mut offsets: Vec<(u64, u32, u32)> = irs
+        .iter()
+        .map(|ir| (ir.offset_data, ir.cookie, ir.size_data))
+        .collect();
+    offsets.sort_unstable();
+    let mut items = Vec::with_capacity(batch.len());
+    let mut errors = Vec::new();
+    for (position, id) in batch {
+        let i = match offsets.binary_search_by_key(&id.offset_data, |(offset, _, _)| *offset) {
+            Ok(i) => i,
+            Err(_) => {
+                errors.push((
+                    position,
+                    ErrorKind::NotFound(CustomError::new(format!(
+                        "no record at {} of stack {}",
+                        id.offset_data, id.stack_id
+                    ))),
+                ));
+                continue;
+            }
+        };
+        if offsets[i].1 != id.cookie {
+            errors.push((
+                position,
+                ErrorKind::InvalidArgument(CustomError::new(String::from("cookie mismatched"))),
+            ));
+            continue;
+        }
+        if deleted.contains(&(id.offset_data, id.cookie)) {
+            errors.push((
+                position,
+                ErrorKind::NotFound(CustomError::new(format!(
+                    "record at {} of stack {} is deleted",
+                    id.offset_data, id.stack_id
+                ))),
+            ));
+            continue;
+        }
+        let next = match offsets.get(i + 1) {
+            Some((next, _, _)) => *next,
+            None => data_end,
+        };
+        items.push(BatchItem::new(
+            position,
+            id.offset_data,
+            id.cookie,
+            offsets[i].2,
+            next,
+        ));
+    }
+    items.sort_by_key(|item| item.offset_data);
+
+    let mut fetchers: Vec<OpendalFetcher> = Vec::new();
+    for item in items {
+        if let Some(last) = fetchers.last_mut() {
+            let end = last.range.end.max(item.end);
+            // the chunk records of a large record are never read as a gap.
+            if !last.ends_with_large()
+                && item.offset_data <= last.range.end + options.batch_gap_tolerance
+                && end - last.range.start <= options.batch_max_range_size
+            {
+                last.range.end = end;
+                last.items.push(item);
+                continue;
+            }
+        }
+        fetchers.push(OpendalFetcher {
+            source: source.clone(),
+            range: item.offset_data..item.end,
+            items: vec![item],
+        });
+    }
+    (fetchers, errors)
+}
+
+/// plan_scan split records of a stack into fetchers for a full scan, records are kept in the order of
+/// index file and position of a record is its index in irs. A fetcher holds up to max_records records
+/// and up to max_bytes bytes of data file, a single record larger than max_bytes still gets a fetcher.
+/// A fetcher ends at a large record, so its chunk records are not read into memory at once.
+/// all_irs is every record of the stack in index file, deleted ones included, to find where records end.
+pub(crate) fn plan_scan(
+    source: Arc<StackSource>,
+    all_irs: &[IndexRecord],
+    data_end: u64,
+    irs: &[IndexRecord],
+    max_bytes: u64,
+    max_records: usize,
+) -> Vec<OpendalFetcher> {
+    let mut offsets: Vec<u64> = all_irs.iter().map(|ir| ir.offset_data).collect();
+    offsets.sort_unstable();
+    let mut fetchers: Vec<OpendalFetcher> = Vec::new();
+    for (position, ir) in irs.iter().enumerate() {
+        let next = match offsets.binary_search(&ir.offset_data) {
+            Ok(i) if i + 1 < offsets.len() => offsets[i + 1],
+            _ => data_end,
+        };
+        let item = BatchItem::new(position, ir.offset_data, ir.cookie, ir.size_data, next);
+        let end = item.end;
+        if let Some(last) = fetchers.last_mut() {
+            if !last.ends_with_large()
+                && last.items.len() < max_records
+                && item.offset_data >= last.range.end
+                && end - last.range.start <= max_bytes
+            {
+                last.range.end = end;
+                last.items.push(item);
+                continue;
+            }
+        }
+        fetchers.push(OpendalFetcher {
+            source: source.clone(),
+            range: item.offset_data..end,
+            items: vec![item],
+        });
+    }
+    fetchers
+}
+
+#[test]
+fn test_plan_large_record() {
+    let source = Arc::new(StackSource {
+        operator: Operator::new(opendal::services::Memory::default())
+            .unwrap()
+            .finish(),
+        data_file_path: String::from("0x0001.data"),
+        crypto: None,
+        check_crc: false,
+    });
+    // a small record, a large record whose chunks take 5 GiB, and two small records after it.
+    let head_end = 8192 + (DataRecordHeader::size() + DataRecordLarge::size()) as u64;
+    let after = 8192 + (5 << 30);
+    let irs = vec![
+        IndexRecord::new(1, 4096, 100, 0, 10),
+        IndexRecord::new(2, 8192, LARGE_RECORD_SIZE, 10, 10),
+        IndexRecord::new(3, after, 100, 20, 10),
+        IndexRecord::new(4, after + 4096, 100, 30, 10),
+    ];
+    let data_end = after + 8192;
+    let fetchers = plan_scan(source.clone(), &irs, data_end, &irs, u64::MAX, usize::MAX);
+    let ranges: Vec<Range<u64>> = fetchers.iter().map(|f| f.range()).collect();
+    assert_eq!(ranges, vec![4096..head_end, after..data_end]);
+
+    let batch = irs
+        .iter()
+        .enumerate()
+        .map(|(position, ir)| {
+            let id = IndexID {
+                stack_id: 1,
+                offset_data: ir.offset_data,
+                cookie: ir.cookie,
+            };
+            (position, id)
+        })
+        .collect();
+    let options = ReaderOptions {
+        batch_gap_tolerance: u64::MAX / 2,
+        batch_max_range_size: u64::MAX,
+        ..Default::default()
+    };
+    let (fetchers, errors) = plan_stack(source, &irs, data_end, &HashSet::new(), batch, &options);
+    assert!(errors.is_empty());
+    let ranges: Vec<Range<u64>> = fetchers.iter().map(|f| f.range()).collect();
+    assert_eq!(ranges, vec![4096..head_end, after..data_end]);
+}
+
+#[tokio::test]
+async fn test_batch_fetch() {
+    use super::bs_opendal_deletion;
+    use super::bs_opendal_testing::{new_writer, temp_operator};
+    use super::{BytestackOpendalReader, WriterOptions};
+
+    let (op, _dir) = temp_operator("batch_fetch");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            max_records: Some(5),
+            ..Default::default()
+        },
+    );
+    let mut ids = vec![];
+    for i in 0..10u8 {
+        let data = vec![i; 100 + i as usize * 3000];
+        ids.push((
+            writer.put(data.clone(), i.to_string(), None).await.unwrap(),
+            data,
+        ));
+    }
+    writer.close().await.unwrap();
+    bs_opendal_deletion::delete(&op, "", &ids[3].0)
+        .await
+        .unwrap();
+
+    // records of two stacks out of order with a duplicate, and ids which can not be fetched.
+    let order = [9, 0, 4, 1, 9, 7, 2, 5];
+    let mut batch: Vec<String> = order.iter().map(|i| ids[*i].0.clone()).collect();
+    let mut bad_cookie = crate::utils::parse_index_id(&ids[6].0).unwrap();
+    bad_cookie.cookie ^= 1;
+    batch.push(ids[3].0.clone());
+    batch.push(format!(
+        "{},{:x}{:08x}",
+        bad_cookie.stack_id, bad_cookie.offset_data, bad_cookie.cookie
+    ));
+    batch.push(String::from("nope"));
+    // far apart records in separate reads, adjacent records in one read, or all in one read.
+    for (gap, max_range) in [(0, 1), (0, 1 << 20), (1 << 20, 1 << 20)] {
+        let reader = BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                batch_gap_tolerance: gap,
+                batch_max_range_size: max_range,
+                batch_concurrency: 2,
+                ..Default::default()
+            },
+        );
+        let results = reader.batch_fetch(batch.clone(), true).await;
+        assert_eq!(results.len(), batch.len());
+        for (n, i) in order.iter().enumerate() {
+            assert_eq!(results[n].as_ref().unwrap(), &ids[*i].1);
+        }
+        assert!(matches!(results[8], Err(ErrorKind::NotFound(_))));
+        assert!(results[9].is_err());
+        assert!(matches!(results[10], Err(ErrorKind::InvalidArgument(_))));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::config::S3;
+use crate::config::{Encryption, IndexCache, S3};
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -6,4 +6,16 @@ use serde::Serialize;
 pub struct Config {
     pub controller: String,
     pub s3: S3,
+    /// encryption is the cipher and keys of encryption at rest, writers encrypt new stacks with it
+    /// unless WriterOptions::encryption is set, readers look up keys of encrypted stacks in it.
+    /// Stacks are not encrypted by default.
+    #[serde(default)]
+    pub encryption: Encryption,
+    /// stack_id_allocator is "controller", "local", "random" or "time-ordered",
+    /// empty means controller if it is configured, otherwise local.
+    #[serde(default)]
+    pub stack_id_allocator: String,
+    /// index_cache is the cache of index files shared by readers of Handler, not cached by default.
+    #[serde(default)]
+    pub index_cache: IndexCache,
 }
@@ -0,0 +1,476 @@
+//! bs_opendal_crypto provides keys and AEAD ciphers to encrypt records at rest.
+
+use super::err::{CustomError, ErrorKind};
+use crate::config::Encryption;
+pub use crate::types::data::Cipher;
+use crate::types::data::{DataEncryptionHeader, DATA_ENCRYPTION_FLAG_META};
+use crate::types::{DataMagicHeader, MetaRecord};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use opendal::Operator;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// NONCE_SIZE is the size of random nonce saved in front of every ciphertext.
+const NONCE_SIZE: usize = 12;
+const _AAD_DOMAIN_DATA: u8 = 1;
+const _AAD_DOMAIN_META: u8 = 2;
+
+/// Keyring holds 32 bytes keys by key id, a stack records the key id it is encrypted with,
+/// so old keys stay readable after new stacks switch to a new key.
+#[derive(Default, Clone)]
+pub struct Keyring {
+    keys: HashMap<u32, [u8; 32]>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut key_ids: Vec<&u32> = self.keys.keys().collect();
+        key_ids.sort();
+        f.debug_struct("Keyring")
+            .field("key_ids", &key_ids)
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// new return an empty Keyring
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// new_from_config return a Keyring holding keys and keys in key_file of config
+    pub fn new_from_config(cfg: &Encryption) -> Result<Self, ErrorKind> {
+        let mut keyring = Self::new();
+        for (key_id, key) in &cfg.keys {
+            let key_id = match key_id.parse::<u32>() {
+                Ok(key_id) => key_id,
+                Err(e) => {
+                    return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                        "invalid key id {}: {}",
+                        key_id, e
+                    ))));
+                }
+            };
+            keyring.add_hex_key(key_id, key)?;
+        }
+        if !cfg.key_file.is_empty() {
+            keyring.load_key_file(&cfg.key_file)?;
+        }
+        Ok(keyring)
+    }
+
+    /// add_key add key by key_id, the old one is replaced
+    pub fn add_key(&mut self, key_id: u32, key: [u8; 32]) {
+        self.keys.insert(key_id, key);
+    }
+
+    /// add_hex_key add a hex encoded key by key_id
+    pub fn add_hex_key(&mut self, key_id: u32, hex_key: &str) -> Result<(), ErrorKind> {
+        let mut key = [0u8; 32];
+        match hex::decode_to_slice(hex_key.trim(), &mut key) {
+            Ok(_) => {}
+            Err(e) => {
+                return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                    "invalid key {}: {}",
+                    key_id, e
+                ))));
+            }
+        }
+        self.add_key(key_id, key);
+        Ok(())
+    }
+
+    /// load_key_file add keys from a local file, one `<key_id> <hex encoded key>` per line,
+    /// empty lines and lines start with `#` are ignored.
+    pub fn load_key_file(&mut self, path: &str) -> Result<(), ErrorKind> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                return Err(ErrorKind::IOError(CustomError::new(format!(
+                    "read key file {} error: {}",
+                    path, e
+                ))));
+            }
+        };
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key_id, key) = match line.split_once(char::is_whitespace) {
+                Some((key_id, key)) => (key_id, key),
+                None => {
+                    return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                        "invalid line in key file {}",
+                        path
+                    ))));
+                }
+            };
+            let key_id = match key_id.parse::<u32>() {
+                Ok(key_id) => key_id,
+                Err(e) => {
+                    return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                        "invalid key id {} in key file {}: {}",
+                        key_id, path, e
+                    ))));
+                }
+            };
+            self.add_hex_key(key_id, key)?;
+        }
+        Ok(())
+    }
+
+    /// get return the key of key_id
+    pub fn get(&self, key_id: u32) -> Option<&[u8; 32]> {
+        self.keys.get(&key_id)
+    }
+
+    /// is_empty check if there is no key
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// EncryptionOptions tells BytestackOpendalWriter how to encrypt new stacks.
+#[derive(Debug, Clone)]
+pub struct EncryptionOptions {
+    /// cipher encrypts data of every record
+    pub cipher: Cipher,
+    /// key_id is saved in every new stack and its key must be in keyring
+    pub key_id: u32,
+    /// encrypt_meta encrypts filename and extra of MetaRecords too
+    pub encrypt_meta: bool,
+    /// keyring holds the key of key_id
+    pub keyring: Arc<Keyring>,
+}
+
+impl EncryptionOptions {
+    /// new_from_config return EncryptionOptions by config, None if cipher of config is empty.
+    pub fn new_from_config(
+        cfg: &Encryption,
+        keyring: Arc<Keyring>,
+    ) -> Result<Option<Self>, ErrorKind> {
+        if cfg.cipher.is_empty() {
+            return Ok(None);
+        }
+        let cipher = match cfg.cipher.parse::<Cipher>() {
+            Ok(cipher) => cipher,
+            Err(e) => return Err(ErrorKind::InvalidArgument(CustomError::new(e))),
+        };
+        Ok(Some(EncryptionOptions {
+            cipher,
+            key_id: cfg.key_id,
+            encrypt_meta: cfg.encrypt_meta,
+            keyring,
+        }))
+    }
+
+    /// stack_crypto return StackCrypto to encrypt stack_id
+    pub(crate) fn stack_crypto(&self, stack_id: u64) -> Result<StackCrypto, ErrorKind> {
+        match self.keyring.get(self.key_id) {
+            Some(key) => Ok(StackCrypto {
+                cipher: self.cipher,
+                key_id: self.key_id,
+                key: *key,
+                stack_id,
+                encrypt_meta: self.encrypt_meta,
+            }),
+            None => Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "key {} not found in keyring",
+                self.key_id
+            )))),
+        }
+    }
+}
+
+/// StackCrypto encrypts and decrypts records of one stack, every ciphertext is bound to
+/// stack_id, offset_data and cookie of its record so that it can not be moved around.
+pub(crate) struct StackCrypto {
+    cipher: Cipher,
+    key_id: u32,
+    key: [u8; 32],
+    stack_id: u64,
+    encrypt_meta: bool,
+}
+
+impl StackCrypto {
+    /// header return DataEncryptionHeader saved in data file
+    pub(crate) fn header(&self) -> DataEncryptionHeader {
+        let flags = if self.encrypt_meta {
+            DATA_ENCRYPTION_FLAG_META
+        } else {
+            0
+        };
+        DataEncryptionHeader::new(self.cipher, self.key_id, flags)
+    }
+
+    /// encrypt_meta check if MetaRecords should be encrypted
+    pub(crate) fn encrypt_meta(&self) -> bool {
+        self.encrypt_meta
+    }
+
+    /// name_key return the key of name hashes in filename index if MetaRecords are encrypted,
+    /// it is derived from the stack key.
+    pub(crate) fn name_key(&self) -> Option<[u8; 32]> {
+        if !self.encrypt_meta {
+            return None;
+        }
+        Some(blake3::derive_key("bytestack filename index v1", &self.key))
+    }
+
+    /// encrypt_data return `| nonce | ciphertext |` of data
+    pub(crate) fn encrypt_data(&self, offset_data: u64, cookie: u32, data: &[u8]) -> Vec<u8> {
+        self.encrypt(&self.aad(_AAD_DOMAIN_DATA, offset_data, cookie), data)
+    }
+
+    /// decrypt_data return data from `| nonce | ciphertext |`
+    pub(crate) fn decrypt_data(
+        &self,
+        offset_data: u64,
+        cookie: u32,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, ErrorKind> {
+        self.decrypt(&self.aad(_AAD_DOMAIN_DATA, offset_data, cookie), sealed)
+    }
+
+    /// seal_meta move filename and extra of mr into its sealed field
+    pub(crate) fn seal_meta(&self, mr: &mut MetaRecord) {
+        let plain = bincode::serialize(&(&mr.filename, &mr.extra)).unwrap();
+        mr.sealed = self.encrypt(
+            &self.aad(_AAD_DOMAIN_META, mr.offset_data, mr.cookie),
+            &plain,
+        );
+        mr.filename = String::new();
+        mr.extra = Vec::new();
+    }
+
+    /// open_meta restore filename and extra of mr from its sealed field
+    pub(crate) fn open_meta(&self, mr: &mut MetaRecord) -> Result<(), ErrorKind> {
+        let plain = self.decrypt(
+            &self.aad(_AAD_DOMAIN_META, mr.offset_data, mr.cookie),
+            &mr.sealed,
+        )?;
+        let (filename, extra) = match bincode::deserialize::<(String, Vec<u8>)>(&plain) {
+            Ok(res) => res,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        mr.filename = filename;
+        mr.extra = extra;
+        mr.sealed = Vec::new();
+        Ok(())
+    }
+
+    fn aad(&self, domain: u8, offset_data: u64, cookie: u32) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(21);
+        aad.push(domain);
+        aad.extend_from_slice(&self.stack_id.to_le_bytes());
+        aad.extend_from_slice(&offset_data.to_le_bytes());
+        aad.extend_from_slice(&cookie.to_le_bytes());
+        aad
+    }
+
+    fn encrypt(&self, aad: &[u8], plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload { msg: plain, aad };
+        let ciphertext = match self.cipher {
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.key.into()).encrypt(&nonce.into(), payload),
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(&self.key.into()).encrypt(&nonce.into(), payload)
+            }
+        }
+        .expect("encrypt in memory never fails");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, ErrorKind> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(ErrorKind::AuthenticationFailed(CustomError::new(
+                String::from("ciphertext too short"),
+            )));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let nonce: [u8; NONCE_SIZE] = nonce.try_into().unwrap();
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        let plain = match self.cipher {
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.key.into()).decrypt(&nonce.into(), payload),
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(&self.key.into()).decrypt(&nonce.into(), payload)
+            }
+        };
+        match plain {
+            Ok(plain) => Ok(plain),
+            Err(_) => Err(ErrorKind::AuthenticationFailed(CustomError::new(format!(
+                "authentication failed in stack {} with key {}",
+                self.stack_id, self.key_id
+            )))),
+        }
+    }
+}
+
+/// read_stack_crypto read DataEncryptionHeader of stack_id, None if the stack is not encrypted.
+/// InvalidArgument is returned if the stack is encrypted but its key is not in keyring.
+pub(crate) async fn read_stack_crypto(
+    operator: &Operator,
+    data_file_path: &str,
+    stack_id: u64,
+    keyring: Option<&Keyring>,
+) -> Result<Option<StackCrypto>, ErrorKind> {
+    let start = DataMagicHeader::size() as u64;
+    let bs = match operator
+        .range_read(
+            data_file_path,
+            start..start + DataEncryptionHeader::size() as u64,
+        )
+        .await
+    {
+        Ok(bs) => bs,
+        Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    };
+    let eh = match bincode::deserialize::<DataEncryptionHeader>(&bs) {
+        Ok(eh) => eh,
+        Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    };
+    if !eh.valid() {
+        return Ok(None);
+    }
+    let cipher = match Cipher::from_id(eh.cipher) {
+        Some(cipher) => cipher,
+        None => {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "unknown cipher {} of stack {}",
+                eh.cipher, stack_id
+            ))));
+        }
+    };
+    let key = match keyring.and_then(|keyring| keyring.get(eh.key_id)) {
+        Some(key) => key,
+        None => {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "stack {} is encrypted with key {} which is not in keyring",
+                stack_id, eh.key_id
+            ))));
+        }
+    };
+    Ok(Some(StackCrypto {
+        cipher,
+        key_id: eh.key_id,
+        key: *key,
+        stack_id,
+        encrypt_meta: eh.flags & DATA_ENCRYPTION_FLAG_META != 0,
+    }))
+}
+
+#[tokio::test]
+async fn test_encrypted_round_trip() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use super::{BytestackOpendalReader, ReaderOptions, VerifyLevel, WriterOptions};
+    use crate::utils;
+    use futures::StreamExt;
+
+    let (op, dir) = temp_operator("encryption");
+    let mut keyring = Keyring::new();
+    keyring.add_key(7, [7; 32]);
+    let keyring = Arc::new(keyring);
+    let mut wrong = Keyring::new();
+    wrong.add_key(7, [8; 32]);
+    let reader_with = |keyring: Arc<Keyring>| {
+        BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                keyring: Some(keyring),
+                ..Default::default()
+            },
+        )
+    };
+    let secret = b"a secret which is never saved in plain text".repeat(10);
+    for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                encryption: Some(EncryptionOptions {
+                    cipher,
+                    key_id: 7,
+                    encrypt_meta: true,
+                    keyring: keyring.clone(),
+                }),
+                ..Default::default()
+            },
+        );
+        let a = writer
+            .put(
+                secret.clone(),
+                String::from("secret.txt"),
+                Some(b"extra".to_vec()),
+            )
+            .await
+            .unwrap();
+        let b = writer
+            .put_reader(&secret[..], secret.len() as u64, String::from("b"), None)
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+        let stack_id = stack_of(&a);
+        for path in [
+            utils::get_data_file_path("", stack_id),
+            utils::get_meta_file_path("", stack_id),
+        ] {
+            let bs = std::fs::read(dir.path().join(path)).unwrap();
+            assert!(!bs.windows(8).any(|w| w == &secret[..8] || w == b"secret.t"));
+        }
+
+        let reader = reader_with(keyring.clone());
+        let (_, mr, data) = reader.fetch_with_meta(&a, true).await.unwrap();
+        assert_eq!(
+            (mr.filename.as_str(), &mr.extra[..]),
+            ("secret.txt", &b"extra"[..])
+        );
+        assert_eq!(data, secret);
+        assert_eq!(reader.fetch(&b, true).await.unwrap(), secret);
+        assert_eq!(
+            reader.fetch_range(&a, 2..8, true).await.unwrap(),
+            &secret[2..8]
+        );
+        let mut iter = reader.list_stack_al_with_data_iter(stack_id).await.unwrap();
+        let mut n = 0;
+        while let Some(res) = iter.next().await {
+            assert_eq!(res.unwrap().2, secret);
+            n += 1;
+        }
+        assert_eq!(n, 2);
+        // verify decrypts records with the key, and says so when it has none.
+        let report = reader
+            .verify_stack(stack_id, VerifyLevel::Full)
+            .await
+            .unwrap();
+        assert_eq!((report.records, report.problems.len()), (2, 0));
+        let report = new_reader(&op)
+            .verify_stack(stack_id, VerifyLevel::Full)
+            .await
+            .unwrap();
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert!(report.problems[0]
+            .message
+            .starts_with("records are not decrypted"));
+
+        // without the key nothing is read, a wrong key fails authentication.
+        assert!(matches!(
+            new_reader(&op).fetch(&a, true).await,
+            Err(ErrorKind::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            reader_with(Arc::new(wrong.clone())).fetch(&a, true).await,
+            Err(ErrorKind::AuthenticationFailed(_))
+        ));
+    }
+}
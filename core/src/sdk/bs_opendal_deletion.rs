@@ -0,0 +1,252 @@
+//! bs_opendal_deletion provides logical deletion of records by tombstones.
+//! Every stack has a deletion log under `0x{stack_id}.del/`, a delete writes one tombstone file into it,
+//! so concurrent deletes never overwrite each other and a tombstone never disappears.
+//! Data of deleted records stays in data file until space is reclaimed.
+
+use super::bs_opendal_record;
+use super::err::{CustomError, ErrorKind};
+use crate::types::Tombstone;
+use crate::utils;
+use futures::TryStreamExt;
+use opendal::{ErrorKind as OpendalErrorKind, Operator};
+use std::collections::{HashMap, HashSet};
+
+/// delete mark the record of index_id as deleted, deleting a deleted record is a no-op.
+/// The record is checked to exist, InvalidArgument is returned if the index_id does not point to a record.
+pub async fn delete(operator: &Operator, prefix: &str, index_id: &str) -> Result<(), ErrorKind> {
+    let parsed = match utils::parse_index_id(index_id) {
+        Some(id) => id,
+        None => {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "invalid index_id: {}",
+                index_id
+            ))));
+        }
+    };
+    let data_file_path = utils::get_data_file_path(prefix, parsed.stack_id);
+    bs_opendal_record::read_record_header(
+        operator,
+        &data_file_path,
+        parsed.offset_data,
+        parsed.cookie,
+    )
+    .await?;
+    let tombstone = Tombstone::new(
+        parsed.stack_id,
+        parsed.offset_data,
+        parsed.cookie,
+        utils::current_time(),
+    );
+    let tombstone_file_path =
+        utils::get_tombstone_file_path(prefix, parsed.stack_id, parsed.offset_data, parsed.cookie);
+    match operator
+        .write(
+            &tombstone_file_path,
+            bincode::serialize(&tombstone).unwrap(),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    }
+}
+
+/// deleted_records return (offset_data, cookie) of every deleted record in stack_id.
+pub async fn deleted_records(
+    operator: &Operator,
+    prefix: &str,
+    stack_id: u64,
+) -> Result<HashSet<(u64, u32)>, ErrorKind> {
+    let mut out = HashSet::new();
+    let deletion_log_path = utils::get_deletion_log_path(prefix, stack_id);
+    let mut ds = match operator.list_with(&deletion_log_path).await {
+        Ok(ds) => ds,
+        Err(e) if e.kind() == OpendalErrorKind::NotFound => return Ok(out),
+        Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    };
+    loop {
+        let de = match ds.try_next().await {
+            Ok(Some(de)) => de,
+            Ok(None) => break,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        // the file name is the index_id without stack_id.
+        if let Some(parsed) = utils::parse_index_id(&format!("{},{}", stack_id, de.name())) {
+            out.insert((parsed.offset_data, parsed.cookie));
+        }
+    }
+    Ok(out)
+}
+
+/// all_deleted_records return (offset_data, cookie) of every deleted record by stack_id for all stacks
+/// under prefix, deletion logs are read by a single recursive list of prefix instead of one per stack.
+/// Stacks without any deleted record are not in the map.
+pub async fn all_deleted_records(
+    operator: &Operator,
+    prefix: &str,
+) -> Result<HashMap<u64, HashSet<(u64, u32)>>, ErrorKind> {
+    let mut out: HashMap<u64, HashSet<(u64, u32)>> = HashMap::new();
+    let mut ds = match operator.scan(prefix).await {
+        Ok(ds) => ds,
+        Err(e) if e.kind() == OpendalErrorKind::NotFound => return Ok(out),
+        Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    };
+    loop {
+        let de = match ds.try_next().await {
+            Ok(Some(de)) => de,
+            Ok(None) => break,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        // a tombstone is at `{prefix}0x{stack_id}.del/{index_id without stack_id}`.
+        let relative = match de.path().strip_prefix(prefix) {
+            Some(relative) => relative,
+            None => continue,
+        };
+        let (dir, name) = match relative.find(".del/") {
+            Some(i) => relative.split_at(i + ".del/".len()),
+            None => continue,
+        };
+        if name.is_empty() || name.contains('/') {
+            continue;
+        }
+        let stack_id = match utils::parse_deletion_log_stack_id(dir) {
+            Some(stack_id) => stack_id,
+            None => continue,
+        };
+        if let Some(parsed) = utils::parse_index_id(&format!("{},{}", stack_id, name)) {
+            out.entry(stack_id)
+                .or_default()
+                .insert((parsed.offset_data, parsed.cookie));
+        }
+    }
+    Ok(out)
+}
+
+#[tokio::test]
+async fn test_delete_records() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use super::WriterOptions;
+    use futures::StreamExt;
+
+    let (op, _dir) = temp_operator("delete");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            max_records: Some(5),
+            ..Default::default()
+        },
+    );
+    let mut records = vec![];
+    for i in 0..10 {
+        let data = vec![i as u8; 100 + i];
+        let index_id = writer
+            .put(data.clone(), format!("f{}", i), None)
+            .await
+            .unwrap();
+        records.push((index_id, data));
+    }
+    writer.close().await.unwrap();
+    for i in [0, 3, 7] {
+        delete(&op, "", &records[i].0).await.unwrap();
+        // deleting a deleted record is a no-op
+        delete(&op, "", &records[i].0).await.unwrap();
+    }
+    assert!(matches!(
+        delete(&op, "", "1,zz").await,
+        Err(ErrorKind::InvalidArgument(_))
+    ));
+    assert!(delete(&op, "", "1,12300000001").await.is_err());
+
+    let all = all_deleted_records(&op, "").await.unwrap();
+    let (first, second) = (stack_of(&records[0].0), stack_of(&records[7].0));
+    assert_ne!(first, second);
+    assert_eq!(all.len(), 2);
+    assert_eq!(all[&first], deleted_records(&op, "", first).await.unwrap());
+    assert_eq!(all[&first].len(), 2);
+    assert_eq!(all[&second].len(), 1);
+
+    let reader = new_reader(&op);
+    // deletion logs are not listed as stacks
+    assert_eq!(reader.list().await.unwrap().len(), 2);
+    for (i, (index_id, data)) in records.iter().enumerate() {
+        let res = reader.fetch(index_id, true).await;
+        if [0, 3, 7].contains(&i) {
+            assert!(matches!(res, Err(ErrorKind::NotFound(_))));
+            assert!(matches!(
+                reader.fetch_range(index_id, 0..1, true).await,
+                Err(ErrorKind::NotFound(_))
+            ));
+        } else {
+            assert_eq!(&res.unwrap(), data);
+        }
+    }
+    assert_eq!(reader.list_stack(first).await.unwrap().len(), 3);
+    let mut iter = reader.list_al_with_data_shuffled_iter(7, 1).await.unwrap();
+    let mut n = 0;
+    while let Some(res) = iter.next().await {
+        let (_, mr, _) = res.unwrap();
+        assert!(!["f0", "f3", "f7"].contains(&mr.filename.as_str()));
+        n += 1;
+    }
+    assert_eq!(n, 7);
+}
+
+#[tokio::test]
+async fn test_delete_with_live_reader() {
+    use super::bs_opendal_testing::{new_writer, stack_of, temp_operator};
+    use super::{BytestackOpendalReader, ReaderOptions};
+    use std::time::Duration;
+
+    let (op, _dir) = temp_operator("delete_live_reader");
+    let writer = new_writer(&op, Default::default());
+    let mut ids = vec![];
+    for i in 0..3u8 {
+        ids.push(writer.put(vec![i; 10], i.to_string(), None).await.unwrap());
+    }
+    writer.close().await.unwrap();
+    let stack_id = stack_of(&ids[0]);
+
+    let ttl = Duration::from_millis(200);
+    let reader = BytestackOpendalReader::new_with_options(
+        op.clone(),
+        String::new(),
+        None,
+        ReaderOptions {
+            tombstone_ttl: ttl,
+            ..Default::default()
+        },
+    );
+    assert_eq!(reader.fetch(&ids[1], true).await.unwrap(), vec![1; 10]);
+    assert_eq!(reader.list_stack(stack_id).await.unwrap().len(), 3);
+
+    // the reader lists the deletion log again once its cache expires.
+    delete(&op, "", &ids[1]).await.unwrap();
+    tokio::time::sleep(ttl).await;
+    assert!(matches!(
+        reader.fetch(&ids[1], true).await,
+        Err(ErrorKind::NotFound(_))
+    ));
+    let listed = reader.list_stack(stack_id).await.unwrap();
+    assert_eq!(listed.len(), 2);
+    assert!(listed
+        .iter()
+        .all(|ir| ir.offset_data != utils::parse_index_id(&ids[1]).unwrap().offset_data));
+
+    // with a zero ttl a deletion is seen right away.
+    let reader = BytestackOpendalReader::new_with_options(
+        op.clone(),
+        String::new(),
+        None,
+        ReaderOptions {
+            tombstone_ttl: Duration::ZERO,
+            ..Default::default()
+        },
+    );
+    assert_eq!(reader.fetch(&ids[2], true).await.unwrap(), vec![2; 10]);
+    delete(&op, "", &ids[2]).await.unwrap();
+    assert!(matches!(
+        reader.fetch(&ids[2], true).await,
+        Err(ErrorKind::NotFound(_))
+    ));
+    assert_eq!(reader.list_stack(stack_id).await.unwrap().len(), 1);
+}
@@ -0,0 +1,375 @@
+//! bs_opendal_index_cache provides a cache of parsed index files.
+//! Only index files of sealed stacks are cached, an entry is keyed by the location of index file and
+//! validated by ETag or last-modified of the object, so a rewritten index file is never served from cache.
+
+use crate::config::IndexCache as IndexCacheConfig;
+use crate::types::{IndexRecord, StackSeal};
+use log::debug;
+use opendal::Metadata;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// CachedIndex is a parsed index file: all records and the seal.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedIndex {
+    /// irs are all records in index file, deleted records are not filtered.
+    pub irs: Vec<IndexRecord>,
+    /// seal is the StackSeal at the end of index file, None for a V1 stack written before seals.
+    pub seal: Option<StackSeal>,
+    /// data_bytes is where records end in data file, told by the seal or the size of data file.
+    pub data_bytes: u64,
+}
+
+impl CachedIndex {
+    /// memory_size is the estimated bytes held in memory.
+    fn memory_size(&self) -> usize {
+        self.irs.len() * size_of::<IndexRecord>() + size_of::<CachedIndex>()
+    }
+}
+
+/// DiskEntry is how CachedIndex is persisted on disk, key and version are kept to check the file.
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    key: String,
+    version: String,
+    index: CachedIndex,
+}
+
+struct MemoryEntry {
+    version: String,
+    index: Arc<CachedIndex>,
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, MemoryEntry>,
+    /// lru is key of every entry by its last_used tick, the first one is the least recently used.
+    lru: BTreeMap<u64, String>,
+    memory_bytes: usize,
+    tick: u64,
+    stats: IndexCacheStats,
+}
+
+/// IndexCacheStats counts lookups of IndexCache.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IndexCacheStats {
+    /// hits is the number of lookups served from memory
+    pub hits: u64,
+    /// disk_hits is the number of lookups served from disk
+    pub disk_hits: u64,
+    /// misses is the number of lookups that need to read the index file
+    pub misses: u64,
+    /// entries is the number of index files in memory
+    pub entries: usize,
+    /// memory_bytes is the estimated bytes of index files in memory
+    pub memory_bytes: usize,
+}
+
+/// IndexCache keeps parsed index files in memory up to memory_budget bytes, the least recently used
+/// ones are evicted first. With disk_dir, index files are persisted there too and survive restarts.
+/// It can be shared by readers with ReaderOptions::index_cache.
+pub struct IndexCache {
+    memory_budget: usize,
+    disk_dir: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+impl IndexCache {
+    /// new IndexCache with memory_budget bytes in memory and optional disk_dir.
+    pub fn new(memory_budget: usize, disk_dir: Option<PathBuf>) -> Self {
+        IndexCache {
+            memory_budget,
+            disk_dir,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// new_from_config return IndexCache configured in Config, None if it is not configured.
+    pub fn new_from_config(cfg: &IndexCacheConfig) -> Option<Arc<Self>> {
+        if cfg.memory_bytes == 0 && cfg.disk_dir.is_empty() {
+            return None;
+        }
+        let disk_dir = if cfg.disk_dir.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(&cfg.disk_dir))
+        };
+        Some(Arc::new(IndexCache::new(
+            cfg.memory_bytes as usize,
+            disk_dir,
+        )))
+    }
+
+    /// stats return counters of this cache.
+    pub fn stats(&self) -> IndexCacheStats {
+        let inner = self.inner.lock().unwrap();
+        let mut stats = inner.stats.clone();
+        stats.entries = inner.entries.len();
+        stats.memory_bytes = inner.memory_bytes;
+        stats
+    }
+
+    /// get return the cached index of key if it is cached with version.
+    pub(crate) async fn get(&self, key: &str, version: &str) -> Option<Arc<CachedIndex>> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.tick += 1;
+            let tick = inner.tick;
+            let Inner { entries, lru, .. } = &mut *inner;
+            if let Some(entry) = entries.get_mut(key) {
+                if entry.version == version {
+                    lru.remove(&entry.last_used);
+                    lru.insert(tick, key.to_string());
+                    entry.last_used = tick;
+                    let index = entry.index.clone();
+                    inner.stats.hits += 1;
+                    return Some(index);
+                }
+            }
+        }
+        if let Some(index) = self.read_disk(key, version).await {
+            let index = Arc::new(index);
+            let mut inner = self.inner.lock().unwrap();
+            inner.stats.disk_hits += 1;
+            self.insert_memory(&mut inner, key, version, index.clone());
+            return Some(index);
+        }
+        self.inner.lock().unwrap().stats.misses += 1;
+        None
+    }
+
+    /// put save index of key with version in memory and on disk.
+    pub(crate) async fn put(
+        &self,
+        key: &str,
+        version: &str,
+        index: CachedIndex,
+    ) -> Arc<CachedIndex> {
+        self.write_disk(key, version, &index).await;
+        let index = Arc::new(index);
+        let mut inner = self.inner.lock().unwrap();
+        self.insert_memory(&mut inner, key, version, index.clone());
+        index
+    }
+
+    /// insert_memory insert index and evict least recently used entries until memory_budget is met,
+    /// an index larger than memory_budget is not kept in memory.
+    fn insert_memory(&self, inner: &mut Inner, key: &str, version: &str, index: Arc<CachedIndex>) {
+        if let Some(old) = inner.entries.remove(key) {
+            inner.lru.remove(&old.last_used);
+            inner.memory_bytes -= old.size;
+        }
+        let size = index.memory_size() + key.len() + version.len();
+        if size > self.memory_budget {
+            return;
+        }
+        while inner.memory_bytes + size > self.memory_budget {
+            let last_used = match inner.lru.keys().next() {
+                Some(last_used) => *last_used,
+                None => break,
+            };
+            let lru = inner.lru.remove(&last_used).unwrap();
+            if let Some(evicted) = inner.entries.remove(&lru) {
+                inner.memory_bytes -= evicted.size;
+            }
+        }
+        inner.tick += 1;
+        let last_used = inner.tick;
+        inner.memory_bytes += size;
+        inner.lru.insert(last_used, key.to_string());
+        inner.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                version: version.to_string(),
+                index,
+                size,
+                last_used,
+            },
+        );
+    }
+
+    /// disk_path return where index of key is persisted.
+    fn disk_path(&self, key: &str) -> Option<PathBuf> {
+        self.disk_dir.as_ref().map(|dir| {
+            dir.join(format!(
+                "{}.idxcache",
+                blake3::hash(key.as_bytes()).to_hex()
+            ))
+        })
+    }
+
+    async fn read_disk(&self, key: &str, version: &str) -> Option<CachedIndex> {
+        let path = self.disk_path(key)?;
+        let bs = tokio::fs::read(&path).await.ok()?;
+        match bincode::deserialize::<DiskEntry>(&bs) {
+            Ok(entry) if entry.key == key && entry.version == version => Some(entry.index),
+            Ok(_) => None,
+            Err(e) => {
+                debug!(target: "IndexCache", "drop broken cache file {:?}: {}", path, e);
+                let _ = tokio::fs::remove_file(&path).await;
+                None
+            }
+        }
+    }
+
+    /// write_disk persist index, a failure only costs a later read of the index file.
+    async fn write_disk(&self, key: &str, version: &str, index: &CachedIndex) {
+        let path = match self.disk_path(key) {
+            Some(path) => path,
+            None => return,
+        };
+        let entry = DiskEntry {
+            key: key.to_string(),
+            version: version.to_string(),
+            index: index.clone(),
+        };
+        let bs = bincode::serialize(&entry).unwrap();
+        // write to a temporary file and rename it, so that a reader never sees a partial file.
+        let tmp_path = path.with_extension(format!("tmp{}", rand::random::<u32>()));
+        let res = async {
+            tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+            tokio::fs::write(&tmp_path, bs).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        };
+        if let Err(e) = res.await {
+            debug!(target: "IndexCache", "persist {:?} error: {}", path, e);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+    }
+}
+
+impl std::fmt::Debug for IndexCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexCache")
+            .field("memory_budget", &self.memory_budget)
+            .field("disk_dir", &self.disk_dir)
+            .finish()
+    }
+}
+
+/// index_version return the version of an index file from its metadata, ETag is preferred and
+/// last-modified with content length is used if there is no ETag. None means it can not be cached.
+pub(crate) fn index_version(meta: &Metadata) -> Option<String> {
+    if let Some(etag) = meta.etag() {
+        return Some(etag.to_string());
+    }
+    meta.last_modified().map(|lm| {
+        format!(
+            "{}.{:09}-{}",
+            lm.timestamp(),
+            lm.timestamp_subsec_nanos(),
+            meta.content_length()
+        )
+    })
+}
+
+#[tokio::test]
+async fn test_evict_least_recently_used() {
+    let index = |n: u32| CachedIndex {
+        irs: (0..n)
+            .map(|i| IndexRecord::new(i, 4096 * i as u64, 10, 0, 10))
+            .collect(),
+        seal: None,
+        data_bytes: 0,
+    };
+    let entry_size = index(10).memory_size() + "a".len() + "v".len();
+    let cache = IndexCache::new(entry_size * 2, None);
+    cache.put("a", "v", index(10)).await;
+    cache.put("b", "v", index(10)).await;
+    // a is used after b, so b is evicted by c.
+    assert!(cache.get("a", "v").await.is_some());
+    cache.put("c", "v", index(10)).await;
+    assert!(cache.get("b", "v").await.is_none());
+    assert!(cache.get("a", "v").await.is_some());
+    assert!(cache.get("c", "v").await.is_some());
+    // another version of a is not served, an index larger than the budget is not kept.
+    assert!(cache.get("a", "w").await.is_none());
+    cache.put("d", "v", index(100)).await;
+    assert!(cache.get("d", "v").await.is_none());
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (3, 3, 2));
+    assert_eq!(stats.memory_bytes, entry_size * 2);
+}
+
+#[tokio::test]
+async fn test_disk_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let index = CachedIndex {
+        irs: vec![IndexRecord::new(1, 4096, 10, 0, 10)],
+        seal: None,
+        data_bytes: 8192,
+    };
+    IndexCache::new(1 << 20, Some(dir.path().to_path_buf()))
+        .put("a", "v", index)
+        .await;
+    // a new cache finds the index persisted by the old one.
+    let cache = IndexCache::new(1 << 20, Some(dir.path().to_path_buf()));
+    let cached = cache.get("a", "v").await.unwrap();
+    assert_eq!((cached.irs.len(), cached.data_bytes), (1, 8192));
+    assert!(cache.get("a", "w").await.is_none());
+    assert_eq!(cache.stats().disk_hits, 1);
+}
+
+#[tokio::test]
+async fn test_reader_index_cache() {
+    use super::bs_opendal_testing::{new_writer, stack_of, temp_operator};
+    use super::err::ErrorKind;
+    use super::{BytestackOpendalReader, FormatVersion, ReaderOptions, WriterOptions};
+    use crate::utils;
+
+    let (op, dir) = temp_operator("reader_index_cache");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            format_version: FormatVersion::V2,
+            ..Default::default()
+        },
+    );
+    let mut ids = vec![];
+    for i in 0..5u8 {
+        ids.push(writer.put(vec![i; 10], i.to_string(), None).await.unwrap());
+    }
+    writer.close().await.unwrap();
+    let stack_id = stack_of(&ids[0]);
+
+    let cache = Arc::new(IndexCache::new(1 << 20, Some(dir.path().join("cache"))));
+    let new_reader = |cache: Arc<IndexCache>| {
+        BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                index_cache: Some(cache),
+                ..Default::default()
+            },
+        )
+    };
+    let reader = new_reader(cache.clone());
+    assert_eq!(reader.list_stack(stack_id).await.unwrap().len(), 5);
+    assert_eq!(reader.list_stack(stack_id).await.unwrap().len(), 5);
+    assert_eq!(reader.stat(&ids[2]).await.unwrap().1.filename, "2");
+    let stats = cache.stats();
+    assert_eq!((stats.misses, stats.hits, stats.entries), (1, 2, 1));
+
+    // another cache on the same disk_dir reads the index from disk.
+    let disk_cache = Arc::new(IndexCache::new(1 << 20, Some(dir.path().join("cache"))));
+    let reader = new_reader(disk_cache.clone());
+    assert_eq!(reader.list_stack(stack_id).await.unwrap().len(), 5);
+    assert_eq!(disk_cache.stats().disk_hits, 1);
+
+    // a rewritten index file is read again, its seal is lost here.
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let index_path = dir.path().join(utils::get_index_file_path("", stack_id));
+    let bs = std::fs::read(&index_path).unwrap();
+    std::fs::write(&index_path, &bs[..bs.len() - StackSeal::size()]).unwrap();
+    assert!(matches!(
+        new_reader(cache.clone()).list_stack(stack_id).await,
+        Err(ErrorKind::UnsealedStack(_))
+    ));
+    assert_eq!(cache.stats().misses, 2);
+}
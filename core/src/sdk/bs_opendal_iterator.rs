@@ -0,0 +1,573 @@
+//! bs_opendal_iterator provides streams to scan records of a stack in opendal way.
+//! A record which can not be decoded is returned as an error and the scan goes on with next record,
+//! such records can be skipped and counted instead by ReaderOptions::skip_bad_records.
+//! An I/O error of the underlying reader ends the stream after it is returned.
+//! With readahead, data file is read by concurrent ranged reads ahead of the consumer instead of
+//! one sequential reader, records are still returned in the order of index file.
+
+use super::bs_opendal_batch::OpendalFetcher;
+use super::bs_opendal_crypto::StackCrypto;
+use super::bs_opendal_record;
+use super::err::{CustomError, ErrorKind};
+use crate::types::{DataRecordHeader, IndexRecord, MetaRecord};
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{AsyncReadExt, Stream, StreamExt};
+use opendal::{Operator, Reader};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// _SKIP_CHUNK_SIZE is the size of buffer used to skip bytes of data or meta file
+const _SKIP_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// ScanError tells a bad record from a broken reader.
+pub(crate) enum ScanError {
+    /// Record means this record is bad, the scan can go on with next record.
+    Record(ErrorKind),
+    /// Reader means the reader is broken, the scan can not go on.
+    Reader(ErrorKind),
+}
+
+/// RecordSource reads records of a stack one by one.
+pub(crate) trait RecordSource: Send + 'static {
+    type Item: Send + 'static;
+
+    /// next_record return None if there is no more record.
+    fn next_record(&mut self) -> BoxFuture<'_, Option<Result<Self::Item, ScanError>>>;
+}
+
+/// record_stream turn source into a stream, bad records are skipped and counted in error_count
+/// if skip_bad_records.
+pub(crate) fn record_stream<S: RecordSource>(
+    source: S,
+    skip_bad_records: bool,
+    error_count: Arc<AtomicU64>,
+) -> BoxStream<'static, Result<S::Item, ErrorKind>> {
+    futures::stream::unfold(Some(source), move |source| {
+        let error_count = error_count.clone();
+        async move {
+            let mut source = source?;
+            loop {
+                match source.next_record().await? {
+                    Ok(item) => return Some((Ok(item), Some(source))),
+                    Err(ScanError::Record(e)) => {
+                        error_count.fetch_add(1, Ordering::Relaxed);
+                        if !skip_bad_records {
+                            return Some((Err(e), Some(source)));
+                        }
+                    }
+                    Err(ScanError::Reader(e)) => {
+                        error_count.fetch_add(1, Ordering::Relaxed);
+                        return Some((Err(e), None));
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// skip_to read and drop bytes from reader at offset until target, in pieces of up to _SKIP_CHUNK_SIZE
+/// bytes so that skipping chunks of a large record or many deleted records does not take much memory.
+async fn skip_to(reader: &mut Reader, offset: &mut u64, target: u64) -> Result<(), ScanError> {
+    if target <= *offset {
+        return Ok(());
+    }
+    let mut skip_buf = vec![0; ((target - *offset) as usize).min(_SKIP_CHUNK_SIZE)];
+    while *offset < target {
+        let size = ((target - *offset) as usize).min(skip_buf.len());
+        read_exact(reader, &mut skip_buf[..size]).await?;
+        *offset += size as u64;
+    }
+    Ok(())
+}
+
+async fn read_exact(reader: &mut Reader, buf: &mut [u8]) -> Result<(), ScanError> {
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ScanError::Reader(ErrorKind::IOError(CustomError::new(
+            e.to_string(),
+        )))),
+    }
+}
+
+/// MetaScanner reads MetaRecords of records in irs from meta file sequentially.
+pub(crate) struct MetaScanner {
+    reader: Reader,
+    meta_offset: u64,
+    crypto: Option<Arc<StackCrypto>>,
+}
+
+impl MetaScanner {
+    /// new MetaScanner, reader starts at meta_offset of meta file. A reader starting at 0 skips
+    /// MetaMagicHeader before the first MetaRecord, whatever format version the file has.
+    pub(crate) fn new(reader: Reader, meta_offset: u64, crypto: Option<Arc<StackCrypto>>) -> Self {
+        MetaScanner {
+            reader,
+            meta_offset,
+            crypto,
+        }
+    }
+
+    /// read return MetaRecord of ir, MetaRecords of deleted records before it are skipped.
+    async fn read(&mut self, ir: &IndexRecord) -> Result<MetaRecord, ScanError> {
+        skip_to(&mut self.reader, &mut self.meta_offset, ir.offset_meta).await?;
+        let mut buf = vec![0; ir.size_meta as usize];
+        read_exact(&mut self.reader, &mut buf).await?;
+        self.meta_offset += buf.len() as u64;
+        decode_meta(&buf, ir, self.crypto.as_deref()).map_err(ScanError::Record)
+    }
+}
+
+/// decode_meta parse the MetaRecord of ir from buf, json or bincode, and decrypt it if it is encrypted.
+pub(crate) fn decode_meta(
+    buf: &[u8],
+    ir: &IndexRecord,
+    crypto: Option<&StackCrypto>,
+) -> Result<MetaRecord, ErrorKind> {
+    let mr = match MetaRecord::decode(buf) {
+        Ok(mr) => mr,
+        Err(e) => {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "meta of record at {}: {}",
+                ir.offset_data, e
+            ))));
+        }
+    };
+    open_meta(mr, crypto)
+}
+
+/// open_meta decrypt filename and extra of mr if they are encrypted.
+pub(crate) fn open_meta(
+    mut mr: MetaRecord,
+    crypto: Option<&StackCrypto>,
+) -> Result<MetaRecord, ErrorKind> {
+    if !mr.is_sealed() {
+        return Ok(mr);
+    }
+    match crypto {
+        Some(crypto) => crypto.open_meta(&mut mr)?,
+        None => {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "meta of record at {} is encrypted but stack has no key",
+                mr.offset_data
+            ))));
+        }
+    }
+    Ok(mr)
+}
+
+/// MetaSource yields IndexRecord and MetaRecord.
+pub(crate) struct MetaSource {
+    pub(crate) irs: std::vec::IntoIter<IndexRecord>,
+    pub(crate) meta: MetaScanner,
+}
+
+impl RecordSource for MetaSource {
+    type Item = (IndexRecord, MetaRecord);
+
+    fn next_record(&mut self) -> BoxFuture<'_, Option<Result<Self::Item, ScanError>>> {
+        Box::pin(async move {
+            let ir = self.irs.next()?;
+            Some(self.meta.read(&ir).await.map(|mr| (ir, mr)))
+        })
+    }
+}
+
+/// DataItem is what DataSource yields.
+pub(crate) type DataItem = (IndexRecord, MetaRecord, Vec<u8>);
+
+/// DataSource yields IndexRecord, MetaRecord and data.
+pub(crate) struct DataSource {
+    pub(crate) irs: std::vec::IntoIter<IndexRecord>,
+    pub(crate) meta: MetaScanner,
+    pub(crate) data_reader: Reader,
+    pub(crate) data_offset: u64,
+    pub(crate) operator: Operator,
+    pub(crate) data_file_path: String,
+    pub(crate) crypto: Option<Arc<StackCrypto>>,
+}
+
+impl DataSource {
+    /// read_data return data of ir, records whose index entry was never written, like a failed
+    /// put_reader, and deleted records before it are skipped.
+    async fn read_data(&mut self, ir: &IndexRecord) -> Result<Vec<u8>, ScanError> {
+        skip_to(&mut self.data_reader, &mut self.data_offset, ir.offset_data).await?;
+        let mut head_buf = vec![0; DataRecordHeader::size()];
+        read_exact(&mut self.data_reader, &mut head_buf).await?;
+        self.data_offset += head_buf.len() as u64;
+        let drh = match DataRecordHeader::new_from_bytes(&head_buf) {
+            Ok(drh) if drh.validate_magic() && drh.cookie == ir.cookie => drh,
+            // the body size is unknown, next record is found by its offset_data.
+            _ => {
+                return Err(ScanError::Record(ErrorKind::CorruptedStack(
+                    CustomError::new(format!("bad header of record at {}", ir.offset_data)),
+                )));
+            }
+        };
+        // only stored bytes are read, padding is skipped by skip_to of the next record,
+        // whatever alignment the stack has.
+        let mut data_buf = vec![0; drh.stored_size()];
+        read_exact(&mut self.data_reader, &mut data_buf).await?;
+        self.data_offset += data_buf.len() as u64;
+        if drh.is_large() {
+            return self.read_large(ir, &drh, &data_buf).await;
+        }
+        let data = if drh.is_link() {
+            bs_opendal_record::resolve_link(
+                &self.operator,
+                &self.data_file_path,
+                &drh,
+                &data_buf,
+                self.crypto.as_deref(),
+                false,
+            )
+            .await
+        } else {
+            bs_opendal_record::decode_body(
+                &drh,
+                ir.offset_data,
+                data_buf,
+                self.crypto.as_deref(),
+                false,
+            )
+        };
+        data.map_err(ScanError::Record)
+    }
+
+    /// read_large return data of large record ir from its chunk records, which follow it in data file.
+    async fn read_large(
+        &mut self,
+        ir: &IndexRecord,
+        drh: &DataRecordHeader,
+        body: &[u8],
+    ) -> Result<Vec<u8>, ScanError> {
+        let large = bs_opendal_record::parse_large(drh, body).map_err(ScanError::Record)?;
+        let mut data = Vec::with_capacity(large.size as usize);
+        for (chunk_offset, size) in large.chunks(ir.offset_data, drh.checksum()) {
+            skip_to(&mut self.data_reader, &mut self.data_offset, chunk_offset).await?;
+            let mut head_buf = vec![0; DataRecordHeader::size()];
+            read_exact(&mut self.data_reader, &mut head_buf).await?;
+            self.data_offset += head_buf.len() as u64;
+            let chunk = match DataRecordHeader::new_from_bytes(&head_buf) {
+                Ok(chunk) if chunk.validate_magic() && chunk.cookie == ir.cookie => chunk,
+                _ => {
+                    return Err(ScanError::Record(ErrorKind::CorruptedStack(
+                        CustomError::new(format!("bad header of chunk at {}", chunk_offset)),
+                    )));
+                }
+            };
+            // a chunk of another size is rejected by decode_chunk, no more than size and a trailer are read.
+            let trailer_size = drh.checksum().size();
+            let mut chunk_body = vec![0; (size as usize + trailer_size).min(chunk.stored_size())];
+            read_exact(&mut self.data_reader, &mut chunk_body).await?;
+            self.data_offset += chunk_body.len() as u64;
+            let chunk_data = bs_opendal_record::decode_chunk(
+                &chunk,
+                chunk_offset,
+                size,
+                drh.checksum(),
+                chunk_body,
+                false,
+            )
+            .map_err(ScanError::Record)?;
+            data.extend(chunk_data);
+        }
+        Ok(data)
+    }
+}
+
+impl RecordSource for DataSource {
+    type Item = DataItem;
+
+    fn next_record(&mut self) -> BoxFuture<'_, Option<Result<Self::Item, ScanError>>> {
+        Box::pin(async move {
+            let ir = self.irs.next()?;
+            let mr = match self.meta.read(&ir).await {
+                Ok(mr) => mr,
+                Err(e) => return Some(Err(e)),
+            };
+            Some(self.read_data(&ir).await.map(|data| (ir, mr, data)))
+        })
+    }
+}
+
+/// FetchedChunk is the data of records in one ranged read, (position in scan, data) of every record.
+type FetchedChunk = Vec<(usize, Result<Vec<u8>, ErrorKind>)>;
+
+/// ReadaheadSource yields IndexRecord, MetaRecord and data like DataSource, but data file is read by
+/// up to concurrency ranged reads ahead of the consumer, each of them is decoded in its own task.
+/// At most concurrency chunks are in flight or waiting for the consumer, so memory stays bounded.
+pub(crate) struct ReadaheadSource {
+    irs: std::vec::IntoIter<IndexRecord>,
+    meta: MetaScanner,
+    chunks: BoxStream<'static, Result<FetchedChunk, ErrorKind>>,
+    current: std::vec::IntoIter<(usize, Result<Vec<u8>, ErrorKind>)>,
+}
+
+impl ReadaheadSource {
+    /// new ReadaheadSource, fetchers cover irs in order.
+    pub(crate) fn new(
+        irs: Vec<IndexRecord>,
+        meta: MetaScanner,
+        fetchers: Vec<OpendalFetcher>,
+        concurrency: usize,
+    ) -> Self {
+        let chunks = futures::stream::iter(fetchers)
+            .map(|fetcher| async move {
+                let task = tokio::spawn(async move {
+                    let buf = fetcher.read_range().await?;
+                    Ok(fetcher.decode_all(&buf).await)
+                });
+                match task.await {
+                    Ok(res) => res,
+                    Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+                }
+            })
+            .buffered(concurrency.max(1))
+            .boxed();
+        ReadaheadSource {
+            irs: irs.into_iter(),
+            meta,
+            chunks,
+            current: Vec::new().into_iter(),
+        }
+    }
+
+    /// next_data return data of next record, it is taken from the current chunk or the next one.
+    async fn next_data(&mut self) -> Result<Vec<u8>, ScanError> {
+        loop {
+            if let Some((_, data)) = self.current.next() {
+                return data.map_err(ScanError::Record);
+            }
+            match self.chunks.next().await {
+                Some(Ok(chunk)) => self.current = chunk.into_iter(),
+                Some(Err(e)) => return Err(ScanError::Reader(e)),
+                None => {
+                    return Err(ScanError::Reader(ErrorKind::IOError(CustomError::new(
+                        String::from("data of record is not fetched"),
+                    ))));
+                }
+            }
+        }
+    }
+}
+
+impl RecordSource for ReadaheadSource {
+    type Item = DataItem;
+
+    fn next_record(&mut self) -> BoxFuture<'_, Option<Result<Self::Item, ScanError>>> {
+        Box::pin(async move {
+            let ir = self.irs.next()?;
+            // data is taken first so that a bad meta does not shift data of the following records.
+            let data = self.next_data().await;
+            let mr = match self.meta.read(&ir).await {
+                Ok(mr) => mr,
+                Err(e) => return Some(Err(e)),
+            };
+            Some(data.map(|data| (ir, mr, data)))
+        })
+    }
+}
+
+/// BytestackOpendalIterator is a stream of IndexRecord and MetaRecord of a stack in opendal way.
+pub struct BytestackOpendalIterator {
+    inner: BoxStream<'static, Result<(IndexRecord, MetaRecord), ErrorKind>>,
+    error_count: Arc<AtomicU64>,
+}
+
+impl BytestackOpendalIterator {
+    pub(crate) fn new(source: MetaSource, skip_bad_records: bool) -> Self {
+        let error_count = Arc::new(AtomicU64::new(0));
+        BytestackOpendalIterator {
+            inner: record_stream(source, skip_bad_records, error_count.clone()),
+            error_count,
+        }
+    }
+
+    /// error_count return how many errors are met so far, skipped records included.
+    pub fn error_count(&self) -> u64 {
+        self.error_count.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for BytestackOpendalIterator {
+    type Item = Result<(IndexRecord, MetaRecord), ErrorKind>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// BytestackopendalDataIterator is a stream of IndexRecord, MetaRecord and data of a stack in opendal way.
+pub struct BytestackopendalDataIterator {
+    inner: BoxStream<'static, Result<DataItem, ErrorKind>>,
+    error_count: Arc<AtomicU64>,
+}
+
+impl BytestackopendalDataIterator {
+    pub(crate) fn new<S: RecordSource<Item = DataItem>>(source: S, skip_bad_records: bool) -> Self {
+        let error_count = Arc::new(AtomicU64::new(0));
+        BytestackopendalDataIterator {
+            inner: record_stream(source, skip_bad_records, error_count.clone()),
+            error_count,
+        }
+    }
+
+    /// error_count return how many errors are met so far, skipped records included.
+    pub fn error_count(&self) -> u64 {
+        self.error_count.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for BytestackopendalDataIterator {
+    type Item = Result<(IndexRecord, MetaRecord, Vec<u8>), ErrorKind>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_skip_to() {
+    let operator = Operator::new(opendal::services::Memory::default())
+        .unwrap()
+        .finish();
+    let data: Vec<u8> = (0..3 * _SKIP_CHUNK_SIZE + 7).map(|i| i as u8).collect();
+    operator.write("skip", data.clone()).await.unwrap();
+    let mut reader = operator.reader("skip").await.unwrap();
+    let mut offset = 0;
+    let target = 2 * _SKIP_CHUNK_SIZE as u64 + 3;
+    skip_to(&mut reader, &mut offset, target)
+        .await
+        .ok()
+        .unwrap();
+    assert_eq!(offset, target);
+    skip_to(&mut reader, &mut offset, 1).await.ok().unwrap();
+    assert_eq!(offset, target);
+    let mut buf = vec![0; 4];
+    read_exact(&mut reader, &mut buf).await.ok().unwrap();
+    assert_eq!(buf, data[target as usize..target as usize + 4]);
+}
+
+#[tokio::test]
+async fn test_iterate_bad_record() {
+    use super::bs_opendal_testing::{new_writer, stack_of, temp_operator};
+    use super::{BytestackOpendalReader, ReaderOptions};
+    use crate::utils;
+    use futures::StreamExt;
+
+    let (op, dir) = temp_operator("iterate_bad_record");
+    let writer = new_writer(&op, Default::default());
+    let mut ids = vec![];
+    for i in 0..5u8 {
+        ids.push(
+            writer
+                .put(vec![i; 5000], i.to_string(), None)
+                .await
+                .unwrap(),
+        );
+    }
+    writer.close().await.unwrap();
+    let stack_id = stack_of(&ids[0]);
+    // break the header magic of record 2.
+    let data_path = dir.path().join(utils::get_data_file_path("", stack_id));
+    let mut bs = std::fs::read(&data_path).unwrap();
+    let offset = utils::parse_index_id(&ids[2]).unwrap().offset_data as usize;
+    bs[offset] ^= 0xff;
+    std::fs::write(&data_path, bs).unwrap();
+
+    for (skip_bad_records, readahead_bytes) in
+        [(false, 0), (true, 0), (false, 1 << 20), (true, 1 << 20)]
+    {
+        let reader = BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                skip_bad_records,
+                readahead_bytes,
+                ..Default::default()
+            },
+        );
+        let mut iter = reader.list_stack_al_with_data_iter(stack_id).await.unwrap();
+        let mut got = vec![];
+        while let Some(res) = iter.next().await {
+            got.push(res.map(|(_, mr, data)| (mr.filename, data[0])));
+        }
+        // the bad record is an error or skipped, records after it are still read.
+        let expected: Vec<(String, u8)> =
+            [0, 1, 3, 4].iter().map(|i| (i.to_string(), *i)).collect();
+        assert_eq!(iter.error_count(), 1);
+        if !skip_bad_records {
+            assert_eq!(got.len(), 5);
+            assert!(got.remove(2).is_err());
+        }
+        let got: Vec<(String, u8)> = got.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(got, expected);
+    }
+    // meta of every record is fine.
+    let reader = BytestackOpendalReader::new(op.clone(), String::new(), None);
+    let iter = reader.list_stack_al_iter(stack_id).await.unwrap();
+    let names: Vec<String> = iter.map(|res| res.unwrap().1.filename).collect().await;
+    assert_eq!(names, vec!["0", "1", "2", "3", "4"]);
+}
+
+#[tokio::test]
+async fn test_readahead_scan() {
+    use super::bs_opendal_deletion;
+    use super::bs_opendal_testing::{new_writer, stack_of, temp_operator};
+    use super::{BytestackOpendalReader, ReaderOptions, WriterOptions};
+    use futures::StreamExt;
+
+    let (op, _dir) = temp_operator("readahead_scan");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            dedup: true,
+            ..Default::default()
+        },
+    );
+    let mut ids = vec![];
+    for i in 0..30u32 {
+        // records after the first ten repeat earlier ones, large ones are saved as links.
+        let data = vec![(i % 10) as u8; 100 + (i % 10) as usize * 2000];
+        ids.push(writer.put(data, i.to_string(), None).await.unwrap());
+    }
+    writer.close().await.unwrap();
+    let stack_id = stack_of(&ids[0]);
+    for i in [0, 13, 29] {
+        bs_opendal_deletion::delete(&op, "", &ids[i]).await.unwrap();
+    }
+
+    let scan = |readahead_bytes, readahead_records, readahead_concurrency| {
+        let reader = BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                readahead_bytes,
+                readahead_records,
+                readahead_concurrency,
+                ..Default::default()
+            },
+        );
+        async move {
+            let iter = reader.list_stack_al_with_data_iter(stack_id).await.unwrap();
+            iter.map(|res| {
+                let (ir, mr, data) = res.unwrap();
+                (ir.offset_data, mr.filename, data)
+            })
+            .collect::<Vec<_>>()
+            .await
+        }
+    };
+    let expected = scan(0, 0, 1).await;
+    assert_eq!(expected.len(), 27);
+    for (bytes, records, concurrency) in [(1, 0, 1), (0, 1, 4), (64 * 1024, 0, 3), (1 << 20, 5, 8)]
+    {
+        assert_eq!(scan(bytes, records, concurrency).await, expected);
+    }
+}
@@ -0,0 +1,208 @@
+//! bs_opendal_names provides the filename index of stacks in opendal way.
+//! Filename index of a stack is written when it is sealed, it holds a NameEntry for every record
+//! sorted by the hash of filename, so a filename is looked up by binary search.
+
+use super::err::{CustomError, ErrorKind};
+use crate::types::names::{self, NAME_INDEX_FLAG_KEYED};
+use crate::types::{NameEntry, NameIndexHeader};
+use crate::utils;
+use opendal::{ErrorKind as OpendalErrorKind, Operator};
+
+/// NameIndex is a parsed filename index file.
+pub(crate) struct NameIndex {
+    pub(crate) header: NameIndexHeader,
+    pub(crate) entries: Vec<NameEntry>,
+}
+
+impl NameIndex {
+    /// new_from_hashes build NameIndex from name hashes of all records in the order of index file.
+    pub(crate) fn new_from_hashes(stack_id: u64, hashes: &[[u8; 16]], keyed: bool) -> Self {
+        let mut entries: Vec<NameEntry> = hashes
+            .iter()
+            .enumerate()
+            .map(|(position, hash)| NameEntry::new(*hash, position as u32))
+            .collect();
+        entries.sort();
+        let flags = if keyed { NAME_INDEX_FLAG_KEYED } else { 0 };
+        NameIndex {
+            header: NameIndexHeader::new(stack_id, entries.len() as u64, flags, 0),
+            entries,
+        }
+    }
+
+    /// to_bytes serialize header and entries, entries_crc of header is filled.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut entries_bytes = Vec::with_capacity(self.entries.len() * NameEntry::size());
+        for entry in &self.entries {
+            entries_bytes.extend(bincode::serialize(entry).unwrap());
+        }
+        let header = NameIndexHeader::new(
+            self.header.stack_id,
+            self.entries.len() as u64,
+            self.header.flags,
+            utils::CASTAGNOLI.checksum(&entries_bytes),
+        );
+        let mut out = bincode::serialize(&header).unwrap();
+        out.extend(entries_bytes);
+        out
+    }
+
+    /// new_from_bytes parse a filename index file of stack_id.
+    pub(crate) fn new_from_bytes(stack_id: u64, bs: &[u8]) -> Result<Self, ErrorKind> {
+        if bs.len() < NameIndexHeader::size() {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "filename index of stack {} is truncated",
+                stack_id
+            ))));
+        }
+        let header = match bincode::deserialize::<NameIndexHeader>(&bs[..NameIndexHeader::size()]) {
+            Ok(header) => header,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        let entries_bytes = &bs[NameIndexHeader::size()..];
+        if !header.valid()
+            || header.stack_id != stack_id
+            || entries_bytes.len() as u64 != header.entry_count * NameEntry::size() as u64
+            || utils::CASTAGNOLI.checksum(entries_bytes) != header.entries_crc
+        {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "filename index of stack {} mismatch its header",
+                stack_id
+            ))));
+        }
+        let mut entries = Vec::with_capacity(header.entry_count as usize);
+        for chunk in entries_bytes.chunks(NameEntry::size()) {
+            match NameEntry::new_from_bytes(chunk) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+            }
+        }
+        Ok(NameIndex { header, entries })
+    }
+
+    /// lookup return positions of records named filename, the last written comes first.
+    pub(crate) fn lookup(&self, filename: &str, key: Option<&[u8; 32]>) -> Vec<u32> {
+        let hash = names::name_hash(filename, key);
+        let start = self.entries.partition_point(|e| e.name_hash < hash);
+        let mut positions: Vec<u32> = self.entries[start..]
+            .iter()
+            .take_while(|e| e.name_hash == hash)
+            .map(|e| e.position)
+            .collect();
+        positions.reverse();
+        positions
+    }
+}
+
+/// read_name_index read filename index of stack_id, None if the stack has no filename index,
+/// like stacks written before filename index exists.
+pub(crate) async fn read_name_index(
+    operator: &Operator,
+    prefix: &str,
+    stack_id: u64,
+) -> Result<Option<NameIndex>, ErrorKind> {
+    let names_file_path = utils::get_names_file_path(prefix, stack_id);
+    let bs = match operator.read(&names_file_path).await {
+        Ok(bs) => bs,
+        Err(e) if e.kind() == OpendalErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    };
+    Ok(Some(NameIndex::new_from_bytes(stack_id, &bs)?))
+}
+
+#[tokio::test]
+async fn test_stat_by_name() {
+    use super::bs_opendal_testing::{new_writer, temp_operator};
+    use super::{
+        bs_opendal_deletion, BytestackOpendalReader, IndexCache, ReaderOptions, StackIdAllocator,
+        WriterOptions,
+    };
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    /// Descending gives stack_ids in decreasing order, so the newest stack has the smallest stack_id.
+    struct Descending(Mutex<u64>);
+    #[async_trait]
+    impl StackIdAllocator for Descending {
+        async fn next_stack_id(&self) -> Result<u64, ErrorKind> {
+            let mut next = self.0.lock().unwrap();
+            *next -= 1;
+            Ok(*next)
+        }
+    }
+
+    let (op, _dir) = temp_operator("names");
+    let allocator: Arc<dyn StackIdAllocator> = Arc::new(Descending(Mutex::new(100)));
+    for s in 0..3u8 {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                stack_id_allocator: Some(allocator.clone()),
+                ..Default::default()
+            },
+        );
+        for i in 0..20u8 {
+            writer
+                .put(vec![s, i], format!("s{}-{}", s, i), None)
+                .await
+                .unwrap();
+        }
+        writer
+            .put(vec![s, 100], String::from("dup"), None)
+            .await
+            .unwrap();
+        writer
+            .put(vec![s, 101], String::from("dup"), None)
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+        // last modified time of index files tells which stack is sealed later.
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    let cache = Arc::new(IndexCache::new(1 << 20, None));
+    let new_reader = || {
+        BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                index_cache: Some(cache.clone()),
+                ..Default::default()
+            },
+        )
+    };
+    let reader = new_reader();
+    assert!(matches!(
+        reader.fetch_by_name("nope", true).await,
+        Err(ErrorKind::NotFound(_))
+    ));
+    // filename index has no candidate, no index file is read.
+    assert_eq!(cache.stats().misses, 0);
+    assert_eq!(
+        reader.fetch_by_name("s1-7", true).await.unwrap(),
+        vec![1, 7]
+    );
+    assert_eq!(cache.stats().misses, 1);
+    let (index_id, ir, mr) = reader.stat_by_name("s0-3").await.unwrap();
+    assert_eq!(mr.filename, "s0-3");
+    assert_eq!(ir.size_data, 2);
+    assert!(index_id.starts_with("99,"));
+
+    // the newest stack wins though it has the smallest stack_id, the last written record wins in it.
+    let (index_id, _, _) = reader.stat_by_name("dup").await.unwrap();
+    assert!(index_id.starts_with("97,"));
+    assert_eq!(
+        reader.fetch_by_name("dup", true).await.unwrap(),
+        vec![2, 101]
+    );
+    bs_opendal_deletion::delete(&op, "", &index_id)
+        .await
+        .unwrap();
+    let reader = new_reader();
+    assert_eq!(
+        reader.fetch_by_name("dup", true).await.unwrap(),
+        vec![2, 100]
+    );
+}
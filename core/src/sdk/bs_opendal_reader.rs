@@ -1,130 +1,49 @@
 //! bs_reader provides all tools for reading bytestacks
 
+pub use super::bs_opendal_batch::OpendalFetcher;
+use super::bs_opendal_batch::{self, StackSource};
+use super::bs_opendal_crypto::{self, StackCrypto};
+use super::bs_opendal_deletion;
+use super::bs_opendal_index_cache::{self, CachedIndex};
+use super::bs_opendal_iterator::{open_meta, DataSource, MetaScanner, MetaSource, ReadaheadSource};
+pub use super::bs_opendal_iterator::{BytestackOpendalIterator, BytestackopendalDataIterator};
+use super::bs_opendal_names;
+use super::bs_opendal_record;
+use super::bs_opendal_shuffle::{EpochSource, ShuffleStack};
+use super::bs_opendal_verify::{self, VerifyLevel, VerifyProblem, VerifyReport};
 use super::err::{CustomError, ErrorKind};
-use crate::types::{
-    DataRecordHeader, IndexMagicHeader, IndexRecord, MetaMagicHeader, MetaRecord, Stack,
-};
+use super::ReaderOptions;
+use crate::types::data::{DataSealedHeader, DATA_SEALED_HEADER_OFFSET};
+use crate::types::{FormatVersion, IndexMagicHeader, IndexRecord, MetaRecord, Stack, StackSeal};
 use crate::utils;
-use futures::AsyncReadExt;
+use crate::utils::IndexID;
+use futures::StreamExt;
 use futures::TryStreamExt;
+use log::{debug, warn};
 use opendal::EntryMode;
 use opendal::Metakey;
 use opendal::Operator;
-use opendal::Reader;
 use proto::controller::controller_client::ControllerClient;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use tonic::transport::Channel;
 
+/// DeletedRecords is (offset_data, cookie) of every deleted record in a stack.
+type DeletedRecords = HashSet<(u64, u32)>;
+
 /// BytestackReader is tool for reading the bytestack
 pub struct BytestackOpendalReader {
-    controller_cli: ControllerClient<Channel>,
+    controller_cli: Option<ControllerClient<Channel>>,
     operator: Operator,
     prefix: String,
-}
-
-/// BytestackOpendalIterator is helper to iterator index items and meta items in opendal way;
-pub struct BytestackOpendalIterator {
-    irs: Vec<IndexRecord>,
-    reader: Reader,
-}
-
-impl BytestackOpendalIterator {
-    /// next work like iterator but async version
-    /// return (ir, mr) if there is, and return None if there is not.
-    pub async fn next(&mut self) -> Option<(IndexRecord, MetaRecord)> {
-        if self.irs.len() == 0 {
-            return None;
-        }
-        let ir = self.irs.remove(0);
-        let mut buf = Vec::with_capacity(ir.size_meta as usize);
-        buf.resize(ir.size_meta as usize, 0);
-        match self.reader.read_exact(&mut buf).await {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("next error {}", e);
-                return None;
-            }
-        };
-        let mr = match serde_json::from_slice::<MetaRecord>(&buf) {
-            Ok(mr) => mr,
-            Err(e) => {
-                eprintln!("deserialize error {}", e);
-                return None;
-            }
-        };
-        Some((ir, mr))
-    }
-}
-
-/// BytestackopendalDataIterator implement next for scan IndexRecord, MetaRecord and DataRecord
-pub struct BytestackopendalDataIterator {
-    irs: Vec<IndexRecord>,
-    meta_reader: Reader,
-    data_reader: Reader,
-}
-
-impl BytestackopendalDataIterator {
-    /// next work like iterator but async version
-    /// return (ir, mr) if there is, and return None if there is not.
-    pub async fn next(&mut self) -> Option<(IndexRecord, MetaRecord, Vec<u8>)> {
-        if self.irs.len() == 0 {
-            return None;
-        }
-        let ir = self.irs.remove(0);
-        let mut buf = Vec::with_capacity(ir.size_meta as usize);
-        buf.resize(ir.size_meta as usize, 0);
-        match self.meta_reader.read_exact(&mut buf).await {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("next error {}", e);
-                return None;
-            }
-        };
-        let mr = match serde_json::from_slice::<MetaRecord>(&buf) {
-            Ok(mr) => mr,
-            Err(e) => {
-                eprintln!("deserialize error {}", e);
-                return None;
-            }
-        };
-
-        let mut data_buf = Vec::new();
-        data_buf.resize(
-            DataRecordHeader::size() + crate::types::data::padding_data_size(ir.size_data as usize),
-            0,
-        );
-        match self.data_reader.read_exact(&mut data_buf).await {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("next error {}", e);
-                return None;
-            }
-        }
-        let drh = match DataRecordHeader::new_from_bytes(&data_buf[..DataRecordHeader::size()]) {
-            Ok(drh) => drh,
-            Err(e) => {
-                eprintln!("deserialize error {}", e);
-                return None;
-            }
-        };
-        if !drh.validate_magic() {
-            // TODO: verify but not panic, just for test now
-            panic!("drh magic error")
-        }
-        data_buf.drain(..DataRecordHeader::size());
-        data_buf.drain(ir.size_data as usize..);
-        Some((ir, mr, data_buf))
-    }
-}
-
-/// Fetcher is return by batch_fetch api
-pub struct OpendalFetcher {}
-
-impl OpendalFetcher {
-    /// do_fetch fetch the data for user
-    pub async fn do_fetch() -> Result<Vec<u8>, opendal::Error> {
-        todo!()
-    }
+    options: ReaderOptions,
+    stack_cryptos: Mutex<HashMap<u64, Option<Arc<StackCrypto>>>>,
+    /// stack_tombstones keeps deleted records of every stack with when they are listed.
+    stack_tombstones: Mutex<HashMap<u64, (Instant, Arc<DeletedRecords>)>>,
 }
 
 impl BytestackOpendalReader {
@@ -132,25 +51,105 @@ impl BytestackOpendalReader {
     pub fn new(
         operator: Operator,
         prefix: String,
-        controller_cli: ControllerClient<Channel>,
+        controller_cli: Option<ControllerClient<Channel>>,
+    ) -> Self {
+        Self::new_with_options(operator, prefix, controller_cli, ReaderOptions::default())
+    }
+
+    /// new_with_options create BytestackOpendalReader with options
+    pub fn new_with_options(
+        operator: Operator,
+        prefix: String,
+        controller_cli: Option<ControllerClient<Channel>>,
+        options: ReaderOptions,
     ) -> Self {
         Self {
             controller_cli,
             operator,
             prefix,
+            options,
+            stack_cryptos: Mutex::new(HashMap::new()),
+            stack_tombstones: Mutex::new(HashMap::new()),
         }
     }
 
+    /// stack_crypto return the cipher of stack_id, None if it is not encrypted.
+    /// The encryption header is read once per stack and cached.
+    async fn stack_crypto(&self, stack_id: u64) -> Result<Option<Arc<StackCrypto>>, ErrorKind> {
+        if let Some(crypto) = self.stack_cryptos.lock().unwrap().get(&stack_id) {
+            return Ok(crypto.clone());
+        }
+        let data_file_path = utils::get_data_file_path(&self.prefix, stack_id);
+        let crypto = bs_opendal_crypto::read_stack_crypto(
+            &self.operator,
+            &data_file_path,
+            stack_id,
+            self.options.keyring.as_deref(),
+        )
+        .await?
+        .map(Arc::new);
+        self.stack_cryptos
+            .lock()
+            .unwrap()
+            .insert(stack_id, crypto.clone());
+        Ok(crypto)
+    }
+
+    /// deleted_records return (offset_data, cookie) of every deleted record in stack_id.
+    /// The deletion log is cached for ReaderOptions::tombstone_ttl and listed again after that, so
+    /// records deleted while this reader is alive are not returned once the cache expires.
+    async fn deleted_records(&self, stack_id: u64) -> Result<Arc<DeletedRecords>, ErrorKind> {
+        if let Some(deleted) = self.cached_deleted_records(stack_id) {
+            return Ok(deleted);
+        }
+        let listed_at = Instant::now();
+        let deleted = Arc::new(
+            bs_opendal_deletion::deleted_records(&self.operator, &self.prefix, stack_id).await?,
+        );
+        self.stack_tombstones
+            .lock()
+            .unwrap()
+            .insert(stack_id, (listed_at, deleted.clone()));
+        Ok(deleted)
+    }
+
+    /// cached_deleted_records return deleted records of stack_id if they are listed within
+    /// ReaderOptions::tombstone_ttl.
+    fn cached_deleted_records(&self, stack_id: u64) -> Option<Arc<DeletedRecords>> {
+        match self.stack_tombstones.lock().unwrap().get(&stack_id) {
+            Some((listed_at, deleted)) if listed_at.elapsed() < self.options.tombstone_ttl => {
+                Some(deleted.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// load_deleted_records cache deleted records of every stack in stack_ids whose cache is missing
+    /// or expired, deletion logs of all stacks under this path are read by a single list.
+    async fn load_deleted_records(&self, stack_ids: &[u64]) -> Result<(), ErrorKind> {
+        if stack_ids
+            .iter()
+            .all(|stack_id| self.cached_deleted_records(*stack_id).is_some())
+        {
+            return Ok(());
+        }
+        let listed_at = Instant::now();
+        let mut all =
+            bs_opendal_deletion::all_deleted_records(&self.operator, &self.prefix).await?;
+        let mut cached = self.stack_tombstones.lock().unwrap();
+        for stack_id in stack_ids {
+            let deleted = all.remove(stack_id).unwrap_or_default();
+            cached.insert(*stack_id, (listed_at, Arc::new(deleted)));
+        }
+        Ok(())
+    }
+
     /// list return all stack(stack_id only) under this path
     pub async fn list(&self) -> Result<Vec<u64>, opendal::Error> {
         let mut out = Vec::<u64>::new();
         let mut ds = self.operator.list_with(self.prefix.as_str()).await?;
         while let Some(de) = ds.try_next().await? {
-            let meta = self
-                .operator
-                .metadata(&de, Metakey::Mode & Metakey::LastModified)
-                .await
-                .unwrap();
+            let meta = self.operator.metadata(&de, Metakey::Mode).await.unwrap();
             match meta.mode() {
                 EntryMode::FILE => {
                     if de.name().ends_with(".idx") {
@@ -158,6 +157,8 @@ impl BytestackOpendalReader {
                         out.push(stack_id_u64);
                     }
                 }
+                // deletion logs of stacks are the only dirs bytestack writes.
+                EntryMode::DIR if de.name().ends_with(".del/") => continue,
                 EntryMode::DIR => {
                     println!("skip dir {}", de.path())
                 }
@@ -174,21 +175,29 @@ impl BytestackOpendalReader {
         while let Some(de) = ds.try_next().await? {
             let meta = self
                 .operator
-                .metadata(&de, Metakey::Mode & Metakey::LastModified)
+                .metadata(
+                    &de,
+                    Metakey::Mode | Metakey::LastModified | Metakey::Etag | Metakey::ContentLength,
+                )
                 .await
                 .unwrap();
             match meta.mode() {
                 EntryMode::FILE => {
                     if de.name().ends_with(".idx") {
                         let stack_id_u64 = utils::parse_index_stack_id(de.name()).unwrap();
-                        let full_size = match self.list_stack(stack_id_u64).await {
-                            Ok(irs) => {
+                        // full_size counts deleted records too, their bytes stay in data file.
+                        let version = bs_opendal_index_cache::index_version(&meta);
+                        let full_size = match self.read_sealed_index(stack_id_u64, version).await {
+                            Ok(index) => {
                                 let mut sum = 0;
-                                irs.iter().for_each(|ir| sum += ir.size_data);
-                                sum as u64
+                                index.irs.iter().for_each(|ir| sum += ir.size_data as u64);
+                                sum
                             }
                             Err(e) => {
-                                eprintln!("cal stack {} size error: {:?}", stack_id_u64, e);
+                                warn!(
+                                    target: "BytestackOpendalReader",
+                                    "cal stack {} size error: {:?}", stack_id_u64, e
+                                );
                                 continue;
                             }
                         };
@@ -199,6 +208,8 @@ impl BytestackOpendalReader {
                         });
                     }
                 }
+                // deletion logs of stacks are the only dirs bytestack writes.
+                EntryMode::DIR if de.name().ends_with(".del/") => continue,
                 EntryMode::DIR => {
                     println!("skip dir {}", de.path())
                 }
@@ -210,52 +221,166 @@ impl BytestackOpendalReader {
 
     /// list_stack return all record(index_id) in giving stack_id.
     /// return with list of format!({:x}{:08x}, data_offset, cookie) which can be used to fetch single or batch data.
+    /// UnsealedStack or CorruptedStack is returned if the stack is not completely written, V1 stacks
+    /// written before seals are listed as they are.
+    /// deleted records are not listed.
     pub async fn list_stack(&self, stack_id: u64) -> Result<Vec<IndexRecord>, ErrorKind> {
-        let mut out = Vec::<IndexRecord>::new();
+        self.read_index(stack_id).await
+    }
+
+    /// read_index read and parse the whole index file of stack_id,
+    /// records with a tombstone in the deletion log are left out.
+    async fn read_index(&self, stack_id: u64) -> Result<Vec<IndexRecord>, ErrorKind> {
+        let mut irs = self.read_sealed_index(stack_id, None).await?.irs.clone();
+        let deleted = self.deleted_records(stack_id).await?;
+        if !deleted.is_empty() {
+            irs.retain(|ir| !deleted.contains(&(ir.offset_data, ir.cookie)));
+        }
+        Ok(irs)
+    }
+
+    /// read_sealed_index return the whole index file of stack_id with its StackSeal from index_cache
+    /// if it is cached with the same version, version is looked up if it is None.
+    async fn read_sealed_index(
+        &self,
+        stack_id: u64,
+        version: Option<String>,
+    ) -> Result<Arc<CachedIndex>, ErrorKind> {
         let index_file_path = utils::get_index_file_path(&self.prefix, stack_id);
-        let imh = match self
-            .operator
-            .reader_with(index_file_path.as_str())
-            .range(0..IndexMagicHeader::size() as u64)
-            .await
-        {
-            Ok(mut reader) => {
-                let mut buf = Vec::new();
-                buf.resize(IndexMagicHeader::size(), 0);
-                match reader.read(&mut buf).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                    }
+        let cache = match &self.options.index_cache {
+            Some(cache) => cache,
+            None => return Ok(Arc::new(self.load_index(stack_id).await?)),
+        };
+        let version = match version {
+            Some(version) => Some(version),
+            None => match self.operator.stat(&index_file_path).await {
+                Ok(meta) => bs_opendal_index_cache::index_version(&meta),
+                Err(e) => {
+                    return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
                 }
-                let deserialized = match bincode::deserialize::<IndexMagicHeader>(&buf) {
-                    Ok(h) => h,
-                    Err(e) => {
-                        return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                    }
-                };
-                deserialized
+            },
+        };
+        let version = match version {
+            Some(version) => version,
+            None => return Ok(Arc::new(self.load_index(stack_id).await?)),
+        };
+        let info = self.operator.info();
+        let key = format!(
+            "{}://{}{}{}",
+            info.scheme(),
+            info.name(),
+            info.root(),
+            index_file_path
+        );
+        if let Some(index) = cache.get(&key, &version).await {
+            return Ok(index);
+        }
+        let index = self.load_index(stack_id).await?;
+        Ok(cache.put(&key, &version, index).await)
+    }
+
+    /// load_index read and parse the whole index file of stack_id with its StackSeal, the seal is
+    /// checked so that a truncated index never looks like a stack with fewer records. An index file
+    /// of a V1 stack written before seals only holds IndexRecords, it is returned without seal if its
+    /// data file has no DataSealedHeader.
+    async fn load_index(&self, stack_id: u64) -> Result<CachedIndex, ErrorKind> {
+        let index_file_path = utils::get_index_file_path(&self.prefix, stack_id);
+        let bs = match self.operator.read(&index_file_path).await {
+            Ok(bs) => bs,
+            Err(e) => {
+                return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
+        };
+        if bs.len() < IndexMagicHeader::size() {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} is truncated",
+                index_file_path
+            ))));
+        }
+        let imh = match bincode::deserialize::<IndexMagicHeader>(&bs[..IndexMagicHeader::size()]) {
+            Ok(h) => h,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
         };
+        if !imh.valid() || imh.stack_id != stack_id {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} header mismatch",
+                index_file_path
+            ))));
+        }
 
-        assert_eq!(imh.valid(), true, "header magic mismatch");
-        assert!(imh.stack_id == stack_id, "stack_id mismatch");
-
-        let bs = match self
-            .operator
-            .range_read(&index_file_path, IndexMagicHeader::size() as u64..)
-            .await
+        let header_size = imh.header_size();
+        if bs.len() < header_size {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} is truncated",
+                index_file_path
+            ))));
+        }
+        let records_size = bs.len() - header_size;
+        if records_size.is_multiple_of(IndexRecord::size()) {
+            if imh.version() != FormatVersion::V1 {
+                return Err(ErrorKind::UnsealedStack(CustomError::new(format!(
+                    "index file {} is not sealed",
+                    index_file_path
+                ))));
+            }
+            // V1 stacks written before seals only hold IndexRecords, there is nothing to check.
+            self.check_written_before_seals(stack_id, &index_file_path)
+                .await?;
+            let irs = Self::parse_index_records(&bs[header_size..])?;
+            let data_file_path = utils::get_data_file_path(&self.prefix, stack_id);
+            let data_bytes = match self.operator.stat(&data_file_path).await {
+                Ok(meta) => meta.content_length(),
+                Err(e) => {
+                    return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+                }
+            };
+            return Ok(CachedIndex {
+                irs,
+                seal: None,
+                data_bytes,
+            });
+        }
+        if records_size < StackSeal::size()
+            || !(records_size - StackSeal::size()).is_multiple_of(IndexRecord::size())
         {
-            Ok(bs) => bs,
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} is truncated",
+                index_file_path
+            ))));
+        }
+        let seal_offset = bs.len() - StackSeal::size();
+        let seal = match StackSeal::new_from_bytes(&bs[seal_offset..]) {
+            Ok(seal) => seal,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
         };
+        let record_count = (seal_offset - header_size) / IndexRecord::size();
+        if !seal.valid()
+            || seal.stack_id != stack_id
+            || seal.record_count != record_count as u64
+            || seal.index_crc != utils::CASTAGNOLI.checksum(&bs[..seal_offset])
+        {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} mismatch its seal",
+                index_file_path
+            ))));
+        }
+
+        Ok(CachedIndex {
+            irs: Self::parse_index_records(&bs[header_size..seal_offset])?,
+            data_bytes: seal.data_bytes,
+            seal: Some(seal),
+        })
+    }
+
+    /// parse_index_records parse IndexRecords saved one by one in bs.
+    fn parse_index_records(bs: &[u8]) -> Result<Vec<IndexRecord>, ErrorKind> {
+        let mut out = Vec::<IndexRecord>::with_capacity(bs.len() / IndexRecord::size());
         for chunk in bs.chunks(IndexRecord::size()) {
-            let ir = match bincode::deserialize::<IndexRecord>(chunk) {
+            let ir = match IndexRecord::new_from_bytes(chunk) {
                 Ok(ir) => ir,
                 Err(e) => {
                     return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
@@ -266,70 +391,53 @@ impl BytestackOpendalReader {
         Ok(out)
     }
 
-    /// list_stack_al_iter return BytestackOpendalIterator which work like an iterator for IndexRecord and MetaRecord
+    /// list_stack_al_iter return BytestackOpendalIterator, a stream of IndexRecord and MetaRecord
     pub async fn list_stack_al_iter(
         &self,
         stack_id: u64,
     ) -> Result<BytestackOpendalIterator, ErrorKind> {
-        let mut irs = Vec::<IndexRecord>::new();
-        let index_file_path = utils::get_index_file_path(&self.prefix, stack_id);
-        let imh = match self
-            .operator
-            .reader_with(index_file_path.as_str())
-            .range(0..IndexMagicHeader::size() as u64)
-            .await
-        {
-            Ok(mut reader) => {
-                let mut buf = Vec::new();
-                buf.resize(IndexMagicHeader::size(), 0);
-                match reader.read(&mut buf).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                    }
-                }
-                let deserialized = match bincode::deserialize::<IndexMagicHeader>(&buf) {
-                    Ok(h) => h,
-                    Err(e) => {
-                        return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                    }
-                };
-                assert!(deserialized.valid());
-                deserialized
-            }
+        let irs = self.read_index(stack_id).await?;
+        let meta_file_path = utils::get_meta_file_path(&self.prefix, stack_id);
+        let reader = match self.operator.reader(&meta_file_path).await {
+            Ok(reader) => reader,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
         };
+        let crypto = self.stack_crypto(stack_id).await?;
 
-        assert_eq!(imh.valid(), true, "header magic mismatch");
-        assert!(imh.stack_id == stack_id, "stack_id mismatch");
+        Ok(BytestackOpendalIterator::new(
+            MetaSource {
+                irs: irs.into_iter(),
+                meta: MetaScanner::new(reader, 0, crypto),
+            },
+            self.options.skip_bad_records,
+        ))
+    }
 
-        let bs = match self
-            .operator
-            .range_read(&index_file_path, IndexMagicHeader::size() as u64..)
-            .await
-        {
-            Ok(bs) => bs,
+    /// list_stack_al_with_data_iter return BytestackOpendalDataIterator, a stream of IndexRecord, MetaRecord and data
+    /// data file is read ahead with concurrent ranged reads if readahead is set in ReaderOptions.
+    pub async fn list_stack_al_with_data_iter(
+        &self,
+        stack_id: u64,
+    ) -> Result<BytestackopendalDataIterator, ErrorKind> {
+        if self.options.readahead_bytes > 0 || self.options.readahead_records > 0 {
+            return self.list_stack_al_with_data_readahead(stack_id).await;
+        }
+        let irs = self.read_index(stack_id).await?;
+        let meta_file_path = utils::get_meta_file_path(&self.prefix, stack_id);
+        let meta_reader = match self.operator.reader(&meta_file_path).await {
+            Ok(reader) => reader,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
         };
-        for chunk in bs.chunks(IndexRecord::size()) {
-            let ir = match bincode::deserialize::<IndexRecord>(chunk) {
-                Ok(ir) => ir,
-                Err(e) => {
-                    return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                }
-            };
-            irs.push(ir);
-        }
-        let meta_file_path = utils::get_meta_file_path(&self.prefix, stack_id);
-        let mgh = MetaMagicHeader::new(stack_id);
-        let reader = match self
+
+        let data_file_path = utils::get_data_file_path(&self.prefix, stack_id);
+        let data_reader = match self
             .operator
-            .reader_with(&meta_file_path)
-            .range(mgh.size() as u64..)
+            .reader_with(&data_file_path)
+            .range(4096..)
             .await
         {
             Ok(reader) => reader,
@@ -338,101 +446,192 @@ impl BytestackOpendalReader {
             }
         };
 
-        Ok(BytestackOpendalIterator { irs, reader })
+        let crypto = self.stack_crypto(stack_id).await?;
+        Ok(BytestackopendalDataIterator::new(
+            DataSource {
+                irs: irs.into_iter(),
+                meta: MetaScanner::new(meta_reader, 0, crypto.clone()),
+                data_reader,
+                data_offset: 4096,
+                operator: self.operator.clone(),
+                data_file_path,
+                crypto,
+            },
+            self.options.skip_bad_records,
+        ))
     }
 
-    /// list_stack_al_with_data_iter return BytestackOpendalDataIterator which work like an iterator for IndexRecord, MetaRecord and data
-    pub async fn list_stack_al_with_data_iter(
+    /// list_stack_al_with_data_readahead return BytestackOpendalDataIterator which reads data file
+    /// by up to readahead_concurrency ranged reads ahead of the consumer, each covers its share of
+    /// the readahead window.
+    async fn list_stack_al_with_data_readahead(
         &self,
         stack_id: u64,
     ) -> Result<BytestackopendalDataIterator, ErrorKind> {
-        let mut irs = Vec::<IndexRecord>::new();
-        let index_file_path = utils::get_index_file_path(&self.prefix, stack_id);
-        let imh = match self
-            .operator
-            .reader_with(index_file_path.as_str())
-            .range(0..IndexMagicHeader::size() as u64)
-            .await
-        {
-            Ok(mut reader) => {
-                let mut buf = Vec::new();
-                buf.resize(IndexMagicHeader::size(), 0);
-                match reader.read(&mut buf).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                    }
-                }
-                let deserialized = match bincode::deserialize::<IndexMagicHeader>(&buf) {
-                    Ok(h) => h,
-                    Err(e) => {
-                        return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                    }
-                };
-                assert!(deserialized.valid());
-                deserialized
-            }
+        let index = self.read_sealed_index(stack_id, None).await?;
+        let deleted = self.deleted_records(stack_id).await?;
+        let irs: Vec<IndexRecord> = index
+            .irs
+            .iter()
+            .filter(|ir| !deleted.contains(&(ir.offset_data, ir.cookie)))
+            .cloned()
+            .collect();
+
+        let meta_file_path = utils::get_meta_file_path(&self.prefix, stack_id);
+        let meta_reader = match self.operator.reader(&meta_file_path).await {
+            Ok(reader) => reader,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
         };
 
-        assert_eq!(imh.valid(), true, "header magic mismatch");
-        assert!(imh.stack_id == stack_id, "stack_id mismatch");
+        let crypto = self.stack_crypto(stack_id).await?;
+        let source = Arc::new(StackSource {
+            operator: self.operator.clone(),
+            data_file_path: utils::get_data_file_path(&self.prefix, stack_id),
+            crypto: crypto.clone(),
+            check_crc: false,
+        });
+        let concurrency = self.options.readahead_concurrency.max(1);
+        let max_bytes = match self.options.readahead_bytes {
+            0 => u64::MAX,
+            bytes => (bytes / concurrency as u64).max(1),
+        };
+        let max_records = match self.options.readahead_records {
+            0 => usize::MAX,
+            records => (records / concurrency).max(1),
+        };
+        let fetchers = bs_opendal_batch::plan_scan(
+            source,
+            &index.irs,
+            index.data_bytes,
+            &irs,
+            max_bytes,
+            max_records,
+        );
+        debug!(
+            target: "BytestackOpendalReader",
+            "scan {} records of stack {} with {} ranged reads",
+            irs.len(),
+            stack_id,
+            fetchers.len()
+        );
+        Ok(BytestackopendalDataIterator::new(
+            ReadaheadSource::new(
+                irs,
+                MetaScanner::new(meta_reader, 0, crypto),
+                fetchers,
+                concurrency,
+            ),
+            self.options.skip_bad_records,
+        ))
+    }
 
-        let bs = match self
-            .operator
-            .range_read(&index_file_path, IndexMagicHeader::size() as u64..)
-            .await
-        {
-            Ok(bs) => bs,
+    /// list_al_with_data_shuffled_iter return BytestackOpendalDataIterator of all records under this path,
+    /// every record which is not deleted comes exactly once in a pseudo-random order decided by
+    /// (seed, epoch), the same seed and epoch give the same order as long as the records are the same.
+    /// Stacks are cut into blocks of shuffle_block_records records, blocks are shuffled and records of
+    /// every shuffle_window_blocks blocks are shuffled again. Unsealed stacks are skipped.
+    pub async fn list_al_with_data_shuffled_iter(
+        &self,
+        seed: u64,
+        epoch: u64,
+    ) -> Result<BytestackopendalDataIterator, ErrorKind> {
+        let mut stack_ids = match self.list().await {
+            Ok(stack_ids) => stack_ids,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
         };
-        for chunk in bs.chunks(IndexRecord::size()) {
-            let ir = match bincode::deserialize::<IndexRecord>(chunk) {
-                Ok(ir) => ir,
-                Err(e) => {
-                    return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        stack_ids.sort_unstable();
+        self.load_deleted_records(&stack_ids).await?;
+        let mut stacks = Vec::with_capacity(stack_ids.len());
+        for stack_id in stack_ids {
+            let index = match self.read_sealed_index(stack_id, None).await {
+                Ok(index) => index,
+                Err(ErrorKind::UnsealedStack(_)) => {
+                    debug!(
+                        target: "BytestackOpendalReader",
+                        "skip unsealed stack {} in shuffled iteration", stack_id
+                    );
+                    continue;
                 }
+                Err(e) => return Err(e),
             };
-            irs.push(ir);
+            let deleted = self.deleted_records(stack_id).await?;
+            let irs: Vec<IndexRecord> = index
+                .irs
+                .iter()
+                .filter(|ir| !deleted.contains(&(ir.offset_data, ir.cookie)))
+                .cloned()
+                .collect();
+            stacks.push(ShuffleStack {
+                source: Arc::new(StackSource {
+                    operator: self.operator.clone(),
+                    data_file_path: utils::get_data_file_path(&self.prefix, stack_id),
+                    crypto: self.stack_crypto(stack_id).await?,
+                    check_crc: false,
+                }),
+                meta_file_path: utils::get_meta_file_path(&self.prefix, stack_id),
+                all_irs: index.irs.clone(),
+                data_end: index.data_bytes,
+                irs,
+            });
         }
-        let meta_file_path = utils::get_meta_file_path(&self.prefix, stack_id);
-        let mgh = MetaMagicHeader::new(stack_id);
-        let meta_reader = match self
-            .operator
-            .reader_with(&meta_file_path)
-            .range(mgh.size() as u64..)
-            .await
-        {
-            Ok(reader) => reader,
-            Err(e) => {
-                return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-            }
-        };
+        Ok(BytestackopendalDataIterator::new(
+            EpochSource::new(
+                stacks,
+                seed,
+                epoch,
+                self.options.shuffle_block_records,
+                self.options.shuffle_window_blocks,
+                self.options.readahead_concurrency,
+            ),
+            self.options.skip_bad_records,
+        ))
+    }
 
-        let data_file_path = utils::get_data_file_path(&self.prefix, stack_id);
-        let data_reader = match self
-            .operator
-            .reader_with(&data_file_path)
-            .range(4096..)
-            .await
-        {
-            Ok(reader) => reader,
-            Err(e) => {
-                return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+    /// verify_stack check that stack_id is healthy at level and return every problem found with the file
+    /// and offset where it is, see VerifyLevel. An error is returned only if the storage can not be read.
+    /// At VerifyLevel::Full encrypted records are decrypted with the keyring of ReaderOptions if the key
+    /// is there, only checksum of their stored bytes is checked otherwise.
+    pub async fn verify_stack(
+        &self,
+        stack_id: u64,
+        level: VerifyLevel,
+    ) -> Result<VerifyReport, ErrorKind> {
+        let mut key_problem = None;
+        let crypto = if level >= VerifyLevel::Full {
+            match self.stack_crypto(stack_id).await {
+                Ok(crypto) => crypto,
+                Err(ErrorKind::IOError(e)) => return Err(ErrorKind::IOError(e)),
+                Err(e) => {
+                    key_problem = Some(VerifyProblem {
+                        file: utils::get_data_file_path(&self.prefix, stack_id),
+                        offset: 0,
+                        message: format!("records are not decrypted: {:?}", e),
+                    });
+                    None
+                }
             }
+        } else {
+            None
         };
-
-        Ok(BytestackopendalDataIterator {
-            irs,
-            meta_reader,
-            data_reader,
-        })
+        let mut report = bs_opendal_verify::verify_stack(
+            &self.operator,
+            &self.prefix,
+            stack_id,
+            level,
+            crypto.as_deref(),
+        )
+        .await?;
+        if let Some(problem) = key_problem {
+            report.problems.insert(0, problem);
+        }
+        Ok(report)
     }
-    /// fetch data by index_id
+
+    /// fetch data by index_id, check_crc checks data by the checksum algorithm of its stack.
     pub async fn fetch(&self, index_id: &str, check_crc: bool) -> Result<Vec<u8>, ErrorKind> {
         let pasred_index_id = match utils::parse_index_id(index_id) {
             Some(id) => id,
@@ -444,66 +643,917 @@ impl BytestackOpendalReader {
             }
         };
         let data_file_path = utils::get_data_file_path(&self.prefix, pasred_index_id.stack_id);
-        let mut reader = match self
-            .operator
-            .reader_with(&data_file_path)
-            .range(pasred_index_id.offset_data..)
-            .await
+        if self
+            .deleted_records(pasred_index_id.stack_id)
+            .await?
+            .contains(&(pasred_index_id.offset_data, pasred_index_id.cookie))
         {
-            Ok(r) => r,
-            Err(e) => {
-                return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+            return Err(ErrorKind::NotFound(CustomError::new(format!(
+                "{} is deleted",
+                index_id
+            ))));
+        }
+        let crypto = self.stack_crypto(pasred_index_id.stack_id).await?;
+        bs_opendal_record::read_data(
+            &self.operator,
+            &data_file_path,
+            pasred_index_id.offset_data,
+            pasred_index_id.cookie,
+            crypto.as_deref(),
+            check_crc,
+        )
+        .await
+    }
+
+    /// fetch_range fetch bytes in range of the data of index_id, e.g. the header of a video, only the
+    /// DataRecordHeader and the bytes in range are read unless data is compressed or encrypted.
+    /// checksum covers the whole data, so it is checked by reading the whole record if check_crc, or the
+    /// chunks holding range of a large record.
+    /// A range out of the size of data is rejected with InvalidArgument.
+    pub async fn fetch_range(
+        &self,
+        index_id: &str,
+        range: Range<u64>,
+        check_crc: bool,
+    ) -> Result<Vec<u8>, ErrorKind> {
+        let pasred_index_id = match utils::parse_index_id(index_id) {
+            Some(id) => id,
+            None => {
+                return Err(ErrorKind::IOError(CustomError::new(format!(
+                    "invalid index_id: {}",
+                    index_id
+                ))));
             }
         };
-        let mut head_buf = Vec::new();
-        head_buf.resize(DataRecordHeader::size(), 0);
-        match reader.read_exact(&mut head_buf).await {
-            Ok(_) => {}
+        if self
+            .deleted_records(pasred_index_id.stack_id)
+            .await?
+            .contains(&(pasred_index_id.offset_data, pasred_index_id.cookie))
+        {
+            return Err(ErrorKind::NotFound(CustomError::new(format!(
+                "{} is deleted",
+                index_id
+            ))));
+        }
+        let crypto = self.stack_crypto(pasred_index_id.stack_id).await?;
+        bs_opendal_record::read_data_range(
+            &self.operator,
+            &utils::get_data_file_path(&self.prefix, pasred_index_id.stack_id),
+            pasred_index_id.offset_data,
+            pasred_index_id.cookie,
+            range,
+            crypto.as_deref(),
+            check_crc,
+        )
+        .await
+    }
+
+    /// stat return IndexRecord and MetaRecord of index_id, NotFound is returned if there is no such record
+    /// or it is deleted. The IndexRecord is found by binary search on offset_data, in the cached index
+    /// if there is index_cache, or with a few ranged reads of the index file otherwise.
+    pub async fn stat(&self, index_id: &str) -> Result<(IndexRecord, MetaRecord), ErrorKind> {
+        let (id, ir) = self.find_index_record(index_id).await?;
+        let crypto = self.stack_crypto(id.stack_id).await?;
+        let mr = self
+            .read_meta_record(id.stack_id, &ir, crypto.as_deref())
+            .await?;
+        Ok((ir, mr))
+    }
+
+    /// fetch_with_meta fetch data of index_id with its IndexRecord and MetaRecord, see stat.
+    pub async fn fetch_with_meta(
+        &self,
+        index_id: &str,
+        check_crc: bool,
+    ) -> Result<(IndexRecord, MetaRecord, Vec<u8>), ErrorKind> {
+        let (id, ir) = self.find_index_record(index_id).await?;
+        let crypto = self.stack_crypto(id.stack_id).await?;
+        let data_file_path = utils::get_data_file_path(&self.prefix, id.stack_id);
+        let (mr, data) = futures::join!(
+            self.read_meta_record(id.stack_id, &ir, crypto.as_deref()),
+            bs_opendal_record::read_data(
+                &self.operator,
+                &data_file_path,
+                id.offset_data,
+                id.cookie,
+                crypto.as_deref(),
+                check_crc,
+            )
+        );
+        Ok((ir, mr?, data?))
+    }
+
+    /// find_index_record parse index_id and find its IndexRecord, deleted records are NotFound.
+    async fn find_index_record(&self, index_id: &str) -> Result<(IndexID, IndexRecord), ErrorKind> {
+        let id = match utils::parse_index_id(index_id) {
+            Some(id) => id,
+            None => {
+                return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                    "invalid index_id: {}",
+                    index_id
+                ))));
+            }
+        };
+        let ir = match self.options.index_cache {
+            Some(_) => {
+                let index = self.read_sealed_index(id.stack_id, None).await?;
+                match index
+                    .irs
+                    .binary_search_by_key(&id.offset_data, |ir| ir.offset_data)
+                {
+                    Ok(i) => Some(index.irs[i].clone()),
+                    Err(_) => None,
+                }
+            }
+            None => self.search_index_file(id.stack_id, id.offset_data).await?,
+        };
+        let ir = match ir {
+            Some(ir) => ir,
+            None => {
+                return Err(ErrorKind::NotFound(CustomError::new(format!(
+                    "no record at {} of stack {}",
+                    id.offset_data, id.stack_id
+                ))));
+            }
+        };
+        if ir.cookie != id.cookie {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(String::from(
+                "cookie mismatched",
+            ))));
+        }
+        if self
+            .deleted_records(id.stack_id)
+            .await?
+            .contains(&(id.offset_data, id.cookie))
+        {
+            return Err(ErrorKind::NotFound(CustomError::new(format!(
+                "{} is deleted",
+                index_id
+            ))));
+        }
+        Ok((id, ir))
+    }
+
+    /// search_index_file find the IndexRecord at offset_data in index file of stack_id by binary search,
+    /// records are written in increasing offset_data so only the seal and log2(n) records are read.
+    async fn search_index_file(
+        &self,
+        stack_id: u64,
+        offset_data: u64,
+    ) -> Result<Option<IndexRecord>, ErrorKind> {
+        let index_file_path = utils::get_index_file_path(&self.prefix, stack_id);
+        let len = match self.operator.stat(&index_file_path).await {
+            Ok(meta) => meta.content_length(),
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
+        };
+        if len < IndexMagicHeader::size() as u64 {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} is truncated",
+                index_file_path
+            ))));
         }
-        let drh = match DataRecordHeader::new_from_bytes(&head_buf) {
-            Ok(drh) => drh,
+        let imh_bs = self
+            .read_index_range(&index_file_path, 0, IndexMagicHeader::size() as u64)
+            .await?;
+        let imh = match bincode::deserialize::<IndexMagicHeader>(&imh_bs) {
+            Ok(h) => h,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
         };
-        if !drh.validate_magic() {
-            return Err(ErrorKind::IOError(CustomError::new(String::from(
-                "invalid drh item",
+        if !imh.valid() || imh.stack_id != stack_id {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} header mismatch",
+                index_file_path
             ))));
         }
-        if drh.cookie != pasred_index_id.cookie {
-            return Err(ErrorKind::InvalidArgument(CustomError::new(String::from(
-                "cookie mismatched",
+        let header_size = imh.header_size() as u64;
+        let record_size = IndexRecord::size() as u64;
+        let seal_size = StackSeal::size() as u64;
+        if len < header_size {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} is truncated",
+                index_file_path
             ))));
         }
-        let mut data_buf = Vec::new();
-        data_buf.resize(drh.size as usize, 0);
-        match reader.read_exact(&mut data_buf).await {
-            Ok(_) => {}
+        let records_size = len - header_size;
+        let record_count = if records_size.is_multiple_of(record_size) {
+            if imh.version() != FormatVersion::V1 {
+                return Err(ErrorKind::UnsealedStack(CustomError::new(format!(
+                    "index file {} is not sealed",
+                    index_file_path
+                ))));
+            }
+            // a V1 stack written before seals, the index file only holds IndexRecords.
+            self.check_written_before_seals(stack_id, &index_file_path)
+                .await?;
+            records_size / record_size
+        } else {
+            if records_size < seal_size || !(records_size - seal_size).is_multiple_of(record_size) {
+                return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                    "index file {} is truncated",
+                    index_file_path
+                ))));
+            }
+            let seal_bs = self
+                .read_index_range(&index_file_path, len - seal_size, len)
+                .await?;
+            let seal = match StackSeal::new_from_bytes(&seal_bs) {
+                Ok(seal) => seal,
+                Err(e) => {
+                    return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+                }
+            };
+            let record_count = (records_size - seal_size) / record_size;
+            if !seal.valid() || seal.stack_id != stack_id || seal.record_count != record_count {
+                return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                    "index file {} mismatch its seal",
+                    index_file_path
+                ))));
+            }
+            record_count
+        };
+
+        let (mut lo, mut hi) = (0, record_count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let start = header_size + mid * record_size;
+            let bs = self
+                .read_index_range(&index_file_path, start, start + record_size)
+                .await?;
+            let ir = match IndexRecord::new_from_bytes(&bs) {
+                Ok(ir) => ir,
+                Err(e) => {
+                    return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+                }
+            };
+            match ir.offset_data.cmp(&offset_data) {
+                Ordering::Equal => return Ok(Some(ir)),
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+            }
+        }
+        Ok(None)
+    }
+
+    /// check_written_before_seals return UnsealedStack if the V1 stack_id, whose index file has no seal,
+    /// was written by a writer which seals stacks on close, told by DataSealedHeader in its data file.
+    /// So an unsealed or truncated index never looks like a stack written before seals.
+    async fn check_written_before_seals(
+        &self,
+        stack_id: u64,
+        index_file_path: &str,
+    ) -> Result<(), ErrorKind> {
+        let data_file_path = utils::get_data_file_path(&self.prefix, stack_id);
+        let start = DATA_SEALED_HEADER_OFFSET as u64;
+        let end = start + DataSealedHeader::size() as u64;
+        let bs = match self.operator.range_read(&data_file_path, start..end).await {
+            Ok(bs) => bs,
             Err(e) => {
                 return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
             }
+        };
+        if DataSealedHeader::has_magic(&bs) {
+            return Err(ErrorKind::UnsealedStack(CustomError::new(format!(
+                "index file {} is not sealed",
+                index_file_path
+            ))));
         }
-        if check_crc {
-            let crc_sum = utils::CASTAGNOLI.checksum(&data_buf);
-            if crc_sum != drh.crc {
-                return Err(ErrorKind::IOError(CustomError::new(String::from(
-                    "crc mismatch",
-                ))));
+        Ok(())
+    }
+
+    /// read_index_range read bytes in start..end of index file, a short read is an error.
+    async fn read_index_range(
+        &self,
+        index_file_path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, ErrorKind> {
+        match self.operator.range_read(index_file_path, start..end).await {
+            Ok(bs) if bs.len() as u64 == end - start => Ok(bs),
+            Ok(_) => Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "index file {} is truncated",
+                index_file_path
+            )))),
+            Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        }
+    }
+
+    /// stat_by_name return index_id, IndexRecord and MetaRecord of the record named filename in all
+    /// stacks under this path, NotFound is returned if there is none.
+    /// If several records have the same filename, the one in the most recently sealed stack wins, told
+    /// by last modified time of index files which are written last when stacks are sealed, so it does
+    /// not depend on how stack_ids are allocated. The last written one wins in a stack. Deleted records
+    /// are skipped, so an older record with the same filename shows up again once the newer one is deleted.
+    /// Stacks which can not be searched, being written or encrypted without key, are skipped.
+    pub async fn stat_by_name(
+        &self,
+        filename: &str,
+    ) -> Result<(String, IndexRecord, MetaRecord), ErrorKind> {
+        let mut stacks = match self.list_sealed_at().await {
+            Ok(stacks) => stacks,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        stacks.sort_unstable_by(|a, b| b.cmp(a));
+        let stack_ids: Vec<u64> = stacks.into_iter().map(|(_, stack_id)| stack_id).collect();
+        self.load_deleted_records(&stack_ids).await?;
+        for stack_id in stack_ids {
+            match self.stat_by_name_in_stack(stack_id, filename).await {
+                Ok(Some((ir, mr))) => {
+                    return Ok((format!("{},{}", stack_id, ir.index_id()), ir, mr));
+                }
+                Ok(None) => {}
+                Err(ErrorKind::UnsealedStack(_)) | Err(ErrorKind::InvalidArgument(_)) => {
+                    debug!(
+                        target: "BytestackOpendalReader",
+                        "skip stack {} when looking up {}", stack_id, filename
+                    );
+                }
+                Err(e) => return Err(e),
             }
         }
-        Ok(data_buf)
+        Err(ErrorKind::NotFound(CustomError::new(format!(
+            "{} is not found",
+            filename
+        ))))
     }
 
-    /// batch_fetch can fetch data for giving a batch of index_id
+    /// list_sealed_at return (last modified time of index file, stack_id) of all stacks under this path.
+    async fn list_sealed_at(&self) -> Result<Vec<(i64, u64)>, opendal::Error> {
+        let mut out = Vec::new();
+        let mut ds = self.operator.list_with(self.prefix.as_str()).await?;
+        while let Some(de) = ds.try_next().await? {
+            let stack_id = match utils::parse_index_stack_id(de.name()) {
+                Some(stack_id) => stack_id,
+                None => continue,
+            };
+            let meta = self
+                .operator
+                .metadata(&de, Metakey::Mode | Metakey::LastModified)
+                .await?;
+            if meta.mode() != EntryMode::FILE {
+                continue;
+            }
+            let sealed_at = meta.last_modified().map_or(0, |t| t.timestamp_millis());
+            out.push((sealed_at, stack_id));
+        }
+        Ok(out)
+    }
+
+    /// fetch_by_name fetch data of the record named filename, see stat_by_name for which record is
+    /// returned if there are several.
+    pub async fn fetch_by_name(
+        &self,
+        filename: &str,
+        check_crc: bool,
+    ) -> Result<Vec<u8>, ErrorKind> {
+        let (index_id, _, _) = self.stat_by_name(filename).await?;
+        self.fetch(&index_id, check_crc).await
+    }
+
+    /// stat_by_name_in_stack look up filename by filename index of stack_id, meta of every candidate is
+    /// read to check its filename. Index file is only read if filename index has candidates.
+    /// Stacks without filename index are scanned.
+    async fn stat_by_name_in_stack(
+        &self,
+        stack_id: u64,
+        filename: &str,
+    ) -> Result<Option<(IndexRecord, MetaRecord)>, ErrorKind> {
+        let name_index = match bs_opendal_names::read_name_index(
+            &self.operator,
+            &self.prefix,
+            stack_id,
+        )
+        .await?
+        {
+            Some(name_index) => name_index,
+            None => {
+                let mut found = None;
+                let mut iter = self.list_stack_al_iter(stack_id).await?;
+                while let Some(res) = iter.next().await {
+                    let (ir, mr) = res?;
+                    if mr.filename == filename {
+                        found = Some((ir, mr));
+                    }
+                }
+                return Ok(found);
+            }
+        };
+        let crypto = self.stack_crypto(stack_id).await?;
+        let key = if name_index.header.is_keyed() {
+            match crypto.as_ref().and_then(|crypto| crypto.name_key()) {
+                Some(key) => Some(key),
+                None => {
+                    return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                        "filename index of stack {} is keyed but stack has no key",
+                        stack_id
+                    ))));
+                }
+            }
+        } else {
+            None
+        };
+        let positions = name_index.lookup(filename, key.as_ref());
+        if positions.is_empty() {
+            return Ok(None);
+        }
+        let index = self.read_sealed_index(stack_id, None).await?;
+        let deleted = self.deleted_records(stack_id).await?;
+        for position in positions {
+            let ir = match index.irs.get(position as usize) {
+                Some(ir) => ir,
+                None => {
+                    return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                        "filename index of stack {} points to record {} out of index",
+                        stack_id, position
+                    ))));
+                }
+            };
+            if deleted.contains(&(ir.offset_data, ir.cookie)) {
+                continue;
+            }
+            let mr = self
+                .read_meta_record(stack_id, ir, crypto.as_deref())
+                .await?;
+            if mr.filename == filename {
+                return Ok(Some((ir.clone(), mr)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// read_meta_record read MetaRecord of ir in stack_id, encrypted filename and extra are decrypted.
+    async fn read_meta_record(
+        &self,
+        stack_id: u64,
+        ir: &IndexRecord,
+        crypto: Option<&StackCrypto>,
+    ) -> Result<MetaRecord, ErrorKind> {
+        let meta_file_path = utils::get_meta_file_path(&self.prefix, stack_id);
+        let bs = match self
+            .operator
+            .range_read(
+                &meta_file_path,
+                ir.offset_meta..ir.offset_meta + ir.size_meta as u64,
+            )
+            .await
+        {
+            Ok(bs) => bs,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        let mr = match MetaRecord::decode(&bs) {
+            Ok(mr) => mr,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        open_meta(mr, crypto)
+    }
+
+    /// batch_fetch fetch data of a batch of index_id, the result of every index_id is returned in the same
+    /// order as index_ids and an error of one index_id does not fail the others.
+    /// index_ids are grouped by stack and sorted by offset_data, records no more than
+    /// batch_gap_tolerance apart are read in one ranged read, see ReaderOptions.
     pub async fn batch_fetch(
         &self,
         index_ids: Vec<String>,
         check_crc: bool,
-    ) -> Result<Vec<OpendalFetcher>, opendal::Error> {
-        todo!()
+    ) -> Vec<Result<Vec<u8>, ErrorKind>> {
+        let mut results: Vec<Option<Result<Vec<u8>, ErrorKind>>> =
+            (0..index_ids.len()).map(|_| None).collect();
+        let mut stacks: BTreeMap<u64, Vec<(usize, IndexID)>> = BTreeMap::new();
+        for (position, index_id) in index_ids.iter().enumerate() {
+            match utils::parse_index_id(index_id) {
+                Some(id) => stacks.entry(id.stack_id).or_default().push((position, id)),
+                None => {
+                    results[position] = Some(Err(ErrorKind::InvalidArgument(CustomError::new(
+                        format!("invalid index_id: {}", index_id),
+                    ))));
+                }
+            }
+        }
+
+        let mut fetchers = Vec::new();
+        for (stack_id, batch) in stacks {
+            let positions: Vec<usize> = batch.iter().map(|(position, _)| *position).collect();
+            match self.plan_stack_batch(stack_id, batch, check_crc).await {
+                Ok((stack_fetchers, errors)) => {
+                    fetchers.extend(stack_fetchers);
+                    for (position, e) in errors {
+                        results[position] = Some(Err(e));
+                    }
+                }
+                Err(e) => {
+                    for position in positions {
+                        results[position] = Some(Err(e.clone()));
+                    }
+                }
+            }
+        }
+
+        debug!(
+            target: "BytestackOpendalReader",
+            "batch_fetch {} index_ids with {} ranged reads",
+            index_ids.len(),
+            fetchers.len()
+        );
+        let mut fetched = futures::stream::iter(fetchers.iter().map(|f| f.do_fetch()))
+            .buffer_unordered(self.options.batch_concurrency.max(1));
+        while let Some(out) = fetched.next().await {
+            for (position, res) in out {
+                results[position] = Some(res);
+            }
+        }
+        results
+            .into_iter()
+            .map(|res| match res {
+                Some(res) => res,
+                None => Err(ErrorKind::IOError(CustomError::new(String::from(
+                    "record is not fetched",
+                )))),
+            })
+            .collect()
+    }
+
+    /// plan_stack_batch turn records of a batch in stack_id into fetchers.
+    async fn plan_stack_batch(
+        &self,
+        stack_id: u64,
+        batch: Vec<(usize, IndexID)>,
+        check_crc: bool,
+    ) -> Result<(Vec<OpendalFetcher>, Vec<(usize, ErrorKind)>), ErrorKind> {
+        let index = self.read_sealed_index(stack_id, None).await?;
+        let deleted = self.deleted_records(stack_id).await?;
+        let source = Arc::new(StackSource {
+            operator: self.operator.clone(),
+            data_file_path: utils::get_data_file_path(&self.prefix, stack_id),
+            crypto: self.stack_crypto(stack_id).await?,
+            check_crc,
+        });
+        Ok(bs_opendal_batch::plan_stack(
+            source,
+            &index.irs,
+            index.data_bytes,
+            &deleted,
+            batch,
+            &self.options,
+        ))
+    }
+}
+
+#[tokio::test]
+async fn test_read_legacy_unsealed_stack() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use super::WriterOptions;
+
+    let (op, dir) = temp_operator("legacy_v1");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            format_version: FormatVersion::V1,
+            ..Default::default()
+        },
+    );
+    let mut records = vec![];
+    for i in 0..10 {
+        let data = vec![i as u8; 100 + i * 1000];
+        let index_id = writer
+            .put(data.clone(), format!("f{}", i), None)
+            .await
+            .unwrap();
+        records.push((index_id, data));
+    }
+    writer.close().await.unwrap();
+    let stack_id = stack_of(&records[0].0);
+
+    // strip the seals and filename index, a stack written before seals looks like this.
+    let truncate = |path: String, len: u64| {
+        let path = dir.path().join(path);
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        let size = file.metadata().unwrap().len();
+        file.set_len(size - len).unwrap();
+    };
+    truncate(
+        utils::get_index_file_path("", stack_id),
+        StackSeal::size() as u64,
+    );
+    truncate(utils::get_data_file_path("", stack_id), 4096);
+    let data_path = dir.path().join(utils::get_data_file_path("", stack_id));
+    let mut data = std::fs::read(&data_path).unwrap();
+    data[DATA_SEALED_HEADER_OFFSET..DATA_SEALED_HEADER_OFFSET + DataSealedHeader::size()].fill(0);
+    std::fs::write(&data_path, data).unwrap();
+    let meta = std::fs::read(dir.path().join(utils::get_meta_file_path("", stack_id))).unwrap();
+    let seal_start = meta[..meta.len() - 1]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .unwrap();
+    truncate(
+        utils::get_meta_file_path("", stack_id),
+        (meta.len() - seal_start - 1) as u64,
+    );
+    std::fs::remove_file(dir.path().join(utils::get_names_file_path("", stack_id))).unwrap();
+
+    let reader = new_reader(&op);
+    for (index_id, data) in &records {
+        assert_eq!(&reader.fetch(index_id, true).await.unwrap(), data);
+        assert_eq!(
+            reader.stat(index_id).await.unwrap().0.size_data as usize,
+            data.len()
+        );
+    }
+    assert_eq!(
+        reader.list_stack(stack_id).await.unwrap().len(),
+        records.len()
+    );
+    let stacks = reader.list_al().await.unwrap();
+    assert_eq!(stacks.len(), 1);
+    assert_eq!(
+        stacks[0].full_size,
+        records
+            .iter()
+            .map(|(_, data)| data.len() as u64)
+            .sum::<u64>()
+    );
+    let mut iter = reader.list_stack_al_with_data_iter(stack_id).await.unwrap();
+    let mut n = 0;
+    while let Some(res) = iter.next().await {
+        let (ir, mr, data) = res.unwrap();
+        assert_eq!(mr.filename, format!("f{}", n));
+        assert_eq!(ir.size_data as usize, data.len());
+        n += 1;
+    }
+    assert_eq!(n, records.len());
+    let mut iter = reader.list_al_with_data_shuffled_iter(1, 0).await.unwrap();
+    let mut n = 0;
+    while let Some(res) = iter.next().await {
+        res.unwrap();
+        n += 1;
+    }
+    assert_eq!(n, records.len());
+    let (_, _, mr) = reader.stat_by_name("f3").await.unwrap();
+    assert_eq!(mr.filename, "f3");
+    // verify takes it for a legacy stack too and checks every record.
+    let report = reader
+        .verify_stack(stack_id, VerifyLevel::Full)
+        .await
+        .unwrap();
+    assert_eq!(report.records, records.len() as u64);
+    assert!(report.is_healthy(), "{:?}", report.problems);
+
+    // the same stack whose data file still has the marker of a sealed stack is unsealed.
+    let mut data = std::fs::read(&data_path).unwrap();
+    data[DATA_SEALED_HEADER_OFFSET..DATA_SEALED_HEADER_OFFSET + DataSealedHeader::size()]
+        .copy_from_slice(&bincode::serialize(&DataSealedHeader::new()).unwrap());
+    std::fs::write(&data_path, data).unwrap();
+    assert!(matches!(
+        new_reader(&op).list_stack(stack_id).await,
+        Err(ErrorKind::UnsealedStack(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_reject_unsealed_stack() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use super::WriterOptions;
+
+    let (op, dir) = temp_operator("unsealed");
+    for format_version in [FormatVersion::V1, FormatVersion::V2] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version,
+                ..Default::default()
+            },
+        );
+        let mut ids = vec![];
+        for i in 0..3u8 {
+            ids.push(writer.put(vec![i; 10], i.to_string(), None).await.unwrap());
+        }
+        writer.close().await.unwrap();
+        let stack_id = stack_of(&ids[0]);
+        let index_path = dir.path().join(utils::get_index_file_path("", stack_id));
+        let sealed = std::fs::read(&index_path).unwrap();
+        assert_eq!(new_reader(&op).list_stack(stack_id).await.unwrap().len(), 3);
+
+        // the seal is lost, the index is cut in the middle of the seal, cut by 12 bytes so that
+        // the rest of the seal looks like one more IndexRecord, or a record is lost. A V1 stack
+        // is told from one written before seals by its data header.
+        let seal_offset = sealed.len() - StackSeal::size();
+        let cases = [
+            (sealed[..seal_offset].to_vec(), true),
+            (sealed[..sealed.len() - 10].to_vec(), false),
+            (sealed[..sealed.len() - 12].to_vec(), true),
+            (
+                [
+                    &sealed[..seal_offset - IndexRecord::size()],
+                    &sealed[seal_offset..],
+                ]
+                .concat(),
+                false,
+            ),
+        ];
+        for (bs, unsealed) in cases {
+            std::fs::write(&index_path, bs).unwrap();
+            let reader = new_reader(&op);
+            let res = reader.list_stack(stack_id).await;
+            if unsealed {
+                assert!(
+                    matches!(res, Err(ErrorKind::UnsealedStack(_))),
+                    "{:?} {:?}",
+                    format_version,
+                    res
+                );
+            } else {
+                assert!(
+                    matches!(res, Err(ErrorKind::CorruptedStack(_))),
+                    "{:?} {:?}",
+                    format_version,
+                    res
+                );
+            }
+            assert!(reader.stat(&ids[0]).await.is_err());
+            let report = reader
+                .verify_stack(stack_id, VerifyLevel::Headers)
+                .await
+                .unwrap();
+            assert!(!report.is_healthy());
+            // a stack which is not sealed is left out of shuffled iteration.
+            if unsealed {
+                let mut iter = reader.list_al_with_data_shuffled_iter(1, 0).await.unwrap();
+                assert!(iter.next().await.is_none());
+            }
+        }
+        // a changed record fails crc of the seal, stat only checks the seal by a binary search.
+        let mut changed = sealed.clone();
+        changed[seal_offset - 1] ^= 1;
+        std::fs::write(&index_path, changed).unwrap();
+        let reader = new_reader(&op);
+        assert!(matches!(
+            reader.list_stack(stack_id).await,
+            Err(ErrorKind::CorruptedStack(_))
+        ));
+        assert_eq!(reader.stat(&ids[0]).await.unwrap().1.filename, "0");
+        // the broken stack is removed so that shuffled iteration over the next one sees no other stack.
+        for path in [
+            utils::get_index_file_path("", stack_id),
+            utils::get_meta_file_path("", stack_id),
+            utils::get_data_file_path("", stack_id),
+            utils::get_names_file_path("", stack_id),
+        ] {
+            let _ = std::fs::remove_file(dir.path().join(path));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_range() {
+    use super::bs_opendal_testing::{new_reader, new_writer, temp_operator};
+    use super::{Compression, CompressionPolicy, WriterOptions};
+    use crate::types::DataRecordHeader;
+
+    let (op, dir) = temp_operator("fetch_range");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            dedup: true,
+            compression: CompressionPolicy {
+                compression: Compression::Zstd,
+                min_size: 0,
+                content_types: Some(vec![String::from("text/plain")]),
+            },
+            ..Default::default()
+        },
+    );
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 253) as u8).collect();
+    let text = b"0123456789".repeat(1000);
+    let plain = writer
+        .put(data.clone(), String::from("plain"), None)
+        .await
+        .unwrap();
+    let link = writer
+        .put(data.clone(), String::from("link"), None)
+        .await
+        .unwrap();
+    let streamed = writer
+        .put_reader(&data[..], data.len() as u64, String::from("streamed"), None)
+        .await
+        .unwrap();
+    let compressed = writer
+        .put(text.clone(), String::from("a.txt"), None)
+        .await
+        .unwrap();
+    writer.close().await.unwrap();
+
+    let reader = new_reader(&op);
+    for (id, data) in [
+        (&plain, &data),
+        (&link, &data),
+        (&streamed, &data),
+        (&compressed, &text),
+    ] {
+        let size = data.len() as u64;
+        for range in [0..0, 0..size, 0..1, 4095..4200, size - 7..size] {
+            assert_eq!(
+                reader.fetch_range(id, range.clone(), true).await.unwrap(),
+                data[range.start as usize..range.end as usize].to_vec()
+            );
+        }
+        assert!(matches!(
+            reader.fetch_range(id, 0..size + 1, true).await,
+            Err(ErrorKind::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            reader.fetch_range(id, 5..4, true).await,
+            Err(ErrorKind::InvalidArgument(_))
+        ));
+    }
+
+    // checksum covers the whole data, a changed byte out of range fails only if it is checked.
+    let id = utils::parse_index_id(&plain).unwrap();
+    let data_path = dir.path().join(utils::get_data_file_path("", id.stack_id));
+    let mut bs = std::fs::read(&data_path).unwrap();
+    bs[id.offset_data as usize + DataRecordHeader::size() + 9000] ^= 1;
+    std::fs::write(&data_path, bs).unwrap();
+    assert_eq!(
+        reader.fetch_range(&plain, 0..10, false).await.unwrap(),
+        data[0..10].to_vec()
+    );
+    assert!(reader.fetch_range(&plain, 0..10, true).await.is_err());
+}
+
+#[tokio::test]
+async fn test_stat() {
+    use super::bs_opendal_testing::{new_writer, stack_of, temp_operator};
+    use super::{IndexCache, WriterOptions};
+
+    let (op, _dir) = temp_operator("stat");
+    let mut ids = vec![];
+    for version in [FormatVersion::V1, FormatVersion::V2] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version: version,
+                ..Default::default()
+            },
+        );
+        for i in 0..37u32 {
+            let meta = format!("m{}", i).into_bytes();
+            let id = writer
+                .put(vec![i as u8; i as usize], format!("f{}", i), Some(meta))
+                .await
+                .unwrap();
+            ids.push((id, i));
+        }
+        writer.close().await.unwrap();
+    }
+    bs_opendal_deletion::delete(&op, "", &ids[5].0)
+        .await
+        .unwrap();
+
+    // index file is searched by a binary search, or looked up in index cache.
+    for index_cache in [None, Some(Arc::new(IndexCache::new(1 << 20, None)))] {
+        let reader = BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                index_cache,
+                ..Default::default()
+            },
+        );
+        for (id, i) in ids.iter().filter(|(id, _)| id != &ids[5].0) {
+            let (ir, mr) = reader.stat(id).await.unwrap();
+            assert_eq!((ir.size_data, mr.filename.clone()), (*i, format!("f{}", i)));
+            assert_eq!(mr.extra, format!("m{}", i).into_bytes());
+            let (ir2, mr2, data) = reader.fetch_with_meta(id, true).await.unwrap();
+            assert_eq!(
+                (ir2.offset_data, mr2.filename),
+                (ir.offset_data, mr.filename)
+            );
+            assert_eq!(data, vec![*i as u8; *i as usize]);
+        }
+        let deleted = reader.stat(&ids[5].0).await;
+        assert!(
+            matches!(deleted, Err(ErrorKind::NotFound(_))),
+            "{:?}",
+            deleted
+        );
+        // no record at the offset, a wrong cookie, or an invalid index_id.
+        let id = utils::parse_index_id(&ids[3].0).unwrap();
+        let stack_id = stack_of(&ids[3].0);
+        let missing = format!("{},{:x}{:08x}", stack_id, id.offset_data + 1, id.cookie);
+        assert!(matches!(
+            reader.stat(&missing).await,
+            Err(ErrorKind::NotFound(_))
+        ));
+        let wrong = format!("{},{:x}{:08x}", stack_id, id.offset_data, id.cookie ^ 1);
+        assert!(matches!(
+            reader.stat(&wrong).await,
+            Err(ErrorKind::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            reader.stat("x").await,
+            Err(ErrorKind::InvalidArgument(_))
+        ));
     }
 }
@@ -0,0 +1,65 @@
+//! bs_opendal_reader_options provides options for BytestackOpendalReader
+
+use super::{IndexCache, Keyring};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// ReaderOptions controls how BytestackOpendalReader reads stacks.
+#[derive(Debug, Clone)]
+pub struct ReaderOptions {
+    /// keyring holds keys to decrypt encrypted stacks, an encrypted stack can not be read without its key
+    pub keyring: Option<Arc<Keyring>>,
+    /// batch_gap_tolerance is the max bytes between two records that batch_fetch still reads in one
+    /// ranged read, bytes in the gap are read and dropped. 0 only merges adjacent records.
+    pub batch_gap_tolerance: u64,
+    /// batch_max_range_size limits the size of one ranged read of batch_fetch, a single record larger
+    /// than it is still read in one range.
+    pub batch_max_range_size: u64,
+    /// batch_concurrency is how many ranged reads batch_fetch issues at the same time.
+    pub batch_concurrency: usize,
+    /// index_cache keeps parsed index files so that listing and iterating a stack again does not read
+    /// its index file again, it can be shared by readers.
+    pub index_cache: Option<Arc<IndexCache>>,
+    /// skip_bad_records makes iterators skip records which can not be decoded instead of returning
+    /// errors for them, skipped records are counted by error_count of iterators.
+    pub skip_bad_records: bool,
+    /// readahead_bytes is how many bytes of data file list_stack_al_with_data_iter reads ahead of the
+    /// consumer with concurrent ranged reads. 0 together with readahead_records = 0 disables readahead,
+    /// then records are read one after another by a single reader.
+    pub readahead_bytes: u64,
+    /// readahead_records is how many records list_stack_al_with_data_iter reads ahead of the consumer,
+    /// 0 means the window is only limited by readahead_bytes.
+    pub readahead_records: usize,
+    /// readahead_concurrency is how many ranged reads are in flight during a readahead scan, the window
+    /// is split evenly between them.
+    pub readahead_concurrency: usize,
+    /// shuffle_block_records is how many consecutive records of a stack list_al_with_data_shuffled_iter
+    /// reads together as a block, larger blocks read faster but shuffle less.
+    pub shuffle_block_records: usize,
+    /// shuffle_window_blocks is how many shuffled blocks have their records shuffled together, like
+    /// the size of a shuffle buffer, they are held in memory at the same time.
+    pub shuffle_window_blocks: usize,
+    /// tombstone_ttl is how long deleted records of a stack are cached by a reader, its deletion log
+    /// is listed again after that, so a record deleted while the reader is alive is served at most
+    /// tombstone_ttl after its deletion. Zero lists the deletion log on every read.
+    pub tombstone_ttl: Duration,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        ReaderOptions {
+            keyring: None,
+            batch_gap_tolerance: 256 * 1024,
+            batch_max_range_size: 16 * 1024 * 1024,
+            batch_concurrency: 8,
+            index_cache: None,
+            skip_bad_records: false,
+            readahead_bytes: 0,
+            readahead_records: 0,
+            readahead_concurrency: 8,
+            shuffle_block_records: 64,
+            shuffle_window_blocks: 16,
+            tombstone_ttl: Duration::from_secs(5),
+        }
+    }
+}
@@ -0,0 +1,531 @@
+//! bs_opendal_record provides helpers to read and decode data records in opendal way
+
+use super::bs_opendal_crypto::StackCrypto;
+use super::err::{CustomError, ErrorKind};
+use crate::types::data::{Checksum, Compression, DataRecordLarge, DataRecordLink};
+use crate::types::DataRecordHeader;
+use crate::utils;
+use crate::utils::checksum;
+use crate::utils::compress;
+use futures::AsyncReadExt;
+use opendal::Operator;
+use std::ops::Range;
+
+/// read_record_body read DataRecordHeader at offset_data and the stored bytes after it,
+/// magic and cookie are checked, padding is not read.
+pub async fn read_record_body(
+    operator: &Operator,
+    data_file_path: &str,
+    offset_data: u64,
+    cookie: u32,
+) -> Result<(DataRecordHeader, Vec<u8>), ErrorKind> {
+    let mut reader = match operator
+        .reader_with(data_file_path)
+        .range(offset_data..)
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+    };
+    let mut head_buf = vec![0; DataRecordHeader::size()];
+    match reader.read_exact(&mut head_buf).await {
+        Ok(_) => {}
+        Err(e) => {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+    }
+    let drh = match DataRecordHeader::new_from_bytes(&head_buf) {
+        Ok(drh) => drh,
+        Err(e) => {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+    };
+    if !drh.validate_magic() {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "invalid drh item",
+        ))));
+    }
+    if drh.cookie != cookie {
+        return Err(ErrorKind::InvalidArgument(CustomError::new(String::from(
+            "cookie mismatched",
+        ))));
+    }
+    let mut body = vec![0; drh.stored_size()];
+    match reader.read_exact(&mut body).await {
+        Ok(_) => {}
+        Err(e) => {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+    }
+    Ok((drh, body))
+}
+
+/// read_data read the data of record at offset_data, a link record is resolved to the record it points to,
+/// crypto is needed if the stack is encrypted.
+pub(crate) async fn read_data(
+    operator: &Operator,
+    data_file_path: &str,
+    offset_data: u64,
+    cookie: u32,
+    crypto: Option<&StackCrypto>,
+    check_crc: bool,
+) -> Result<Vec<u8>, ErrorKind> {
+    let (drh, body) = read_record_body(operator, data_file_path, offset_data, cookie).await?;
+    if drh.is_large() {
+        return read_large(
+            operator,
+            data_file_path,
+            offset_data,
+            &drh,
+            &body,
+            check_crc,
+        )
+        .await;
+    }
+    if !drh.is_link() {
+        return decode_body(&drh, offset_data, body, crypto, check_crc);
+    }
+    resolve_link(operator, data_file_path, &drh, &body, crypto, check_crc).await
+}
+
+/// read_record_header read DataRecordHeader at offset_data alone, magic and cookie are checked.
+pub(crate) async fn read_record_header(
+    operator: &Operator,
+    data_file_path: &str,
+    offset_data: u64,
+    cookie: u32,
+) -> Result<DataRecordHeader, ErrorKind> {
+    let head_buf = match operator
+        .range_read(
+            data_file_path,
+            offset_data..offset_data + DataRecordHeader::size() as u64,
+        )
+        .await
+    {
+        Ok(buf) => buf,
+        Err(e) => {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+    };
+    if head_buf.len() != DataRecordHeader::size() {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "record is truncated",
+        ))));
+    }
+    let drh = match DataRecordHeader::new_from_bytes(&head_buf) {
+        Ok(drh) => drh,
+        Err(e) => {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+    };
+    if !drh.validate_magic() {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "invalid drh item",
+        ))));
+    }
+    if drh.cookie != cookie {
+        return Err(ErrorKind::InvalidArgument(CustomError::new(String::from(
+            "cookie mismatched",
+        ))));
+    }
+    Ok(drh)
+}
+
+/// read_data_range read bytes in range of the data of record at offset_data, a link record is resolved
+/// to the record it points to. Only the header and the bytes in range are read unless data is compressed
+/// or encrypted, such a record is read and decoded whole. checksum is checked if check_crc, then the record is
+/// read whole too, except that only the chunk records holding range are read for a large record.
+/// A range out of the size of data is rejected with InvalidArgument.
+pub(crate) async fn read_data_range(
+    operator: &Operator,
+    data_file_path: &str,
+    offset_data: u64,
+    cookie: u32,
+    range: Range<u64>,
+    crypto: Option<&StackCrypto>,
+    check_crc: bool,
+) -> Result<Vec<u8>, ErrorKind> {
+    let mut drh = read_record_header(operator, data_file_path, offset_data, cookie).await?;
+    let mut offset_data = offset_data;
+    if drh.is_link() {
+        let body_offset = offset_data + DataRecordHeader::size() as u64;
+        let body = match operator
+            .range_read(
+                data_file_path,
+                body_offset..body_offset + DataRecordLink::size() as u64,
+            )
+            .await
+        {
+            Ok(body) => body,
+            Err(e) => {
+                return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+            }
+        };
+        if body.len() != DataRecordLink::size() {
+            return Err(ErrorKind::IOError(CustomError::new(String::from(
+                "record is truncated",
+            ))));
+        }
+        let link = parse_link(&body)?;
+        let target =
+            read_record_header(operator, data_file_path, link.offset_data, link.cookie).await?;
+        if target.is_link() || (!target.is_encoded() && target.size != drh.size) {
+            return Err(ErrorKind::IOError(CustomError::new(format!(
+                "link target at {} mismatched",
+                link.offset_data
+            ))));
+        }
+        drh = target;
+        offset_data = link.offset_data;
+    }
+    if drh.is_large() {
+        let body_offset = offset_data + DataRecordHeader::size() as u64;
+        let body = match operator
+            .range_read(
+                data_file_path,
+                body_offset..body_offset + DataRecordLarge::size() as u64,
+            )
+            .await
+        {
+            Ok(body) if body.len() == DataRecordLarge::size() => body,
+            Ok(_) => {
+                return Err(ErrorKind::IOError(CustomError::new(String::from(
+                    "record is truncated",
+                ))));
+            }
+            Err(e) => {
+                return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+            }
+        };
+        let large = parse_large(&drh, &body)?;
+        check_range(&range, large.size)?;
+        let mut out = Vec::with_capacity((range.end - range.start) as usize);
+        let mut chunk_start = 0;
+        for (chunk_offset, size) in large.chunks(offset_data, drh.checksum()) {
+            let chunk_end = chunk_start + size as u64;
+            if chunk_end > range.start && chunk_start < range.end {
+                let from = range.start.max(chunk_start) - chunk_start;
+                let to = range.end.min(chunk_end) - chunk_start;
+                if check_crc {
+                    let (chunk, chunk_body) =
+                        read_record_body(operator, data_file_path, chunk_offset, drh.cookie)
+                            .await?;
+                    let data =
+                        decode_chunk(&chunk, chunk_offset, size, drh.checksum(), chunk_body, true)?;
+                    out.extend_from_slice(&data[from as usize..to as usize]);
+                    chunk_start = chunk_end;
+                    continue;
+                }
+                let start = chunk_offset + DataRecordHeader::size() as u64;
+                match operator
+                    .range_read(data_file_path, start + from..start + to)
+                    .await
+                {
+                    Ok(buf) if buf.len() as u64 == to - from => out.extend(buf),
+                    Ok(_) => {
+                        return Err(ErrorKind::IOError(CustomError::new(String::from(
+                            "record is truncated",
+                        ))));
+                    }
+                    Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+                }
+            }
+            chunk_start = chunk_end;
+        }
+        return Ok(out);
+    }
+    if drh.is_encoded() || check_crc {
+        let data = read_data(
+            operator,
+            data_file_path,
+            offset_data,
+            drh.cookie,
+            crypto,
+            check_crc,
+        )
+        .await?;
+        return slice_data(data, range);
+    }
+    check_range(&range, drh.size as u64)?;
+    if range.start == range.end {
+        return Ok(Vec::new());
+    }
+    let start = offset_data + DataRecordHeader::size() as u64;
+    match operator
+        .range_read(data_file_path, start + range.start..start + range.end)
+        .await
+    {
+        Ok(buf) if buf.len() as u64 == range.end - range.start => Ok(buf),
+        Ok(_) => Err(ErrorKind::IOError(CustomError::new(String::from(
+            "record is truncated",
+        )))),
+        Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    }
+}
+
+/// slice_data return bytes in range of data, a range out of data is rejected with InvalidArgument.
+pub(crate) fn slice_data(mut data: Vec<u8>, range: Range<u64>) -> Result<Vec<u8>, ErrorKind> {
+    check_range(&range, data.len() as u64)?;
+    data.truncate(range.end as usize);
+    Ok(data.split_off(range.start as usize))
+}
+
+/// check_range check that range is in data of size bytes.
+fn check_range(range: &Range<u64>, size: u64) -> Result<(), ErrorKind> {
+    if range.start > range.end || range.end > size {
+        return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+            "range {}..{} is out of data of {} bytes",
+            range.start, range.end, size
+        ))));
+    }
+    Ok(())
+}
+
+/// resolve_link read the data of target record of a link record, body is the stored bytes of link record.
+pub(crate) async fn resolve_link(
+    operator: &Operator,
+    data_file_path: &str,
+    drh: &DataRecordHeader,
+    body: &[u8],
+    crypto: Option<&StackCrypto>,
+    check_crc: bool,
+) -> Result<Vec<u8>, ErrorKind> {
+    let link = parse_link(body)?;
+    let (target, target_body) =
+        read_record_body(operator, data_file_path, link.offset_data, link.cookie).await?;
+    decode_link_target(drh, &link, &target, target_body, crypto, check_crc)
+}
+
+/// parse_link parse DataRecordLink from body, the stored bytes of a link record.
+pub(crate) fn parse_link(body: &[u8]) -> Result<DataRecordLink, ErrorKind> {
+    match DataRecordLink::new_from_bytes(&body[..DataRecordLink::size()]) {
+        Ok(link) => Ok(link),
+        Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    }
+}
+
+/// decode_link_target check that target is the record link points to and decode its data,
+/// drh is the header of link record.
+pub(crate) fn decode_link_target(
+    drh: &DataRecordHeader,
+    link: &DataRecordLink,
+    target: &DataRecordHeader,
+    target_body: Vec<u8>,
+    crypto: Option<&StackCrypto>,
+    check_crc: bool,
+) -> Result<Vec<u8>, ErrorKind> {
+    if target.is_link()
+        || target.is_large()
+        || target.data_size(&target_body) != Some(drh.size)
+        || target.crc_from_body(&target_body) != drh.crc
+    {
+        return Err(ErrorKind::IOError(CustomError::new(format!(
+            "link target at {} mismatched",
+            link.offset_data
+        ))));
+    }
+    decode_body(target, link.offset_data, target_body, crypto, check_crc)
+}
+
+/// parse_large parse DataRecordLarge from body, the stored bytes of a large record, crc in header covers it.
+pub(crate) fn parse_large(
+    drh: &DataRecordHeader,
+    body: &[u8],
+) -> Result<DataRecordLarge, ErrorKind> {
+    let body = &body[..DataRecordLarge::size()];
+    if utils::CASTAGNOLI.checksum(body) != drh.crc {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "crc mismatch",
+        ))));
+    }
+    match DataRecordLarge::new_from_bytes(body) {
+        Ok(large) if large.valid() => Ok(large),
+        Ok(_) => Err(ErrorKind::IOError(CustomError::new(String::from(
+            "invalid large record",
+        )))),
+        Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    }
+}
+
+/// decode_chunk check that chunk is a chunk record of size bytes at offset_data checksummed by checksum
+/// and return its data, the cookie of chunk should be checked already.
+pub(crate) fn decode_chunk(
+    chunk: &DataRecordHeader,
+    offset_data: u64,
+    size: u32,
+    checksum: Checksum,
+    body: Vec<u8>,
+    check_crc: bool,
+) -> Result<Vec<u8>, ErrorKind> {
+    if !chunk.is_chunk()
+        || chunk.is_encoded()
+        || chunk.is_link()
+        || chunk.size != size
+        || chunk.checksum() != checksum
+    {
+        return Err(ErrorKind::IOError(CustomError::new(format!(
+            "invalid chunk record at {}",
+            offset_data
+        ))));
+    }
+    decode_body(chunk, offset_data, body, None, check_crc)
+}
+
+/// read_large read the data of large record at offset_data from its chunk records, body is the stored
+/// bytes of the large record.
+pub(crate) async fn read_large(
+    operator: &Operator,
+    data_file_path: &str,
+    offset_data: u64,
+    drh: &DataRecordHeader,
+    body: &[u8],
+    check_crc: bool,
+) -> Result<Vec<u8>, ErrorKind> {
+    let large = parse_large(drh, body)?;
+    let mut data = Vec::with_capacity(large.size as usize);
+    for (chunk_offset, size) in large.chunks(offset_data, drh.checksum()) {
+        let (chunk, chunk_body) =
+            read_record_body(operator, data_file_path, chunk_offset, drh.cookie).await?;
+        data.extend(decode_chunk(
+            &chunk,
+            chunk_offset,
+            size,
+            drh.checksum(),
+            chunk_body,
+            check_crc,
+        )?);
+    }
+    Ok(data)
+}
+
+/// parse_record_body parse DataRecordHeader and the stored bytes after it from buf,
+/// which starts with the record, it is the in-memory version of read_record_body.
+pub(crate) fn parse_record_body(
+    buf: &[u8],
+    cookie: u32,
+) -> Result<(DataRecordHeader, Vec<u8>), ErrorKind> {
+    if buf.len() < DataRecordHeader::size() {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "record is truncated",
+        ))));
+    }
+    let drh = match DataRecordHeader::new_from_bytes(&buf[..DataRecordHeader::size()]) {
+        Ok(drh) => drh,
+        Err(e) => {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+    };
+    if !drh.validate_magic() {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "invalid drh item",
+        ))));
+    }
+    if drh.cookie != cookie {
+        return Err(ErrorKind::InvalidArgument(CustomError::new(String::from(
+            "cookie mismatched",
+        ))));
+    }
+    let end = DataRecordHeader::size() + drh.stored_size();
+    if buf.len() < end {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "record is truncated",
+        ))));
+    }
+    let body = buf[DataRecordHeader::size()..end].to_vec();
+    if drh.data_size(&body).is_none() {
+        return Err(ErrorKind::CorruptedStack(CustomError::new(String::from(
+            "encoded record is too short to hold its size",
+        ))));
+    }
+    Ok((drh, body))
+}
+
+/// decode_body turn the stored bytes of record at offset_data into data, encrypted data is decrypted
+/// by crypto and compressed data is decompressed.
+/// checksum is checked if check_crc, by the algorithm flags of record tell. It covers stored bytes of an
+/// encrypted record and original data of the others.
+pub(crate) fn decode_body(
+    drh: &DataRecordHeader,
+    offset_data: u64,
+    mut body: Vec<u8>,
+    crypto: Option<&StackCrypto>,
+    check_crc: bool,
+) -> Result<Vec<u8>, ErrorKind> {
+    let expected_sum = drh.checksum_from_body(&body);
+    body.truncate(drh.size as usize);
+    let size = match drh.data_size(&body) {
+        Some(size) => size as usize,
+        None => {
+            return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                "encoded record at {} is too short to hold its size",
+                offset_data
+            ))));
+        }
+    };
+    let compression = drh.compression();
+    let encoded = if drh.is_encrypted() {
+        if check_crc && checksum::sum(drh.checksum(), &body) != expected_sum {
+            return Err(ErrorKind::IOError(CustomError::new(String::from(
+                "checksum mismatch",
+            ))));
+        }
+        let crypto = match crypto {
+            Some(crypto) => crypto,
+            None => {
+                return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                    "record at {} is encrypted but stack has no key",
+                    offset_data
+                ))));
+            }
+        };
+        crypto.decrypt_data(offset_data, drh.cookie, &body[4..])?
+    } else if compression != Compression::None {
+        body.split_off(4)
+    } else {
+        body
+    };
+    let data = if compression != Compression::None {
+        match compress::decompress(compression, &encoded, size) {
+            Ok(data) => data,
+            Err(e) => {
+                return Err(ErrorKind::IOError(CustomError::new(e)));
+            }
+        }
+    } else {
+        encoded
+    };
+    if data.len() != size {
+        return Err(ErrorKind::IOError(CustomError::new(format!(
+            "size of record at {} mismatch",
+            offset_data
+        ))));
+    }
+    // the plaintext of an encrypted record is authenticated by cipher, there is no checksum of it.
+    if check_crc && !drh.is_encrypted() && checksum::sum(drh.checksum(), &data) != expected_sum {
+        return Err(ErrorKind::IOError(CustomError::new(String::from(
+            "checksum mismatch",
+        ))));
+    }
+    Ok(data)
+}
+
+#[test]
+fn test_short_encoded_body() {
+    use crate::types::data::{DATA_RECORD_FLAG_ENCRYPTED, DATA_RECORD_FLAG_ZSTD};
+
+    for flags in [DATA_RECORD_FLAG_ZSTD, DATA_RECORD_FLAG_ENCRYPTED] {
+        let drh = DataRecordHeader::new_with_flags(7, 2, 0, flags);
+        let mut buf = bincode::serialize(&drh).unwrap();
+        buf.extend([1, 2]);
+        assert!(matches!(
+            parse_record_body(&buf, 7),
+            Err(ErrorKind::CorruptedStack(_))
+        ));
+        assert!(matches!(
+            decode_body(&drh, 4096, vec![1, 2], None, true),
+            Err(ErrorKind::CorruptedStack(_))
+        ));
+    }
+}
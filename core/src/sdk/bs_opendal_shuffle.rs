@@ -0,0 +1,305 @@
+//! bs_opendal_shuffle provides iteration over all records under a path in a shuffled order.
+//! Records of every stack are cut into blocks of consecutive records which are read by one ranged read
+//! (and one more after every large record), blocks are shuffled and records of every window of blocks
+//! are shuffled again, like a shuffle buffer.
+//! The order only depends on (seed, epoch) and the records listed, so an epoch can be repeated exactly.
+
+use super::bs_opendal_batch::{self, OpendalFetcher, StackSource};
+use super::bs_opendal_iterator::{decode_meta, DataItem, RecordSource, ScanError};
+use super::err::{CustomError, ErrorKind};
+use crate::types::IndexRecord;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::sync::Arc;
+
+/// ShuffleStack is a sealed stack to be shuffled, irs are its records which are not deleted and
+/// all_irs are all records in its index file.
+pub(crate) struct ShuffleStack {
+    pub(crate) source: Arc<StackSource>,
+    pub(crate) meta_file_path: String,
+    pub(crate) all_irs: Vec<IndexRecord>,
+    pub(crate) data_end: u64,
+    pub(crate) irs: Vec<IndexRecord>,
+}
+
+/// ShuffleBlock is consecutive records of a stack, its data and meta are read by one ranged read each,
+/// data is read by one more ranged read after every large record whose chunks are read one by one.
+struct ShuffleBlock {
+    source: Arc<StackSource>,
+    meta_file_path: Arc<String>,
+    irs: Vec<IndexRecord>,
+    fetchers: Vec<OpendalFetcher>,
+}
+
+impl ShuffleBlock {
+    /// fetch read data and meta of all records in this block, an error of one record does not
+    /// affect the others, an error of a read fails the block.
+    async fn fetch(self) -> Result<Vec<Result<DataItem, ErrorKind>>, ErrorKind> {
+        let mut data: Vec<Option<Result<Vec<u8>, ErrorKind>>> = Vec::new();
+        data.resize_with(self.irs.len(), || None);
+        for fetcher in &self.fetchers {
+            let buf = fetcher.read_range().await?;
+            for (position, res) in fetcher.decode_all(&buf).await {
+                data[position] = Some(res);
+            }
+        }
+        let meta_start = self.irs[0].offset_meta;
+        let meta_end = self
+            .irs
+            .iter()
+            .map(|ir| ir.offset_meta + ir.size_meta as u64)
+            .max()
+            .unwrap_or(meta_start);
+        let meta_buf = match self
+            .source
+            .operator
+            .range_read(&self.meta_file_path, meta_start..meta_end)
+            .await
+        {
+            Ok(buf) => buf,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        let crypto = self.source.crypto.as_deref();
+        let mut out = Vec::with_capacity(self.irs.len());
+        for (ir, data) in self.irs.into_iter().zip(data) {
+            let data = data.unwrap_or_else(|| {
+                Err(ErrorKind::IOError(CustomError::new(format!(
+                    "record at {} is not fetched",
+                    ir.offset_data
+                ))))
+            });
+            let start = (ir.offset_meta - meta_start) as usize;
+            let end = start + ir.size_meta as usize;
+            if end > meta_buf.len() {
+                out.push(Err(ErrorKind::IOError(CustomError::new(format!(
+                    "meta of record at {} is truncated",
+                    ir.offset_data
+                )))));
+                continue;
+            }
+            let item = decode_meta(&meta_buf[start..end], &ir, crypto)
+                .and_then(|mr| data.map(|data| (ir, mr, data)));
+            out.push(item);
+        }
+        Ok(out)
+    }
+}
+
+/// epoch_rng return the random generator of (seed, epoch), every epoch of a seed gets its own stream.
+/// ChaCha8Rng is value-stable, so the same (seed, epoch) gives the same numbers across releases.
+fn epoch_rng(seed: u64, epoch: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(epoch);
+    rng
+}
+
+/// shuffle items by Fisher-Yates, the index is drawn by multiply-shift instead of rand's
+/// gen_range so that the order does not change with the version of rand.
+fn shuffle<T>(items: &mut [T], rng: &mut ChaCha8Rng) {
+    for i in (1..items.len()).rev() {
+        let j = ((rng.next_u64() as u128 * (i as u128 + 1)) >> 64) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// EpochWindow is how many blocks a window has and the order of its records as
+/// (block in window, record in block).
+struct EpochWindow {
+    blocks: usize,
+    order: Vec<(usize, usize)>,
+}
+
+/// EpochSource yields IndexRecord, MetaRecord and data of all records of stacks in a shuffled order.
+/// Blocks are read by up to concurrency ranged reads ahead of the consumer, so memory is bounded by
+/// one window of blocks and concurrency blocks in flight.
+pub(crate) struct EpochSource {
+    windows: std::vec::IntoIter<EpochWindow>,
+    blocks: BoxStream<'static, Result<Vec<Result<DataItem, ErrorKind>>, ErrorKind>>,
+    current: Vec<Vec<Option<Result<DataItem, ErrorKind>>>>,
+    order: std::vec::IntoIter<(usize, usize)>,
+}
+
+impl EpochSource {
+    /// new EpochSource of stacks, which should be sorted by stack_id so that the order only depends
+    /// on (seed, epoch). block_records is how many records a block has and window_blocks is how many
+    /// blocks are shuffled together.
+    pub(crate) fn new(
+        stacks: Vec<ShuffleStack>,
+        seed: u64,
+        epoch: u64,
+        block_records: usize,
+        window_blocks: usize,
+        concurrency: usize,
+    ) -> Self {
+        let mut rng = epoch_rng(seed, epoch);
+        let mut blocks = Vec::new();
+        for stack in stacks {
+            let meta_file_path = Arc::new(stack.meta_file_path);
+            for irs in stack.irs.chunks(block_records.max(1)) {
+                let fetchers = bs_opendal_batch::plan_scan(
+                    stack.source.clone(),
+                    &stack.all_irs,
+                    stack.data_end,
+                    irs,
+                    u64::MAX,
+                    usize::MAX,
+                );
+                blocks.push(ShuffleBlock {
+                    source: stack.source.clone(),
+                    meta_file_path: meta_file_path.clone(),
+                    irs: irs.to_vec(),
+                    fetchers,
+                });
+            }
+        }
+        shuffle(&mut blocks, &mut rng);
+
+        let mut windows = Vec::new();
+        for window in blocks.chunks(window_blocks.max(1)) {
+            let mut order: Vec<(usize, usize)> = window
+                .iter()
+                .enumerate()
+                .flat_map(|(b, block)| (0..block.irs.len()).map(move |r| (b, r)))
+                .collect();
+            shuffle(&mut order, &mut rng);
+            windows.push(EpochWindow {
+                blocks: window.len(),
+                order,
+            });
+        }
+
+        let blocks = futures::stream::iter(blocks)
+            .map(|block| async move {
+                match tokio::spawn(block.fetch()).await {
+                    Ok(res) => res,
+                    Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+                }
+            })
+            .buffered(concurrency.max(1))
+            .boxed();
+        EpochSource {
+            windows: windows.into_iter(),
+            blocks,
+            current: Vec::new(),
+            order: Vec::new().into_iter(),
+        }
+    }
+
+    /// next_window read all blocks of the next window, false if there is no more window.
+    async fn next_window(&mut self) -> Result<bool, ScanError> {
+        let window = match self.windows.next() {
+            Some(window) => window,
+            None => return Ok(false),
+        };
+        self.current.clear();
+        for _ in 0..window.blocks {
+            match self.blocks.next().await {
+                Some(Ok(items)) => self.current.push(items.into_iter().map(Some).collect()),
+                Some(Err(e)) => return Err(ScanError::Reader(e)),
+                None => {
+                    return Err(ScanError::Reader(ErrorKind::IOError(CustomError::new(
+                        String::from("block of records is not fetched"),
+                    ))));
+                }
+            }
+        }
+        self.order = window.order.into_iter();
+        Ok(true)
+    }
+}
+
+impl RecordSource for EpochSource {
+    type Item = DataItem;
+
+    fn next_record(&mut self) -> BoxFuture<'_, Option<Result<Self::Item, ScanError>>> {
+        Box::pin(async move {
+            loop {
+                if let Some((b, r)) = self.order.next() {
+                    return match self.current[b][r].take() {
+                        Some(item) => Some(item.map_err(ScanError::Record)),
+                        None => Some(Err(ScanError::Reader(ErrorKind::IOError(
+                            CustomError::new(String::from("record is returned twice")),
+                        )))),
+                    };
+                }
+                match self.next_window().await {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_shuffle_determinism() {
+    use super::bs_opendal_deletion;
+    use super::bs_opendal_testing::{new_writer, temp_operator};
+    use super::{BytestackOpendalReader, ReaderOptions, WriterOptions};
+    use std::collections::HashSet;
+
+    let (op, _dir) = temp_operator("shuffle");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            max_records: Some(40),
+            ..Default::default()
+        },
+    );
+    let mut ids = vec![];
+    for i in 0..100u32 {
+        ids.push(
+            writer
+                .put(i.to_le_bytes().to_vec(), i.to_string(), None)
+                .await
+                .unwrap(),
+        );
+    }
+    writer.close().await.unwrap();
+    bs_opendal_deletion::delete(&op, "", &ids[42])
+        .await
+        .unwrap();
+
+    let epoch = |seed, epoch| {
+        let reader = BytestackOpendalReader::new_with_options(
+            op.clone(),
+            String::new(),
+            None,
+            ReaderOptions {
+                shuffle_block_records: 4,
+                shuffle_window_blocks: 3,
+                readahead_concurrency: 2,
+                ..Default::default()
+            },
+        );
+        async move {
+            let iter = reader
+                .list_al_with_data_shuffled_iter(seed, epoch)
+                .await
+                .unwrap();
+            iter.map(|res| {
+                let (_, mr, data) = res.unwrap();
+                assert_eq!(data, mr.filename.parse::<u32>().unwrap().to_le_bytes());
+                mr.filename.parse::<u32>().unwrap()
+            })
+            .collect::<Vec<u32>>()
+            .await
+        }
+    };
+    let order = epoch(7, 0).await;
+    // every record which is not deleted comes exactly once.
+    let expected: HashSet<u32> = (0..100).filter(|i| *i != 42).collect();
+    assert_eq!(order.len(), expected.len());
+    assert_eq!(order.iter().cloned().collect::<HashSet<u32>>(), expected);
+    let mut sorted = order.clone();
+    sorted.sort_unstable();
+    assert_ne!(order, sorted);
+    // the same seed and epoch give the same order with a new reader, another epoch or seed does not.
+    assert_eq!(epoch(7, 0).await, order);
+    assert_ne!(epoch(7, 1).await, order);
+    assert_ne!(epoch(8, 0).await, order);
+}
@@ -0,0 +1,227 @@
+//! bs_opendal_stack_id provides allocators which give every new stack a stack_id.
+
+use super::err::{CustomError, ErrorKind};
+use crate::utils;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use opendal::{ErrorKind as OpendalErrorKind, Operator};
+use proto::controller::controller_client::ControllerClient;
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tonic::transport::Channel;
+
+/// STACK_ID_COUNTER_FILE is saved under prefix by LocalCounterAllocator.
+pub const STACK_ID_COUNTER_FILE: &str = "stack_id.counter";
+
+/// _MAX_ALLOCATE_RETRIES limits how many times a colliding stack_id is regenerated.
+const _MAX_ALLOCATE_RETRIES: usize = 16;
+
+/// StackIdAllocator gives every new stack a stack_id which must not be used by any existing stack.
+#[async_trait]
+pub trait StackIdAllocator: Send + Sync {
+    /// next_stack_id return a new stack_id
+    async fn next_stack_id(&self) -> Result<u64, ErrorKind>;
+}
+
+/// ControllerAllocator ask controller for stack_id, the controller makes stack_id unique across machines.
+pub struct ControllerAllocator {
+    controller_cli: ControllerClient<Channel>,
+}
+
+impl ControllerAllocator {
+    /// new create ControllerAllocator
+    pub fn new(controller_cli: ControllerClient<Channel>) -> Self {
+        ControllerAllocator { controller_cli }
+    }
+}
+
+#[async_trait]
+impl StackIdAllocator for ControllerAllocator {
+    async fn next_stack_id(&self) -> Result<u64, ErrorKind> {
+        let req = tonic::Request::new(());
+        let mut controller_cli = self.controller_cli.clone();
+        match controller_cli.next_stack_id(req).await {
+            Ok(resp) => Ok(resp.get_ref().stack_id),
+            Err(e) => Err(ErrorKind::ControllerError(CustomError::new(e.to_string()))),
+        }
+    }
+}
+
+/// LocalCounterAllocator keeps a counter in STACK_ID_COUNTER_FILE next to the stacks, the counter
+/// starts after the largest existing stack_id.
+/// # Note
+/// The counter is only guarded inside this process, do not write the same prefix from several machines.
+pub struct LocalCounterAllocator {
+    operator: Operator,
+    prefix: String,
+    lock: Mutex<()>,
+}
+
+impl LocalCounterAllocator {
+    /// new create LocalCounterAllocator for stacks under prefix
+    pub fn new(operator: Operator, prefix: String) -> Self {
+        LocalCounterAllocator {
+            operator,
+            prefix,
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn read_counter(&self, counter_path: &str) -> Result<u64, ErrorKind> {
+        match self.operator.read(counter_path).await {
+            Ok(bs) => match String::from_utf8_lossy(&bs).trim().parse::<u64>() {
+                Ok(counter) => Ok(counter),
+                Err(e) => Err(ErrorKind::IOError(CustomError::new(format!(
+                    "invalid counter in {}: {}",
+                    counter_path, e
+                )))),
+            },
+            Err(e) if e.kind() == OpendalErrorKind::NotFound => {
+                max_stack_id(&self.operator, &self.prefix).await
+            }
+            Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        }
+    }
+}
+
+#[async_trait]
+impl StackIdAllocator for LocalCounterAllocator {
+    async fn next_stack_id(&self) -> Result<u64, ErrorKind> {
+        let _guard = self.lock.lock().await;
+        let counter_path = format!("{}{}", self.prefix, STACK_ID_COUNTER_FILE);
+        let mut stack_id = self.read_counter(&counter_path).await? + 1;
+        while stack_exists(&self.operator, &self.prefix, stack_id).await? {
+            stack_id += 1;
+        }
+        if let Err(e) = self
+            .operator
+            .write(&counter_path, stack_id.to_string())
+            .await
+        {
+            return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
+        }
+        Ok(stack_id)
+    }
+}
+
+/// RandomAllocator generates random stack_id, or time-ordered ones whose high 44 bits are unix
+/// milliseconds and low 20 bits are random, a stack_id whose index or data file exists is regenerated.
+pub struct RandomAllocator {
+    operator: Operator,
+    prefix: String,
+    time_ordered: bool,
+    last: Mutex<u64>,
+}
+
+impl RandomAllocator {
+    /// new create RandomAllocator for stacks under prefix
+    pub fn new(operator: Operator, prefix: String, time_ordered: bool) -> Self {
+        RandomAllocator {
+            operator,
+            prefix,
+            time_ordered,
+            last: Mutex::new(0),
+        }
+    }
+
+    fn generate(&self, last: u64) -> u64 {
+        let mut rng = rand::thread_rng();
+        if !self.time_ordered {
+            return rng.gen_range(1..=u64::MAX);
+        }
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let stack_id = (millis << 20) | rng.gen_range(0..1 << 20);
+        // keep stack_ids given by this allocator increasing even if clock goes back.
+        stack_id.max(last + 1)
+    }
+}
+
+#[async_trait]
+impl StackIdAllocator for RandomAllocator {
+    async fn next_stack_id(&self) -> Result<u64, ErrorKind> {
+        let mut last = self.last.lock().await;
+        for _ in 0.._MAX_ALLOCATE_RETRIES {
+            let stack_id = self.generate(*last);
+            if !stack_exists(&self.operator, &self.prefix, stack_id).await? {
+                *last = stack_id;
+                return Ok(stack_id);
+            }
+        }
+        Err(ErrorKind::IOError(CustomError::new(format!(
+            "no free stack_id after {} retries",
+            _MAX_ALLOCATE_RETRIES
+        ))))
+    }
+}
+
+/// stack_exists check if index or data file of stack_id exists under prefix, a data file without
+/// index is left by a writer which died early and may still be recovered, so its stack_id is taken.
+async fn stack_exists(operator: &Operator, prefix: &str, stack_id: u64) -> Result<bool, ErrorKind> {
+    let paths = [
+        utils::get_index_file_path(prefix, stack_id),
+        utils::get_data_file_path(prefix, stack_id),
+    ];
+    for path in paths {
+        match operator.is_exist(&path).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        }
+    }
+    Ok(false)
+}
+
+/// max_stack_id return the largest stack_id of index or data files under prefix, 0 if there is no stack.
+async fn max_stack_id(operator: &Operator, prefix: &str) -> Result<u64, ErrorKind> {
+    let mut ds = match operator.list_with(prefix).await {
+        Ok(ds) => ds,
+        Err(e) if e.kind() == OpendalErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+    };
+    let mut max = 0;
+    loop {
+        match ds.try_next().await {
+            Ok(Some(de)) => {
+                let name = de.name();
+                let stack_id =
+                    utils::parse_index_stack_id(name).or_else(|| utils::parse_data_stack_id(name));
+                if let Some(stack_id) = stack_id {
+                    max = max.max(stack_id);
+                }
+            }
+            Ok(None) => break,
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        }
+    }
+    Ok(max)
+}
+
+#[tokio::test]
+async fn test_skip_existing_stack() {
+    let operator = Operator::new(opendal::services::Memory::default())
+        .unwrap()
+        .finish();
+    // stack 1 only has a data file, left by a writer which died before writing its index.
+    operator
+        .write(&utils::get_data_file_path("", 1), vec![0; 16])
+        .await
+        .unwrap();
+    operator
+        .write(&utils::get_index_file_path("", 2), vec![0; 16])
+        .await
+        .unwrap();
+    assert!(stack_exists(&operator, "", 1).await.unwrap());
+    assert!(!stack_exists(&operator, "", 3).await.unwrap());
+    operator
+        .write(&utils::get_data_file_path("", 4), vec![0; 16])
+        .await
+        .unwrap();
+    let allocator = LocalCounterAllocator::new(operator.clone(), String::new());
+    assert_eq!(allocator.next_stack_id().await.unwrap(), 5);
+    operator.write(STACK_ID_COUNTER_FILE, "0").await.unwrap();
+    assert_eq!(allocator.next_stack_id().await.unwrap(), 3);
+}
@@ -0,0 +1,46 @@
+//! bs_opendal_testing provides helpers for round-trip tests of the sdk on a local directory,
+//! stack_id is allocated by LocalCounterAllocator so no controller is needed.
+
+use super::{BytestackOpendalReader, BytestackOpendalWriter, LocalCounterAllocator, WriterOptions};
+use crate::utils;
+use opendal::services::Fs;
+use opendal::Operator;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// temp_operator return an Operator on an empty temp directory prefixed by name, with its guard,
+/// the directory is removed when the guard is dropped, even if the test fails.
+pub(crate) fn temp_operator(name: &str) -> (Operator, TempDir) {
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("bytestack_{}_", name))
+        .tempdir()
+        .unwrap();
+    let mut builder = Fs::default();
+    builder.root(dir.path().to_str().unwrap());
+    (Operator::new(builder).unwrap().finish(), dir)
+}
+
+/// new_writer create a BytestackOpendalWriter with options, a LocalCounterAllocator is used if
+/// options has no stack_id_allocator.
+pub(crate) fn new_writer(
+    operator: &Operator,
+    mut options: WriterOptions,
+) -> BytestackOpendalWriter {
+    if options.stack_id_allocator.is_none() {
+        options.stack_id_allocator = Some(Arc::new(LocalCounterAllocator::new(
+            operator.clone(),
+            String::new(),
+        )));
+    }
+    BytestackOpendalWriter::new(operator.clone(), String::new(), None, options)
+}
+
+/// new_reader create a BytestackOpendalReader with default options.
+pub(crate) fn new_reader(operator: &Operator) -> BytestackOpendalReader {
+    BytestackOpendalReader::new(operator.clone(), String::new(), None)
+}
+
+/// stack_of return stack_id of index_id.
+pub(crate) fn stack_of(index_id: &str) -> u64 {
+    utils::parse_index_id(index_id).unwrap().stack_id
+}
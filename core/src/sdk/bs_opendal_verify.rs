@@ -0,0 +1,921 @@
+//! bs_opendal_verify provides verification of stacks in opendal way, like fsck of a file system.
+//! A problem found in a stack is reported with the file and offset where it is, so that a stack is
+//! checked as a whole instead of failing at the first problem.
+
+use super::bs_opendal_crypto::StackCrypto;
+use super::bs_opendal_names;
+use super::bs_opendal_record;
+use super::err::{CustomError, ErrorKind};
+use crate::types::data::{
+    Checksum, DataChecksumHeader, DataLayoutHeader, DataRecordLarge, DataRecordLink,
+    DataSealedHeader, DATA_CHECKSUM_HEADER_OFFSET, DATA_FORMAT_HEADER_OFFSET,
+    DATA_HEADER_BLOCK_SIZE, DATA_LAYOUT_HEADER_OFFSET, DATA_SEALED_HEADER_OFFSET,
+    DATA_SEAL_BLOCK_SIZE, DEFAULT_ALIGNMENT_SIZE, LARGE_RECORD_SIZE,
+};
+use crate::types::{
+    DataMagicHeader, DataRecordHeader, FormatHeader, FormatVersion, IndexMagicHeader, IndexRecord,
+    MetaEncoding, MetaMagicHeader, MetaRecord, StackSeal,
+};
+use crate::utils;
+use crate::utils::checksum;
+use futures::StreamExt;
+use opendal::{ErrorKind as OpendalErrorKind, Operator};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// VERIFY_RANGE_SIZE is the max size of one ranged read of data file in a full verification.
+const VERIFY_RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// VERIFY_CONCURRENCY is how many ranged reads of data file are issued at the same time.
+const VERIFY_CONCURRENCY: usize = 16;
+
+/// VerifyLevel decides how deep verify_stack checks a stack, every level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyLevel {
+    /// Headers checks magic headers, stack_id and seals of index, meta and data file, crc of index and
+    /// meta file and the filename index. Data file is not read beyond its header and seal.
+    Headers,
+    /// Records checks every IndexRecord against its MetaRecord and DataRecordHeader too: offsets,
+    /// sizes, cookies, checksum algorithms and link targets. Only the header of every record is read.
+    Records,
+    /// Full checks checksum of every record too, which reads the whole data file. Encrypted records are
+    /// decrypted if the key is known, only checksum of their stored bytes is checked otherwise.
+    Full,
+}
+
+/// VerifyProblem is a problem found by verify_stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyProblem {
+    /// file is the path of the file where the problem is
+    pub file: String,
+    /// offset is where the problem is in file
+    pub offset: u64,
+    /// message describes the problem
+    pub message: String,
+}
+
+/// VerifyReport is the result of verify_stack.
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    /// stack_id of the verified stack
+    pub stack_id: u64,
+    /// level the stack is verified at
+    pub level: VerifyLevel,
+    /// records is how many IndexRecords are checked
+    pub records: u64,
+    /// checksum is the algorithm records of the stack are checksummed by as data header says
+    pub checksum: Checksum,
+    /// problems found in the stack, empty if the stack is healthy
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    /// is_healthy check if no problem is found
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Verifier collects problems of one stack.
+struct Verifier<'a> {
+    operator: &'a Operator,
+    prefix: &'a str,
+    index_file_path: String,
+    meta_file_path: String,
+    data_file_path: String,
+    /// version of index file, meta and data file should have the same one.
+    version: FormatVersion,
+    /// index_header_size is where IndexRecords start in index file.
+    index_header_size: usize,
+    /// meta_encoding is how MetaRecords are encoded as meta header says.
+    meta_encoding: MetaEncoding,
+    /// alignment of records as data header says.
+    alignment: usize,
+    /// unsealed_index is the length of index file of a V1 stack if it has no seal, which is fine only
+    /// if the stack was written before seals.
+    unsealed_index: Option<u64>,
+    report: VerifyReport,
+}
+
+/// verify_stack check stack_id under prefix at level, problems of the stack are returned in the report,
+/// an error is returned only if the storage can not be read. crypto is used to decrypt records at
+/// VerifyLevel::Full, it can be None even if the stack is encrypted.
+pub(crate) async fn verify_stack(
+    operator: &Operator,
+    prefix: &str,
+    stack_id: u64,
+    level: VerifyLevel,
+    crypto: Option<&StackCrypto>,
+) -> Result<VerifyReport, ErrorKind> {
+    let mut v = Verifier {
+        operator,
+        prefix,
+        index_file_path: utils::get_index_file_path(prefix, stack_id),
+        meta_file_path: utils::get_meta_file_path(prefix, stack_id),
+        data_file_path: utils::get_data_file_path(prefix, stack_id),
+        version: FormatVersion::V1,
+        index_header_size: IndexMagicHeader::size(),
+        meta_encoding: MetaEncoding::Json,
+        alignment: DEFAULT_ALIGNMENT_SIZE,
+        unsealed_index: None,
+        report: VerifyReport {
+            stack_id,
+            level,
+            records: 0,
+            checksum: Checksum::Crc32c,
+            problems: Vec::new(),
+        },
+    };
+    let (irs, seal) = match v.check_index().await? {
+        Some(index) => index,
+        None => return Ok(v.report),
+    };
+    let meta = v.check_meta(seal.as_ref()).await?;
+    let data_end = v.check_data_file(seal.as_ref()).await?;
+    v.check_names(irs.len() as u64).await;
+    if level >= VerifyLevel::Records {
+        if let Some(meta) = meta {
+            v.check_meta_records(&irs, &meta);
+        }
+        if let Some(data_end) = data_end {
+            v.check_data_records(&irs, data_end, level, crypto).await?;
+        }
+    }
+    v.report.records = irs.len() as u64;
+    Ok(v.report)
+}
+
+impl Verifier<'_> {
+    fn problem(&mut self, file: &str, offset: u64, message: String) {
+        self.report.problems.push(VerifyProblem {
+            file: file.to_string(),
+            offset,
+            message,
+        });
+    }
+
+    /// check_version report a problem if version of the file at path differs from index file.
+    fn check_version(&mut self, path: &str, version: FormatVersion) {
+        if version != self.version {
+            self.problem(
+                path,
+                0,
+                format!(
+                    "format version {} differs from version {} of index file",
+                    version.id(),
+                    self.version.id()
+                ),
+            );
+        }
+    }
+
+    /// read return the whole file at path, None if it does not exist.
+    async fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>, ErrorKind> {
+        match self.operator.read(path).await {
+            Ok(bs) => Ok(Some(bs)),
+            Err(e) if e.kind() == OpendalErrorKind::NotFound => {
+                self.problem(path, 0, String::from("file does not exist"));
+                Ok(None)
+            }
+            Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        }
+    }
+
+    /// check_index check header, seal and crc of index file, return its IndexRecords and the seal if
+    /// it is valid. None means there is nothing left to check without IndexRecords.
+    async fn check_index(
+        &mut self,
+    ) -> Result<Option<(Vec<IndexRecord>, Option<StackSeal>)>, ErrorKind> {
+        let path = self.index_file_path.clone();
+        let stack_id = self.report.stack_id;
+        let bs = match self.read(&path).await? {
+            Some(bs) => bs,
+            None => return Ok(None),
+        };
+        if bs.len() < IndexMagicHeader::size() {
+            self.problem(&path, 0, String::from("index file is truncated"));
+            return Ok(None);
+        }
+        match bincode::deserialize::<IndexMagicHeader>(&bs[..IndexMagicHeader::size()]) {
+            Ok(imh) if imh.valid() => {
+                if imh.stack_id != stack_id {
+                    self.problem(&path, 0, format!("header has stack_id {}", imh.stack_id));
+                }
+                self.version = imh.version();
+                self.index_header_size = imh.header_size();
+            }
+            _ => self.problem(&path, 0, String::from("invalid magic header")),
+        }
+        let header_size = self.index_header_size;
+        if bs.len() < header_size {
+            self.problem(&path, 0, String::from("index file is truncated"));
+            return Ok(None);
+        }
+        if self.version == FormatVersion::V2 {
+            let offset = IndexMagicHeader::size();
+            match FormatHeader::new_from_bytes(&bs[offset..header_size]) {
+                Ok(fh) if fh.valid() => {}
+                _ => self.problem(&path, offset as u64, String::from("invalid format header")),
+            }
+        }
+
+        let records_size = bs.len() - header_size;
+        let mut seal = None;
+        let mut records_end = bs.len();
+        if records_size.is_multiple_of(IndexRecord::size()) {
+            // V1 stacks written before seals have no seal to check, it is told by data header.
+            if self.version == FormatVersion::V1 {
+                self.unsealed_index = Some(bs.len() as u64);
+            } else {
+                self.problem(
+                    &path,
+                    bs.len() as u64,
+                    String::from("index file is not sealed"),
+                );
+            }
+        } else if records_size < StackSeal::size()
+            || !(records_size - StackSeal::size()).is_multiple_of(IndexRecord::size())
+        {
+            self.problem(
+                &path,
+                bs.len() as u64,
+                String::from("index file is truncated"),
+            );
+            records_end = header_size + records_size / IndexRecord::size() * IndexRecord::size();
+        } else {
+            records_end = bs.len() - StackSeal::size();
+            match StackSeal::new_from_bytes(&bs[records_end..]) {
+                Ok(s) if s.valid() && s.stack_id == stack_id => {
+                    let record_count = (records_end - header_size) / IndexRecord::size();
+                    if s.record_count != record_count as u64 {
+                        self.problem(
+                            &path,
+                            records_end as u64,
+                            format!(
+                                "seal counts {} records but there are {}",
+                                s.record_count, record_count
+                            ),
+                        );
+                    }
+                    if s.index_crc != utils::CASTAGNOLI.checksum(&bs[..records_end]) {
+                        self.problem(
+                            &path,
+                            records_end as u64,
+                            String::from("index crc mismatch"),
+                        );
+                    }
+                    seal = Some(s);
+                }
+                _ => self.problem(&path, records_end as u64, String::from("invalid seal")),
+            }
+        }
+
+        let mut irs = Vec::with_capacity((records_end - header_size) / IndexRecord::size());
+        for chunk in bs[header_size..records_end].chunks(IndexRecord::size()) {
+            match IndexRecord::new_from_bytes(chunk) {
+                Ok(ir) => irs.push(ir),
+                Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+            }
+        }
+        Ok(Some((irs, seal)))
+    }
+
+    /// check_meta check header, seal and crc of meta file, return its content.
+    async fn check_meta(&mut self, seal: Option<&StackSeal>) -> Result<Option<Vec<u8>>, ErrorKind> {
+        let path = self.meta_file_path.clone();
+        let stack_id = self.report.stack_id;
+        let bs = match self.read(&path).await? {
+            Some(bs) => bs,
+            None => return Ok(None),
+        };
+        match MetaMagicHeader::new_from_bytes(&bs) {
+            Ok((mh, _)) if mh.valid() => {
+                if mh.stack_id != stack_id {
+                    self.problem(&path, 0, format!("header has stack_id {}", mh.stack_id));
+                }
+                self.check_version(&path, mh.version());
+                self.meta_encoding = mh.encoding();
+            }
+            _ => self.problem(&path, 0, String::from("invalid magic header")),
+        }
+        let seal = match seal {
+            Some(seal) => seal,
+            None => return Ok(Some(bs)),
+        };
+        // the seal is the last line of a json meta file, or the last StackSeal::size() bytes.
+        let (seal_start, meta_seal) = match self.meta_encoding {
+            MetaEncoding::Json => {
+                let body = bs.strip_suffix(b"\n").unwrap_or(&bs);
+                let seal_start = body.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+                let meta_seal = serde_json::from_slice::<StackSeal>(&body[seal_start..]).ok();
+                (seal_start, meta_seal)
+            }
+            MetaEncoding::Bincode => {
+                let seal_start = bs.len().saturating_sub(StackSeal::size());
+                let meta_seal = StackSeal::new_from_bytes(&bs[seal_start..])
+                    .ok()
+                    .filter(|s| s.valid());
+                (seal_start, meta_seal)
+            }
+        };
+        match meta_seal {
+            Some(meta_seal) if &meta_seal == seal => {
+                if seal.meta_crc != utils::CASTAGNOLI.checksum(&bs[..seal_start]) {
+                    self.problem(&path, seal_start as u64, String::from("meta crc mismatch"));
+                }
+            }
+            Some(_) => self.problem(
+                &path,
+                seal_start as u64,
+                String::from("seal mismatch the seal of index file"),
+            ),
+            None => self.problem(
+                &path,
+                seal_start as u64,
+                String::from("meta file is not sealed"),
+            ),
+        }
+        Ok(Some(bs))
+    }
+
+    /// check_data_file check header and seal of data file, return where records end in data file,
+    /// None if data file can not be read.
+    async fn check_data_file(
+        &mut self,
+        seal: Option<&StackSeal>,
+    ) -> Result<Option<u64>, ErrorKind> {
+        let path = self.data_file_path.clone();
+        let stack_id = self.report.stack_id;
+        let len = match self.operator.stat(&path).await {
+            Ok(meta) => meta.content_length(),
+            Err(e) if e.kind() == OpendalErrorKind::NotFound => {
+                self.problem(&path, 0, String::from("file does not exist"));
+                return Ok(None);
+            }
+            Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        };
+        if len < DATA_HEADER_BLOCK_SIZE as u64 {
+            self.problem(&path, 0, String::from("data file is truncated"));
+            return Ok(None);
+        }
+        let format_end = DATA_FORMAT_HEADER_OFFSET + FormatHeader::size();
+        let layout_end = DATA_LAYOUT_HEADER_OFFSET + DataLayoutHeader::size();
+        let checksum_end = DATA_CHECKSUM_HEADER_OFFSET + DataChecksumHeader::size();
+        let sealed_end = DATA_SEALED_HEADER_OFFSET + DataSealedHeader::size();
+        let dh_bytes = self.range_read(&path, 0..sealed_end as u64).await?;
+        match self.unsealed_index {
+            Some(index_len)
+                if DataSealedHeader::has_magic(
+                    &dh_bytes[DATA_SEALED_HEADER_OFFSET..sealed_end],
+                ) =>
+            {
+                let index_file_path = self.index_file_path.clone();
+                self.problem(
+                    &index_file_path,
+                    index_len,
+                    String::from("index file is not sealed"),
+                );
+            }
+            _ => {}
+        }
+        match bincode::deserialize::<DataMagicHeader>(&dh_bytes[..DataMagicHeader::size()]) {
+            Ok(dh) if dh.valid() => {
+                if dh.stack_id != stack_id {
+                    self.problem(&path, 0, format!("header has stack_id {}", dh.stack_id));
+                }
+                self.check_version(&path, dh.version());
+                if dh.version() == FormatVersion::V2 {
+                    match FormatHeader::new_from_bytes(
+                        &dh_bytes[DATA_FORMAT_HEADER_OFFSET..format_end],
+                    ) {
+                        Ok(fh) if fh.valid() => {}
+                        _ => self.problem(
+                            &path,
+                            DATA_FORMAT_HEADER_OFFSET as u64,
+                            String::from("invalid format header"),
+                        ),
+                    }
+                    match DataLayoutHeader::alignment_from_bytes(
+                        &dh_bytes[DATA_LAYOUT_HEADER_OFFSET..layout_end],
+                    ) {
+                        Some(alignment) => self.alignment = alignment,
+                        None => self.problem(
+                            &path,
+                            DATA_LAYOUT_HEADER_OFFSET as u64,
+                            String::from("invalid layout header"),
+                        ),
+                    }
+                    match DataChecksumHeader::checksum_from_bytes(
+                        &dh_bytes[DATA_CHECKSUM_HEADER_OFFSET..checksum_end],
+                    ) {
+                        Some(checksum) => self.report.checksum = checksum,
+                        None => self.problem(
+                            &path,
+                            DATA_CHECKSUM_HEADER_OFFSET as u64,
+                            String::from("invalid checksum header"),
+                        ),
+                    }
+                }
+            }
+            _ => self.problem(&path, 0, String::from("invalid magic header")),
+        }
+        let seal = match seal {
+            Some(seal) => seal,
+            None => return Ok(Some(len)),
+        };
+        if len != seal.data_bytes + DATA_SEAL_BLOCK_SIZE as u64 {
+            self.problem(
+                &path,
+                len,
+                format!(
+                    "data file has {} bytes, {} are expected by seal",
+                    len,
+                    seal.data_bytes + DATA_SEAL_BLOCK_SIZE as u64
+                ),
+            );
+        }
+        if len < seal.data_bytes + StackSeal::size() as u64 {
+            self.problem(
+                &path,
+                seal.data_bytes,
+                String::from("data file is not sealed"),
+            );
+            return Ok(Some(len.min(seal.data_bytes)));
+        }
+        let seal_bytes = self
+            .range_read(
+                &path,
+                seal.data_bytes..seal.data_bytes + StackSeal::size() as u64,
+            )
+            .await?;
+        match StackSeal::new_from_bytes(&seal_bytes) {
+            // crcs of index and meta file are not compared, they change when the stack is repaired.
+            Ok(data_seal)
+                if data_seal.valid()
+                    && data_seal.stack_id == seal.stack_id
+                    && data_seal.record_count == seal.record_count
+                    && data_seal.data_bytes == seal.data_bytes => {}
+            Ok(data_seal) if data_seal.valid() => self.problem(
+                &path,
+                seal.data_bytes,
+                String::from("seal mismatch the seal of index file"),
+            ),
+            _ => self.problem(
+                &path,
+                seal.data_bytes,
+                String::from("data file is not sealed"),
+            ),
+        }
+        Ok(Some(seal.data_bytes))
+    }
+
+    /// check_names check the filename index if there is one, stacks written before filename index
+    /// exists have none.
+    async fn check_names(&mut self, record_count: u64) {
+        let stack_id = self.report.stack_id;
+        let path = utils::get_names_file_path(self.prefix, stack_id);
+        match bs_opendal_names::read_name_index(self.operator, self.prefix, stack_id).await {
+            Ok(Some(names)) => {
+                if names.header.entry_count != record_count
+                    || names
+                        .entries
+                        .iter()
+                        .any(|e| e.position as u64 >= record_count)
+                {
+                    self.problem(
+                        &path,
+                        0,
+                        String::from("filename index mismatch records of index file"),
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => self.problem(&path, 0, format!("{:?}", e)),
+        }
+    }
+
+    /// check_meta_records check that every IndexRecord points to a MetaRecord of the same record.
+    fn check_meta_records(&mut self, irs: &[IndexRecord], meta: &[u8]) {
+        let path = self.meta_file_path.clone();
+        for ir in irs {
+            let start = ir.offset_meta as usize;
+            let end = start + ir.size_meta as usize;
+            if end > meta.len() {
+                self.problem(
+                    &path,
+                    ir.offset_meta,
+                    String::from("meta record is out of meta file"),
+                );
+                continue;
+            }
+            let is_json = meta[start..end].iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{');
+            if is_json != (self.meta_encoding == MetaEncoding::Json) {
+                self.problem(
+                    &path,
+                    ir.offset_meta,
+                    format!(
+                        "meta record is not encoded with {:?} as meta header says",
+                        self.meta_encoding
+                    ),
+                );
+                continue;
+            }
+            match MetaRecord::decode(&meta[start..end]) {
+                Ok(mr) => {
+                    if mr.offset_data != ir.offset_data
+                        || mr.cookie != ir.cookie
+                        || mr.size_data != ir.size_data
+                    {
+                        self.problem(
+                            &path,
+                            ir.offset_meta,
+                            format!(
+                                "meta record is of record at {} but index points to record at {}",
+                                mr.offset_data, ir.offset_data
+                            ),
+                        );
+                    }
+                }
+                Err(e) => {
+                    self.problem(&path, ir.offset_meta, format!("invalid meta record: {}", e))
+                }
+            }
+        }
+    }
+
+    /// check_data_records check that every IndexRecord points to a record with the same cookie and
+    /// size which ends before the next one starts, and checksum of every record at VerifyLevel::Full.
+    async fn check_data_records(
+        &mut self,
+        irs: &[IndexRecord],
+        data_end: u64,
+        level: VerifyLevel,
+        crypto: Option<&StackCrypto>,
+    ) -> Result<(), ErrorKind> {
+        let index_path = self.index_file_path.clone();
+        // records by offset_data with cookie and size to check links.
+        let mut records: HashMap<u64, (u32, u32)> = HashMap::with_capacity(irs.len());
+        let mut extents = Vec::with_capacity(irs.len());
+        let mut last_end = DATA_HEADER_BLOCK_SIZE as u64;
+        for (i, ir) in irs.iter().enumerate() {
+            let index_offset = (self.index_header_size + i * IndexRecord::size()) as u64;
+            let end = irs.get(i + 1).map_or(data_end, |next| next.offset_data);
+            if ir.offset_data < last_end || end <= ir.offset_data || end > data_end {
+                self.problem(
+                    &index_path,
+                    index_offset,
+                    format!(
+                        "record at {} is out of order or out of data file",
+                        ir.offset_data
+                    ),
+                );
+                continue;
+            }
+            last_end = end;
+            records.insert(ir.offset_data, (ir.cookie, ir.size_data));
+            extents.push((ir, ir.offset_data..end));
+        }
+
+        if level < VerifyLevel::Full {
+            let operator = self.operator;
+            let data_path = self.data_file_path.clone();
+            let mut heads = futures::stream::iter(extents.iter().map(|(ir, extent)| {
+                let range = head_range(ir, extent);
+                let data_path = &data_path;
+                async move { operator.range_read(data_path, range).await }
+            }))
+            .buffered(VERIFY_CONCURRENCY);
+            let mut i = 0;
+            while let Some(head) = heads.next().await {
+                let (ir, extent) = &extents[i];
+                i += 1;
+                match head {
+                    Ok(head) => self.check_record(ir, extent, &head, &records, None),
+                    Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+                }
+            }
+            return Ok(());
+        }
+
+        // group records into ranged reads of up to VERIFY_RANGE_SIZE bytes, large records are
+        // checked chunk by chunk instead.
+        let mut ranges: Vec<(Range<u64>, Vec<usize>)> = Vec::new();
+        for (i, (ir, extent)) in extents.iter().enumerate() {
+            if ir.size_data == LARGE_RECORD_SIZE {
+                let head = self
+                    .range_read(&self.data_file_path, head_range(ir, extent))
+                    .await?;
+                if self.check_large_chunks(ir, &head).await? {
+                    self.check_record(ir, extent, &head, &records, None);
+                    continue;
+                }
+            }
+            if let Some((range, items)) = ranges.last_mut() {
+                if extent.end - range.start <= VERIFY_RANGE_SIZE {
+                    range.end = extent.end;
+                    items.push(i);
+                    continue;
+                }
+            }
+            ranges.push((extent.clone(), vec![i]));
+        }
+        let operator = self.operator;
+        let data_path = self.data_file_path.clone();
+        let mut bufs = futures::stream::iter(ranges.iter().map(|(range, _)| {
+            let data_path = &data_path;
+            async move { operator.range_read(data_path, range.clone()).await }
+        }))
+        .buffered(VERIFY_CONCURRENCY);
+        let mut r = 0;
+        while let Some(buf) = bufs.next().await {
+            let (range, items) = &ranges[r];
+            r += 1;
+            let buf = match buf {
+                Ok(buf) => buf,
+                Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+            };
+            for i in items {
+                let (ir, extent) = &extents[*i];
+                let start = ((extent.start - range.start) as usize).min(buf.len());
+                let end = ((extent.end - range.start) as usize).min(buf.len());
+                self.check_record(ir, extent, &buf[start..end], &records, Some(crypto));
+            }
+        }
+        Ok(())
+    }
+
+    /// check_record check the record of ir, buf is the start of the record within extent, the whole
+    /// extent if checksum is checked, crypto is Some if checksum is checked.
+    fn check_record(
+        &mut self,
+        ir: &IndexRecord,
+        extent: &Range<u64>,
+        buf: &[u8],
+        records: &HashMap<u64, (u32, u32)>,
+        crypto: Option<Option<&StackCrypto>>,
+    ) {
+        let path = self.data_file_path.clone();
+        let offset = ir.offset_data;
+        if buf.len() < DataRecordHeader::size() {
+            self.problem(&path, offset, String::from("record is truncated"));
+            return;
+        }
+        let drh = match DataRecordHeader::new_from_bytes(&buf[..DataRecordHeader::size()]) {
+            Ok(drh) if drh.validate_magic() => drh,
+            _ => {
+                self.problem(&path, offset, String::from("invalid record header"));
+                return;
+            }
+        };
+        if drh.cookie != ir.cookie {
+            self.problem(&path, offset, String::from("cookie mismatch index"));
+            return;
+        }
+        if !offset.is_multiple_of(self.alignment as u64) {
+            self.problem(
+                &path,
+                offset,
+                format!("record is not aligned to {}", self.alignment),
+            );
+        }
+        // a link record has no data to checksum.
+        if !drh.is_link() && drh.checksum() != self.report.checksum {
+            self.problem(
+                &path,
+                offset,
+                format!(
+                    "record is checksummed by {} instead of {}",
+                    drh.checksum().name(),
+                    self.report.checksum.name()
+                ),
+            );
+        }
+        let record_end = offset + (DataRecordHeader::size() + drh.body_size(self.alignment)) as u64;
+        if record_end > extent.end {
+            self.problem(
+                &path,
+                offset,
+                format!(
+                    "record ends at {} beyond next record at {}",
+                    record_end, extent.end
+                ),
+            );
+            return;
+        }
+        let body = &buf[DataRecordHeader::size()..];
+        if drh.is_large() {
+            if ir.size_data != LARGE_RECORD_SIZE {
+                self.problem(
+                    &path,
+                    offset,
+                    String::from("large record has a size in index"),
+                );
+            }
+            if body.len() < DataRecordLarge::size() {
+                self.problem(&path, offset, String::from("record is truncated"));
+                return;
+            }
+            match bs_opendal_record::parse_large(&drh, body) {
+                Ok(large) if large.end(offset, drh.checksum()) > extent.end => self.problem(
+                    &path,
+                    offset,
+                    format!(
+                        "chunks end at {} beyond next record at {}",
+                        large.end(offset, drh.checksum()),
+                        extent.end
+                    ),
+                ),
+                Ok(_) => {}
+                Err(e) => self.problem(&path, offset, format!("{:?}", e)),
+            }
+            // chunks are checked by check_large_chunks.
+            return;
+        }
+        if drh.is_link() {
+            if body.len() < DataRecordLink::size() {
+                self.problem(&path, offset, String::from("record is truncated"));
+                return;
+            }
+            match bs_opendal_record::parse_link(body) {
+                Ok(link) => match records.get(&link.offset_data) {
+                    Some((cookie, size)) if *cookie == link.cookie && *size == ir.size_data => {}
+                    _ => self.problem(
+                        &path,
+                        offset,
+                        format!("link target at {} mismatch", link.offset_data),
+                    ),
+                },
+                Err(e) => self.problem(&path, offset, format!("{:?}", e)),
+            }
+        }
+        let data_size = match drh.data_size(body) {
+            Some(data_size) => data_size,
+            None => {
+                self.problem(&path, offset, String::from("record is truncated"));
+                return;
+            }
+        };
+        if data_size != ir.size_data {
+            self.problem(
+                &path,
+                offset,
+                format!("record has {} bytes, index has {}", data_size, ir.size_data),
+            );
+            return;
+        }
+        let crypto = match crypto {
+            Some(crypto) if !drh.is_link() => crypto,
+            _ => return,
+        };
+        if body.len() < drh.stored_size() {
+            self.problem(&path, offset, String::from("record is truncated"));
+            return;
+        }
+        let body = body[..drh.stored_size()].to_vec();
+        let res = if drh.is_encrypted() && crypto.is_none() {
+            // checksum of an encrypted record covers its stored bytes, so it is checked without key.
+            if checksum::sum(drh.checksum(), &body[..drh.size as usize])
+                == drh.checksum_from_body(&body)
+            {
+                Ok(())
+            } else {
+                Err(String::from("checksum mismatch"))
+            }
+        } else {
+            match bs_opendal_record::decode_body(&drh, offset, body, crypto, true) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("{:?}", e)),
+            }
+        };
+        if let Err(message) = res {
+            self.problem(&path, offset, message);
+        }
+    }
+
+    /// check_large_chunks check checksum of every chunk record of the large record of ir, head is the start
+    /// of the record. false if it is not a large record, it is checked as a normal one then.
+    /// Problems of the large record itself are left to check_record.
+    async fn check_large_chunks(
+        &mut self,
+        ir: &IndexRecord,
+        head: &[u8],
+    ) -> Result<bool, ErrorKind> {
+        let path = self.data_file_path.clone();
+        let drh = match head.get(..DataRecordHeader::size()) {
+            Some(bs) => match DataRecordHeader::new_from_bytes(bs) {
+                Ok(drh) if drh.validate_magic() && drh.is_large() => drh,
+                _ => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        let body = &head[DataRecordHeader::size()..];
+        if drh.cookie != ir.cookie || body.len() < DataRecordLarge::size() {
+            return Ok(true);
+        }
+        let large = match bs_opendal_record::parse_large(&drh, body) {
+            Ok(large) => large,
+            Err(_) => return Ok(true),
+        };
+        let checksum = drh.checksum();
+        for (chunk_offset, size) in large.chunks(ir.offset_data, checksum) {
+            let chunk_end =
+                chunk_offset + (DataRecordHeader::size() + size as usize + checksum.size()) as u64;
+            let buf = self.range_read(&path, chunk_offset..chunk_end).await?;
+            let res = bs_opendal_record::parse_record_body(&buf, ir.cookie).and_then(
+                |(chunk, chunk_body)| {
+                    bs_opendal_record::decode_chunk(
+                        &chunk,
+                        chunk_offset,
+                        size,
+                        checksum,
+                        chunk_body,
+                        true,
+                    )
+                },
+            );
+            if let Err(e) = res {
+                self.problem(&path, chunk_offset, format!("{:?}", e));
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn range_read(&self, path: &str, range: Range<u64>) -> Result<Vec<u8>, ErrorKind> {
+        match self.operator.range_read(path, range).await {
+            Ok(bs) => Ok(bs),
+            Err(e) => Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
+        }
+    }
+}
+
+/// head_range return the range of record of ir to check its header, link or large record within extent.
+fn head_range(ir: &IndexRecord, extent: &Range<u64>) -> Range<u64> {
+    let head_size = DataRecordHeader::size() + DataRecordLink::size().max(DataRecordLarge::size());
+    ir.offset_data..(ir.offset_data + head_size as u64).min(extent.end)
+}
+
+#[tokio::test]
+async fn test_verify_stack() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, dir) = temp_operator("verify_stack");
+    let writer = new_writer(&op, Default::default());
+    let mut ids = vec![];
+    for i in 0..5u8 {
+        ids.push(
+            writer
+                .put(vec![i; 5000], i.to_string(), None)
+                .await
+                .unwrap(),
+        );
+    }
+    writer.close().await.unwrap();
+    let stack_id = stack_of(&ids[0]);
+    let reader = new_reader(&op);
+    for level in [
+        VerifyLevel::Headers,
+        VerifyLevel::Records,
+        VerifyLevel::Full,
+    ] {
+        let report = reader.verify_stack(stack_id, level).await.unwrap();
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        assert_eq!((report.records, report.checksum), (5, Checksum::Crc32c));
+    }
+
+    let data_path = dir.path().join(utils::get_data_file_path("", stack_id));
+    let meta_path = dir.path().join(utils::get_meta_file_path("", stack_id));
+    let (ir, _) = reader.stat(&ids[2]).await.unwrap();
+    // (file, offset of the flipped byte, deepest level the stack is still healthy at)
+    let cases = [
+        (&data_path, ir.offset_data + 100, Some(VerifyLevel::Records)),
+        (&data_path, ir.offset_data + 4, Some(VerifyLevel::Headers)),
+        (&meta_path, ir.offset_meta + 2, None),
+    ];
+    for (path, offset, healthy_at) in cases {
+        let origin = std::fs::read(path).unwrap();
+        let mut bs = origin.clone();
+        bs[offset as usize] ^= 0xff;
+        std::fs::write(path, bs).unwrap();
+        for level in [
+            VerifyLevel::Headers,
+            VerifyLevel::Records,
+            VerifyLevel::Full,
+        ] {
+            let report = reader.verify_stack(stack_id, level).await.unwrap();
+            if Some(level) <= healthy_at {
+                assert!(report.is_healthy(), "{:?}", report.problems);
+            } else {
+                // the problem is reported where it is.
+                assert!(
+                    report.problems.iter().any(|p| p
+                        .file
+                        .ends_with(path.file_name().unwrap().to_str().unwrap())),
+                    "{:?}",
+                    report.problems
+                );
+            }
+        }
+        std::fs::write(path, origin).unwrap();
+    }
+}
@@ -1,40 +1,172 @@
 //! bs_writer provides all tools for writing bytestacks
 
+use super::bs_opendal_crypto::StackCrypto;
+use super::bs_opendal_names::NameIndex;
+use super::bs_opendal_stack_id::{ControllerAllocator, StackIdAllocator};
 use super::err::{CustomError, ErrorKind};
+use super::{SealedStack, WriterOptions};
+use crate::types::data::{
+    padding_data_size, valid_alignment, Checksum, Compression, DataChecksumHeader,
+    DataLayoutHeader, DataRecordLarge, DataRecordLink, DataSealedHeader,
+    DATA_CHECKSUM_HEADER_OFFSET, DATA_FORMAT_HEADER_OFFSET, DATA_HEADER_BLOCK_SIZE,
+    DATA_LAYOUT_HEADER_OFFSET, DATA_RECORD_FLAG_CHUNK, DATA_RECORD_FLAG_CRC_TRAILER,
+    DATA_RECORD_FLAG_ENCRYPTED, DATA_SEALED_HEADER_OFFSET, DATA_SEAL_BLOCK_SIZE,
+    DEFAULT_ALIGNMENT_SIZE, LARGE_RECORD_SIZE,
+};
+use crate::types::names;
 use crate::types::{
-    DataMagicHeader, DataRecord, IndexMagicHeader, IndexRecord, MetaMagicHeader, MetaRecord,
+    DataMagicHeader, DataRecord, DataRecordHeader, FormatHeader, FormatVersion, IndexMagicHeader,
+    IndexRecord, MetaEncoding, MetaMagicHeader, MetaRecord, StackSeal, FORMAT_FLAG_META_BINCODE,
 };
 use bincode;
 use proto::controller::controller_client::ControllerClient;
 use tonic::transport::Channel;
 
 use crate::utils;
+use crate::utils::checksum;
+use crate::utils::compress;
+use log::warn;
 use opendal::Operator;
-use rand::rngs::ThreadRng;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde_json;
 
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, MutexGuard};
 
+use crc::Digest;
+use futures::{AsyncRead, AsyncReadExt};
 use opendal::Writer;
 
-/// _MAX_DATA_BYTES for test now
-const _MAX_DATA_BYTES: usize = 5 * 1024 * 1024 * 1024;
+/// _STREAM_CHUNK_SIZE is the size of buffer used by put_reader
+const _STREAM_CHUNK_SIZE: usize = 256 * 1024;
+/// _ENCRYPTED_RECORD_OVERHEAD is what encryption adds to data: size prefix, nonce and tag
+const _ENCRYPTED_RECORD_OVERHEAD: u64 = 32;
+
+/// DedupTarget is a record already written into current stack, identical data is linked to it.
+struct DedupTarget {
+    offset_data: u64,
+    cookie: u32,
+    size: u32,
+    crc: u32,
+    stored_size: usize,
+}
+
+/// WriterStats is a snapshot of what BytestackOpendalWriter has written.
+#[derive(Debug, Clone, Default)]
+pub struct WriterStats {
+    /// records is how many records are put
+    pub records: u64,
+    /// deduplicated_records is how many records are linked to identical data instead of written
+    pub deduplicated_records: u64,
+    /// saved_bytes is how many bytes of data file are saved by deduplication
+    pub saved_bytes: u64,
+}
+
 /// InnnerWriter is the real one who write data.
-/// User may write data all the timem but we divided billions of data by every 10GB
+/// User may write data all the time but we divided billions of data into stacks by WriterOptions.
 struct InnerWriter {
     data_offset: u64,
     meta_offset: u64,
-    rng: ThreadRng,
+    index_offset: u64,
+    index_digest: Digest<'static, u32>,
+    meta_digest: Digest<'static, u32>,
+    record_count: u64,
+    created_at: Instant,
+    dedup: Option<HashMap<[u8; 32], DedupTarget>>,
+    crypto: Option<StackCrypto>,
+    rng: StdRng,
     stack_id: u64,
+    /// name_hashes of every record in index order, they become filename index at close
+    name_hashes: Vec<[u8; 16]>,
+    name_key: Option<[u8; 32]>,
+    meta_encoding: MetaEncoding,
+    /// alignment every record of this stack is padded to
+    alignment: usize,
+    /// checksum every record of this stack is checksummed by
+    checksum: Checksum,
+    operator: Operator,
+    names_file_path: String,
     _current_index_writer: Writer,
     _current_meta_writer: Writer,
     _current_data_writer: Writer,
 }
 
 impl InnerWriter {
-    /// close
-    async fn close(mut self) -> Result<(), ErrorKind> {
+    /// should_rollover check if writing data_size more bytes exceeds any limit of opts.
+    /// an empty stack never rolls over, so that a big record can always be written.
+    fn should_rollover(&self, opts: &WriterOptions, data_size: usize) -> bool {
+        if self.record_count == 0 {
+            return false;
+        }
+        let record_size = DataRecordHeader::size() + padding_data_size(data_size, self.alignment);
+        if self.data_offset + record_size as u64 > opts.max_data_bytes {
+            return true;
+        }
+        if let Some(max_records) = opts.max_records {
+            if self.record_count >= max_records {
+                return true;
+            }
+        }
+        if let Some(max_meta_bytes) = opts.max_meta_bytes {
+            if self.meta_offset >= max_meta_bytes {
+                return true;
+            }
+        }
+        if let Some(max_age) = opts.max_age {
+            if self.created_at.elapsed() >= max_age {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// close write StackSeal to the end of data, meta and index file, then flush and close them,
+    /// return the SealedStack describing this stack.
+    /// filename index is written before index file is sealed.
+    /// index file is closed at last, so a sealed index means the whole stack is complete.
+    async fn close(mut self) -> Result<SealedStack, ErrorKind> {
+        let seal = StackSeal::new(
+            self.stack_id,
+            self.record_count,
+            self.data_offset,
+            self.index_digest.clone().finalize(),
+            self.meta_digest.clone().finalize(),
+        );
+        let mut data_bytes = bincode::serialize(&seal).unwrap();
+        data_bytes.resize(DATA_SEAL_BLOCK_SIZE, 0);
+        self.data_offset += data_bytes.len() as u64;
+        self.write_data_bytes(data_bytes).await?;
+        let meta_bytes = match self.meta_encoding {
+            MetaEncoding::Json => {
+                let mut meta_bytes = serde_json::to_vec(&seal).unwrap();
+                meta_bytes.push(b'\n');
+                meta_bytes
+            }
+            MetaEncoding::Bincode => bincode::serialize(&seal).unwrap(),
+        };
+        self.meta_offset += meta_bytes.len() as u64;
+        if let Err(err) = self._current_meta_writer.write(meta_bytes).await {
+            return Err(ErrorKind::IOError(CustomError::new(err.to_string())));
+        }
+        let name_index =
+            NameIndex::new_from_hashes(self.stack_id, &self.name_hashes, self.name_key.is_some());
+        if let Err(err) = self
+            .operator
+            .write(&self.names_file_path, name_index.to_bytes())
+            .await
+        {
+            return Err(ErrorKind::IOError(CustomError::new(err.to_string())));
+        }
+        let index_bytes = bincode::serialize(&seal).unwrap();
+        self.index_offset += index_bytes.len() as u64;
+        if let Err(err) = self._current_index_writer.write(index_bytes).await {
+            return Err(ErrorKind::IOError(CustomError::new(err.to_string())));
+        }
+
         if let Err(err) = self._current_data_writer.close().await {
             return Err(ErrorKind::CloseError(CustomError::new(err.to_string())));
         }
@@ -44,13 +176,39 @@ impl InnerWriter {
         if let Err(err) = self._current_index_writer.close().await {
             return Err(ErrorKind::CloseError(CustomError::new(err.to_string())));
         }
-        Ok(())
+        Ok(SealedStack {
+            stack_id: self.stack_id,
+            record_count: self.record_count,
+            data_bytes: self.data_offset,
+            meta_bytes: self.meta_offset,
+            index_bytes: self.index_offset,
+        })
+    }
+
+    /// abort give up this stack after a failed write, its files are aborted, or closed unsealed if
+    /// the storage can not abort them, so that recover_stack can salvage records written before.
+    async fn abort(mut self) {
+        let writers = [
+            &mut self._current_data_writer,
+            &mut self._current_meta_writer,
+            &mut self._current_index_writer,
+        ];
+        for writer in writers {
+            let res = match writer.abort().await {
+                Err(e) if e.kind() == opendal::ErrorKind::Unsupported => writer.close().await,
+                res => res,
+            };
+            if let Err(e) = res {
+                warn!(target: "writer", "abort stack {}: {}", self.stack_id, e);
+            }
+        }
     }
 
     /// write_index
     async fn write_index(&mut self, ir: IndexRecord) -> Result<usize, ErrorKind> {
         let data_bytes = bincode::serialize(&ir).unwrap();
         let index_bytes_length = data_bytes.len();
+        self.index_digest.update(&data_bytes);
         match self._current_index_writer.write(data_bytes).await {
             Ok(_) => return Ok(index_bytes_length),
             Err(err) => return Err(ErrorKind::IOError(CustomError::new(err.to_string()))),
@@ -58,10 +216,9 @@ impl InnerWriter {
     }
 
     /// write_meta
-    async fn write_meta(&mut self, mr: MetaRecord) -> Result<usize, ErrorKind> {
-        let mut data_bytes = serde_json::to_vec(&mr).unwrap();
-        data_bytes.push(b'\n');
+    async fn write_meta(&mut self, data_bytes: Vec<u8>) -> Result<usize, ErrorKind> {
         let meta_bytes_length = data_bytes.len();
+        self.meta_digest.update(&data_bytes);
         match self._current_meta_writer.write(data_bytes).await {
             Ok(_) => return Ok(meta_bytes_length),
             Err(err) => return Err(ErrorKind::IOError(CustomError::new(err.to_string()))),
@@ -86,9 +243,12 @@ impl InnerWriter {
         }
     }
 
-    async fn write(
+    /// write_entry write IndexRecord and MetaRecord of data at offset_data, return the index_id
+    async fn write_entry(
         &mut self,
-        buf: Vec<u8>,
+        cookie: u32,
+        offset_data: u64,
+        size_data: u32,
         filename: String,
         meta: Option<Vec<u8>>,
     ) -> Result<String, ErrorKind> {
@@ -96,125 +256,657 @@ impl InnerWriter {
             Some(meta) => meta,
             None => Vec::new(),
         };
-        let crc_sum = utils::CASTAGNOLI.checksum(&buf);
-        let cookie: u32 = self.rng.gen();
-
-        let mr = MetaRecord::new(
+        let name_hash = names::name_hash(&filename, self.name_key.as_ref());
+        let mut mr = MetaRecord::new(
             utils::current_time(),
-            self.data_offset,
+            offset_data,
             cookie,
-            buf.len() as u32,
+            size_data,
             filename,
             meta,
         );
-        let mr_size = mr.size();
+        if let Some(crypto) = &self.crypto {
+            if crypto.encrypt_meta() {
+                crypto.seal_meta(&mut mr);
+            }
+        }
+        let mr_bytes = mr.encode(self.meta_encoding);
+        let mr_size = mr_bytes.len();
         let ir = IndexRecord::new(
             cookie,
-            self.data_offset,
-            buf.len() as u32,
+            offset_data,
+            size_data,
             self.meta_offset,
             mr_size as u32,
         );
         let index_id = ir.index_id();
-        let dr = DataRecord::new(cookie, buf.len() as u32, crc_sum, buf);
 
         match self.write_index(ir).await {
-            Ok(_) => {}
+            Ok(n) => {
+                self.index_offset += n as u64;
+            }
             Err(e) => return Err(e),
         }
-        match self.write_meta(mr).await {
-            Ok(n) => {
-                assert!(n == mr_size, "meta size mismatch");
+        match self.write_meta(mr_bytes).await {
+            Ok(n) if n == mr_size => {
                 self.meta_offset += n as u64;
             }
+            Ok(n) => {
+                return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                    "meta size mismatch, {} bytes written, expected {}",
+                    n, mr_size
+                ))));
+            }
             Err(e) => return Err(e),
         }
+        self.name_hashes.push(name_hash);
+        self.record_count += 1;
+        Ok(format!("{},{}", self.stack_id, index_id))
+    }
+
+    /// write write buf as a record, return the index_id and how many bytes are saved by deduplication.
+    /// if compressed is given, it is saved in place of buf.
+    /// Like write_stream, data is written before index and meta, so a failed write never leaves an
+    /// index entry pointing to missing data.
+    /// if dedup is enabled and identical data is already in current stack, a link record is written
+    /// instead of data, but only if it saves space.
+    async fn write(
+        &mut self,
+        buf: Vec<u8>,
+        compressed: Option<(Compression, Vec<u8>)>,
+        filename: String,
+        meta: Option<Vec<u8>>,
+    ) -> Result<(String, u64), ErrorKind> {
+        let cookie: u32 = self.rng.gen();
+        let offset_data = self.data_offset;
+        let size_data = buf.len() as u32;
+        let hash = self.dedup.as_ref().map(|_| *blake3::hash(&buf).as_bytes());
+        let target = match (&self.dedup, &hash) {
+            (Some(dedup), Some(hash)) => dedup.get(hash),
+            _ => None,
+        };
+        let saved_bytes = match target {
+            Some(target) if target.size == size_data => {
+                padding_data_size(target.stored_size, self.alignment)
+                    .saturating_sub(padding_data_size(DataRecordLink::size(), self.alignment))
+                    as u64
+            }
+            _ => 0,
+        };
+        let mut new_target = None;
+        let dr = match target {
+            Some(target) if saved_bytes > 0 => {
+                let link = DataRecordLink {
+                    offset_data: target.offset_data,
+                    cookie: target.cookie,
+                };
+                DataRecord::new_link(cookie, target.size, target.crc, &link, self.alignment)
+            }
+            _ => {
+                let checksum = self.checksum;
+                let dr = match (&self.crypto, compressed) {
+                    (Some(crypto), compressed) => {
+                        let (flags, encoded) = match compressed {
+                            Some((compression, compressed)) => (compression.flag(), compressed),
+                            None => (0, buf),
+                        };
+                        let sealed = crypto.encrypt_data(offset_data, cookie, &encoded);
+                        let dr = DataRecord::new_encoded(
+                            cookie,
+                            size_data,
+                            0,
+                            flags | DATA_RECORD_FLAG_ENCRYPTED,
+                            sealed,
+                            self.alignment,
+                        );
+                        // checksum of an encrypted record covers stored bytes, it reveals nothing about data.
+                        let sum = checksum::sum(checksum, &dr.data);
+                        dr.with_checksum(checksum, &sum, self.alignment)
+                    }
+                    (None, Some((compression, compressed))) => {
+                        let sum = checksum::sum(checksum, &buf);
+                        DataRecord::new_compressed(
+                            cookie,
+                            size_data,
+                            0,
+                            compression,
+                            compressed,
+                            self.alignment,
+                        )
+                        .with_checksum(checksum, &sum, self.alignment)
+                    }
+                    (None, None) => {
+                        let sum = checksum::sum(checksum, &buf);
+                        let dr = DataRecord::new(cookie, size_data, 0, buf, self.alignment);
+                        dr.with_checksum(checksum, &sum, self.alignment)
+                    }
+                };
+                if let Some(hash) = hash {
+                    new_target = Some((
+                        hash,
+                        DedupTarget {
+                            offset_data,
+                            cookie,
+                            size: size_data,
+                            crc: dr.header.crc,
+                            stored_size: dr.data.len(),
+                        },
+                    ));
+                }
+                dr
+            }
+        };
+
         match self.write_data(dr).await {
             Ok(n) => {
                 self.data_offset += n as u64;
             }
             Err(e) => return Err(e),
         }
+        // data is linked only once it is written.
+        if let (Some(dedup), Some((hash, target))) = (&mut self.dedup, new_target) {
+            dedup.entry(hash).or_insert(target);
+        }
+        let id = self
+            .write_entry(cookie, offset_data, size_data, filename, meta)
+            .await?;
+        Ok((id, saved_bytes))
+    }
 
-        Ok(format!("{},{}", self.stack_id, index_id))
+    /// write_stream copy size bytes from reader into data file, the crc is computed while copying
+    /// and saved as a trailer right after data.
+    /// Data is written before index and meta, so a reader ending early leaves nothing reachable:
+    /// the rest of record is filled with 0 and its trailer never matches, InvalidArgument is returned
+    /// and the stack should be sealed, see release_stream.
+    async fn write_stream<R>(
+        &mut self,
+        mut reader: R,
+        size: u32,
+        filename: String,
+        meta: Option<Vec<u8>>,
+    ) -> Result<String, ErrorKind>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let cookie: u32 = self.rng.gen();
+        let offset_data = self.data_offset;
+        self.write_stream_record(&mut reader, cookie, size, DATA_RECORD_FLAG_CRC_TRAILER)
+            .await?;
+        self.write_entry(cookie, offset_data, size, filename, meta)
+            .await
+    }
+
+    /// write_large copy size bytes from reader into data file as a large record, data is saved in
+    /// chunk records right after it, see DataRecordLarge.
+    /// Like write_stream, a reader ending early leaves nothing reachable: no more chunk is written,
+    /// InvalidArgument is returned and the stack should be sealed, see release_stream.
+    async fn write_large<R>(
+        &mut self,
+        mut reader: R,
+        size: u64,
+        filename: String,
+        meta: Option<Vec<u8>>,
+    ) -> Result<String, ErrorKind>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let cookie: u32 = self.rng.gen();
+        let offset_data = self.data_offset;
+        let large = DataRecordLarge::new(size, self.alignment);
+        let n = self
+            .write_data(DataRecord::new_large(
+                cookie,
+                &large,
+                self.checksum,
+                self.alignment,
+            ))
+            .await?;
+        self.data_offset += n as u64;
+        for (chunk_offset, chunk_size) in large.chunks(offset_data, self.checksum) {
+            if chunk_offset != self.data_offset {
+                return Err(ErrorKind::CorruptedStack(CustomError::new(format!(
+                    "chunk offset mismatch, chunk at {}, data file at {}",
+                    chunk_offset, self.data_offset
+                ))));
+            }
+            self.write_stream_record(
+                &mut reader,
+                cookie,
+                chunk_size,
+                DATA_RECORD_FLAG_CRC_TRAILER | DATA_RECORD_FLAG_CHUNK,
+            )
+            .await?;
+        }
+        self.write_entry(cookie, offset_data, LARGE_RECORD_SIZE, filename, meta)
+            .await
+    }
+
+    /// write_stream_record copy size bytes from reader into data file as a record with flags, which
+    /// should have DATA_RECORD_FLAG_CRC_TRAILER, the flag of checksum of stack is added.
+    /// If reader ends early, the rest of record is filled with 0, its trailer never matches and
+    /// InvalidArgument is returned.
+    async fn write_stream_record<R>(
+        &mut self,
+        reader: &mut R,
+        cookie: u32,
+        size: u32,
+        flags: u16,
+    ) -> Result<(), ErrorKind>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let drh = DataRecordHeader::new_with_flags(cookie, size, 0, flags | self.checksum.flag());
+        let body_size = drh.body_size(self.alignment);
+        self.write_data_bytes(bincode::serialize(&drh).unwrap())
+            .await?;
+
+        let mut digest = checksum::Digest::new(self.checksum);
+        let mut remaining = size as usize;
+        let mut read_err = None;
+        let mut buf = vec![0; _STREAM_CHUNK_SIZE];
+        while remaining > 0 {
+            let want = remaining.min(buf.len());
+            match reader.read(&mut buf[..want]).await {
+                Ok(0) => {
+                    read_err = Some(format!(
+                        "reader ended after {} of {} bytes",
+                        size as usize - remaining,
+                        size
+                    ));
+                    break;
+                }
+                Ok(n) => {
+                    digest.update(&buf[..n]);
+                    self.write_data_bytes(buf[..n].to_vec()).await?;
+                    remaining -= n;
+                }
+                Err(e) => {
+                    read_err = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+        let mut tail = if read_err.is_some() {
+            // keep alignment of data file, and make sure this record never passes checksum.
+            let zeros = vec![0; remaining];
+            digest.update(&zeros);
+            self.write_data_bytes(zeros).await?;
+            digest.finalize().iter().map(|b| !b).collect()
+        } else {
+            digest.finalize()
+        };
+        tail.resize(body_size - size as usize, 0);
+        self.write_data_bytes(tail).await?;
+        self.data_offset += (DataRecordHeader::size() + body_size) as u64;
+
+        match read_err {
+            Some(e) => Err(ErrorKind::InvalidArgument(CustomError::new(e))),
+            None => Ok(()),
+        }
+    }
+
+    /// write_data_bytes write raw bytes to data file
+    async fn write_data_bytes(&mut self, bs: Vec<u8>) -> Result<(), ErrorKind> {
+        match self._current_data_writer.write(bs).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(ErrorKind::IOError(CustomError::new(err.to_string()))),
+        }
     }
 }
 
 /// BytestackOpendalWriter is tool for writing the bytestack
+/// # Note
+/// put only takes `&self`, so one writer can be shared (e.g. in an `Arc`) across tokio tasks.
+/// Puts are spread over `WriterOptions::concurrency` slots, every slot fills its own stack, so one
+/// slow upload only blocks the slot it happens on.
 pub struct BytestackOpendalWriter {
-    controller_cli: ControllerClient<Channel>,
+    stack_id_allocator: Option<Arc<dyn StackIdAllocator>>,
     operator: Operator,
     prefix: String,
-    total_size: usize,
-    inner_writer: Mutex<Option<InnerWriter>>,
+    options: WriterOptions,
+    next_slot: AtomicUsize,
+    inner_writers: Vec<Mutex<Option<InnerWriter>>>,
+    records: AtomicU64,
+    deduplicated_records: AtomicU64,
+    saved_bytes: AtomicU64,
+    closed: AtomicBool,
 }
 
 impl BytestackOpendalWriter {
-    /// new TODO doc
+    /// new create a BytestackOpendalWriter which fills and rolls over stacks by options.
+    /// New stacks get stack_id from WriterOptions::stack_id_allocator, or from controller if it is None.
     pub fn new(
         operator: Operator,
         prefix: String,
-        controller_cli: ControllerClient<Channel>,
+        controller_cli: Option<ControllerClient<Channel>>,
+        options: WriterOptions,
     ) -> Self {
+        let mut inner_writers = Vec::with_capacity(options.concurrency);
+        inner_writers.resize_with(options.concurrency.max(1), || Mutex::new(None));
+        let stack_id_allocator = match (&options.stack_id_allocator, controller_cli) {
+            (Some(allocator), _) => Some(allocator.clone()),
+            (None, Some(controller_cli)) => {
+                Some(Arc::new(ControllerAllocator::new(controller_cli)) as Arc<dyn StackIdAllocator>)
+            }
+            (None, None) => None,
+        };
         BytestackOpendalWriter {
-            controller_cli,
+            stack_id_allocator,
             operator,
             prefix,
-            total_size: 0,
-            inner_writer: Mutex::<Option<InnerWriter>>::new(None),
+            options,
+            next_slot: AtomicUsize::new(0),
+            inner_writers,
+            records: AtomicU64::new(0),
+            deduplicated_records: AtomicU64::new(0),
+            saved_bytes: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// lock_slot picks a slot in round-robin order, an idle slot is preferred so that
+    /// puts are pipelined over all open stacks.
+    async fn lock_slot(&self) -> MutexGuard<'_, Option<InnerWriter>> {
+        let slots = self.inner_writers.len();
+        let start = self.next_slot.fetch_add(1, Ordering::Relaxed) % slots;
+        for i in 0..slots {
+            if let Ok(guard) = self.inner_writers[(start + i) % slots].try_lock() {
+                return guard;
+            }
         }
+        self.inner_writers[start].lock().await
     }
 
     /// put puts data, filename and meta_info to server.
+    /// data of u32::MAX bytes or more is saved as a large record, see check_size.
+    /// CloseError is returned after the writer is closed.
     pub async fn put(
-        &mut self,
+        &self,
         buf: Vec<u8>,
         filename: String,
         meta: Option<Vec<u8>>,
     ) -> Result<String, ErrorKind> {
-        let data_size = buf.len();
-        let full = self.total_size + data_size > _MAX_DATA_BYTES;
-        let mut inner_writer = self.inner_writer.lock().unwrap();
+        if self.check_size(buf.len() as u64)? {
+            return self
+                .put_large(&buf[..], buf.len() as u64, filename, meta)
+                .await;
+        }
+        // compress before locking a slot so that other puts are not blocked.
+        let compressed = match self.options.compression.choose(&filename, buf.len()) {
+            Compression::None => None,
+            compression => match compress::compress(compression, &buf) {
+                Ok(compressed) if compressed.len() + 4 < buf.len() => {
+                    Some((compression, compressed))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    return Err(ErrorKind::InvalidArgument(CustomError::new(e)));
+                }
+            },
+        };
+        let data_size = match &compressed {
+            Some((_, compressed)) => compressed.len() + 4,
+            None => buf.len(),
+        };
+        let data_size = match &self.options.encryption {
+            Some(_) => data_size + _ENCRYPTED_RECORD_OVERHEAD as usize,
+            None => data_size,
+        };
+        let mut inner_writer = self.lock_slot().await;
+        let mut writer = self
+            .take_writer(&mut inner_writer, self.stored_size(data_size, 0))
+            .await?;
+        let res = writer.write(buf, compressed, filename, meta).await;
+        self.release(&mut inner_writer, writer, &res).await;
+        let (id, saved_bytes) = res?;
+        self.records.fetch_add(1, Ordering::Relaxed);
+        if saved_bytes > 0 {
+            self.deduplicated_records.fetch_add(1, Ordering::Relaxed);
+            self.saved_bytes.fetch_add(saved_bytes, Ordering::Relaxed);
+        }
+        Ok(id)
+    }
+
+    /// stats return a snapshot of what this writer has written.
+    pub fn stats(&self) -> WriterStats {
+        WriterStats {
+            records: self.records.load(Ordering::Relaxed),
+            deduplicated_records: self.deduplicated_records.load(Ordering::Relaxed),
+            saved_bytes: self.saved_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// put_reader puts data read from reader, filename and meta_info to server.
+    /// size must be declared up front and exactly size bytes are read, so that large data
+    /// is streamed into data file without holding it in memory.
+    /// A record is encrypted as a whole, so data is read into memory and put if encryption is enabled.
+    /// If reader ends early InvalidArgument is returned and the current stack is sealed, since the
+    /// unfinished record is left in its data file, the next put opens a new stack.
+    pub async fn put_reader<R>(
+        &self,
+        mut reader: R,
+        size: u64,
+        filename: String,
+        meta: Option<Vec<u8>>,
+    ) -> Result<String, ErrorKind>
+    where
+        R: AsyncRead + Unpin,
+    {
+        if self.check_size(size)? {
+            return self.put_large(reader, size, filename, meta).await;
+        }
+        if self.options.encryption.is_some() {
+            let mut buf = Vec::with_capacity(size as usize);
+            match (&mut reader).take(size).read_to_end(&mut buf).await {
+                Ok(n) if n as u64 == size => {}
+                Ok(n) => {
+                    return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                        "reader ends after {} bytes, expected {}",
+                        n, size
+                    ))));
+                }
+                Err(e) => {
+                    return Err(ErrorKind::InvalidArgument(CustomError::new(e.to_string())));
+                }
+            }
+            return self.put(buf, filename, meta).await;
+        }
+        let mut inner_writer = self.lock_slot().await;
+        let mut writer = self
+            .take_writer(
+                &mut inner_writer,
+                self.stored_size(size as usize, DATA_RECORD_FLAG_CRC_TRAILER),
+            )
+            .await?;
+        let res = writer
+            .write_stream(reader, size as u32, filename, meta)
+            .await;
+        self.release_stream(&mut inner_writer, writer, &res).await;
+        if res.is_ok() {
+            self.records.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// check_size check that a record of size bytes can be written, return true if it has u32::MAX
+    /// bytes or more and should be saved as a large record, which needs FormatVersion::V2 and can
+    /// not be encrypted. Such record is rejected with InvalidArgument otherwise.
+    /// A record of exactly u32::MAX bytes is large too, since LARGE_RECORD_SIZE marks large records.
+    fn check_size(&self, size: u64) -> Result<bool, ErrorKind> {
+        if self.options.encryption.is_some() && size + _ENCRYPTED_RECORD_OVERHEAD > u32::MAX as u64
+        {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "size {} exceeds the max size of an encrypted record",
+                size
+            ))));
+        }
+        if size < LARGE_RECORD_SIZE as u64 {
+            return Ok(false);
+        }
+        if self.options.format_version == FormatVersion::V1 {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "size {} exceeds the max size of a record, large records need format version 2",
+                size
+            ))));
+        }
+        Ok(true)
+    }
+
+    /// put_large puts size bytes read from reader as a large record, it is never compressed or deduplicated.
+    async fn put_large<R>(
+        &self,
+        reader: R,
+        size: u64,
+        filename: String,
+        meta: Option<Vec<u8>>,
+    ) -> Result<String, ErrorKind>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut inner_writer = self.lock_slot().await;
+        let mut writer = self.take_writer(&mut inner_writer, size as usize).await?;
+        let res = writer.write_large(reader, size, filename, meta).await;
+        self.release_stream(&mut inner_writer, writer, &res).await;
+        if res.is_ok() {
+            self.records.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// release put writer back into slot after a write, unless the write failed with IOError or
+    /// CorruptedStack which means a file of the stack is broken, the writer is aborted then and the
+    /// next put opens a new stack.
+    async fn release<T>(
+        &self,
+        inner_writer: &mut Option<InnerWriter>,
+        writer: InnerWriter,
+        res: &Result<T, ErrorKind>,
+    ) {
+        match res {
+            Err(ErrorKind::IOError(_)) | Err(ErrorKind::CorruptedStack(_)) => writer.abort().await,
+            _ => {
+                inner_writer.replace(writer);
+            }
+        }
+    }
+
+    /// release_stream is release after write_stream or write_large. A reader ending early leaves an
+    /// unfinished record in data file which is never indexed, the stack is sealed then so that no
+    /// record is written after it, and recover_stack never has to scan past it after a crash.
+    async fn release_stream<T>(
+        &self,
+        inner_writer: &mut Option<InnerWriter>,
+        writer: InnerWriter,
+        res: &Result<T, ErrorKind>,
+    ) {
+        match res {
+            Err(ErrorKind::InvalidArgument(_)) => {
+                let stack_id = writer.stack_id;
+                if let Err(e) = self.seal(writer).await {
+                    warn!(target: "writer", "seal stack {} after a failed stream: {:?}", stack_id, e);
+                }
+            }
+            _ => self.release(inner_writer, writer, res).await,
+        }
+    }
+
+    /// stored_size return the bytes a record of data_size bytes written with flags takes after its
+    /// header, the checksum of stack included, should_rollover pads it to the alignment.
+    fn stored_size(&self, data_size: usize, flags: u16) -> usize {
+        DataRecordHeader::new_with_flags(
+            0,
+            data_size as u32,
+            0,
+            flags | self.options.checksum.flag(),
+        )
+        .stored_size()
+    }
+
+    /// take_writer take the writer out of slot, a new one is created if slot is empty
+    /// or writing data_size more bytes into current stack should roll over.
+    /// CloseError is returned after close, so that no put opens a new stack then.
+    async fn take_writer(
+        &self,
+        inner_writer: &mut Option<InnerWriter>,
+        data_size: usize,
+    ) -> Result<InnerWriter, ErrorKind> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ErrorKind::CloseError(CustomError::new(String::from(
+                "writer is closed",
+            ))));
+        }
         let writer = match inner_writer.take() {
             Some(writer) => {
-                if !full {
+                if !writer.should_rollover(&self.options, data_size) {
                     Some(writer)
                 } else {
-                    match writer.close().await {
-                        Ok(_) => {}
-                        Err(e) => return Err(e),
-                    };
+                    self.seal(writer).await?;
                     None
                 }
             }
             None => None,
         };
-        let mut writer = match writer {
-            Some(writer) => writer,
+        match writer {
+            Some(writer) => Ok(writer),
             None => {
-                let req = tonic::Request::new(());
-                let next_stack_id = match self.controller_cli.next_stack_id(req).await {
-                    Ok(resp) => resp.get_ref().stack_id,
-                    Err(e) => {
-                        return Err(ErrorKind::IOError(CustomError::new(e.to_string())));
-                    }
-                };
-                let inner_new_writer = self.create_new_writers(next_stack_id).await.unwrap();
-                self.total_size = 0;
-                inner_new_writer
+                let next_stack_id = self.next_stack_id().await?;
+                self.create_new_writers(next_stack_id).await
             }
-        };
+        }
+    }
 
-        let id = match writer.write(buf, filename, meta).await {
-            Ok(id) => id,
-            Err(e) => return Err(e),
-        };
-        self.total_size += data_size;
-        inner_writer.replace(writer);
-        Ok(id)
+    /// seal close the writer and report the sealed stack to WriterOptions::on_seal
+    async fn seal(&self, writer: InnerWriter) -> Result<(), ErrorKind> {
+        let sealed = writer.close().await?;
+        if let Some(on_seal) = &self.options.on_seal {
+            on_seal(sealed);
+        }
+        Ok(())
+    }
+
+    /// next_stack_id ask the allocator for a new stack_id
+    async fn next_stack_id(&self) -> Result<u64, ErrorKind> {
+        match &self.stack_id_allocator {
+            Some(allocator) => allocator.next_stack_id().await,
+            None => Err(ErrorKind::ControllerError(CustomError::new(String::from(
+                "no controller or stack_id allocator to allocate stack_id",
+            )))),
+        }
     }
 
     async fn create_new_writers(&self, stack_id: u64) -> Result<InnerWriter, ErrorKind> {
+        // options are checked before any file of the stack is created.
+        let version = self.options.format_version;
+        let meta_encoding = self.options.meta_encoding;
+        if meta_encoding == MetaEncoding::Bincode && version == FormatVersion::V1 {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(String::from(
+                "meta encoded with bincode needs format version 2",
+            ))));
+        }
+        let alignment = self.options.alignment;
+        if !valid_alignment(alignment) {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "alignment {} is not a power of two up to {}",
+                alignment, DEFAULT_ALIGNMENT_SIZE
+            ))));
+        }
+        if alignment != DEFAULT_ALIGNMENT_SIZE && version == FormatVersion::V1 {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "alignment {} needs format version 2",
+                alignment
+            ))));
+        }
+        let checksum = self.options.checksum;
+        if checksum != Checksum::Crc32c && version == FormatVersion::V1 {
+            return Err(ErrorKind::InvalidArgument(CustomError::new(format!(
+                "checksum {} needs format version 2",
+                checksum.name()
+            ))));
+        }
+        let crypto = match &self.options.encryption {
+            Some(encryption) => Some(encryption.stack_crypto(stack_id)?),
+            None => None,
+        };
         let index_file_path = utils::get_index_file_path(&self.prefix, stack_id);
         let mut index_writer = match self.operator.writer_with(&index_file_path).await {
             Ok(writer) => writer,
@@ -231,15 +923,41 @@ impl BytestackOpendalWriter {
             Err(e) => return Err(ErrorKind::IOError(CustomError::new(e.to_string()))),
         };
 
-        let ih = IndexMagicHeader::new(stack_id);
-        let mh = MetaMagicHeader::new(stack_id);
-        let dh = DataMagicHeader::new(stack_id);
-        let ih_bytes = bincode::serialize(&ih).unwrap();
-        let mut mh_bytes = serde_json::to_vec(&mh).unwrap();
-        mh_bytes.push(b'\n');
-        let mh_bytes_length = mh_bytes.len();
+        let ih = IndexMagicHeader::new_with_version(stack_id, version);
+        let dh = DataMagicHeader::new_with_version(stack_id, version);
+        let mut ih_bytes = bincode::serialize(&ih).unwrap();
         let mut dh_bytes = bincode::serialize(&dh).unwrap();
-        dh_bytes.resize(4096, 0);
+        if let Some(crypto) = &crypto {
+            dh_bytes.extend(bincode::serialize(&crypto.header()).unwrap());
+        }
+        let mh = match version {
+            FormatVersion::V1 => MetaMagicHeader::new(stack_id),
+            FormatVersion::V2 => {
+                let format = FormatHeader::new(match meta_encoding {
+                    MetaEncoding::Json => 0,
+                    MetaEncoding::Bincode => FORMAT_FLAG_META_BINCODE,
+                });
+                ih_bytes.extend(bincode::serialize(&format).unwrap());
+                dh_bytes.resize(DATA_FORMAT_HEADER_OFFSET, 0);
+                dh_bytes.extend(bincode::serialize(&format).unwrap());
+                dh_bytes.resize(DATA_LAYOUT_HEADER_OFFSET, 0);
+                dh_bytes.extend(bincode::serialize(&DataLayoutHeader::new(alignment)).unwrap());
+                dh_bytes.resize(DATA_CHECKSUM_HEADER_OFFSET, 0);
+                dh_bytes.extend(bincode::serialize(&DataChecksumHeader::new(checksum)).unwrap());
+                MetaMagicHeader::new_with_format(stack_id, format)
+            }
+        };
+        // the stack is sealed when it is closed, readers tell it from a V1 stack written before seals.
+        dh_bytes.resize(DATA_SEALED_HEADER_OFFSET, 0);
+        dh_bytes.extend(bincode::serialize(&DataSealedHeader::new()).unwrap());
+        let ih_bytes_length = ih_bytes.len();
+        let mh_bytes = mh.to_bytes();
+        let mh_bytes_length = mh_bytes.len();
+        dh_bytes.resize(DATA_HEADER_BLOCK_SIZE, 0);
+        let mut index_digest = utils::CASTAGNOLI.digest();
+        index_digest.update(&ih_bytes);
+        let mut meta_digest = utils::CASTAGNOLI.digest();
+        meta_digest.update(&mh_bytes);
 
         match index_writer.write(ih_bytes).await {
             Ok(_) => {}
@@ -255,22 +973,832 @@ impl BytestackOpendalWriter {
         }
 
         Ok(InnerWriter {
-            data_offset: 4096,
+            data_offset: DATA_HEADER_BLOCK_SIZE as u64,
             meta_offset: mh_bytes_length as u64,
+            index_offset: ih_bytes_length as u64,
+            index_digest,
+            meta_digest,
+            record_count: 0,
+            created_at: Instant::now(),
+            dedup: if self.options.dedup {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+            name_key: crypto.as_ref().and_then(|crypto| crypto.name_key()),
+            meta_encoding,
+            alignment,
+            checksum,
+            crypto,
             stack_id,
-            rng: rand::thread_rng(),
+            name_hashes: Vec::new(),
+            operator: self.operator.clone(),
+            names_file_path: utils::get_names_file_path(&self.prefix, stack_id),
+            rng: StdRng::from_entropy(),
             _current_index_writer: index_writer,
             _current_meta_writer: meta_writer,
             _current_data_writer: data_writer,
         })
     }
-    /// close flush and close all writer.
+    /// close flush and close all writer, puts fail with CloseError afterwards.
     pub async fn close(&self) -> Result<(), ErrorKind> {
-        if let Ok(mut mu) = self.inner_writer.lock() {
-            if let Some(writer) = mu.take() {
-                writer.close().await?
+        self.closed.store(true, Ordering::SeqCst);
+        for slot in &self.inner_writers {
+            if let Some(writer) = slot.lock().await.take() {
+                self.seal(writer).await?
             }
         }
         Ok(())
     }
 }
+
+#[test]
+fn test_check_size() {
+    let operator = Operator::new(opendal::services::Memory::default())
+        .unwrap()
+        .finish();
+    let writer =
+        BytestackOpendalWriter::new(operator.clone(), String::new(), None, Default::default());
+    // new stacks are V1 by default, they have no large records
+    assert!(writer.check_size(LARGE_RECORD_SIZE as u64 - 1).is_ok());
+    assert!(writer.check_size(LARGE_RECORD_SIZE as u64).is_err());
+    let writer = BytestackOpendalWriter::new(
+        operator,
+        String::new(),
+        None,
+        WriterOptions {
+            format_version: FormatVersion::V2,
+            ..Default::default()
+        },
+    );
+    assert!(!writer.check_size(LARGE_RECORD_SIZE as u64 - 1).unwrap());
+    // a record of exactly u32::MAX bytes would look like a large record in IndexRecord
+    assert!(writer.check_size(LARGE_RECORD_SIZE as u64).unwrap());
+    assert!(writer.check_size(LARGE_RECORD_SIZE as u64 + 1).unwrap());
+}
+
+/// test_put_u32_max_record writes 4 GiB to temp dir, run it with `cargo test -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn test_put_u32_max_record() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use futures::io::AsyncReadExt;
+
+    let (op, _dir) = temp_operator("u32_max");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            format_version: FormatVersion::V2,
+            ..Default::default()
+        },
+    );
+    let size = LARGE_RECORD_SIZE as u64;
+    let reader = futures::io::repeat(7).take(size);
+    let index_id = writer
+        .put_reader(reader, size, String::from("max"), None)
+        .await
+        .unwrap();
+    let small = writer
+        .put(vec![1; 100], String::from("small"), None)
+        .await
+        .unwrap();
+    writer.close().await.unwrap();
+
+    let reader = new_reader(&op);
+    let (ir, _) = reader.stat(&index_id).await.unwrap();
+    assert_eq!(ir.size_data, LARGE_RECORD_SIZE);
+    assert_eq!(
+        reader.fetch_range(&index_id, 0..16, true).await.unwrap(),
+        vec![7; 16]
+    );
+    assert_eq!(
+        reader
+            .fetch_range(&index_id, size - 16..size, true)
+            .await
+            .unwrap(),
+        vec![7; 16]
+    );
+    assert_eq!(reader.fetch(&small, true).await.unwrap(), vec![1; 100]);
+    // the next record is written after every chunk of the large one in the same stack.
+    assert_eq!(stack_of(&small), stack_of(&index_id));
+    let (small_ir, _) = reader.stat(&small).await.unwrap();
+    assert!(small_ir.offset_data > ir.offset_data + size);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_concurrent_puts() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use std::collections::HashSet;
+
+    let (op, _dir) = temp_operator("concurrent_puts");
+    let writer = Arc::new(new_writer(
+        &op,
+        WriterOptions {
+            concurrency: 4,
+            ..Default::default()
+        },
+    ));
+    let mut tasks = Vec::new();
+    for t in 0..8u8 {
+        let writer = writer.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut ids = Vec::new();
+            for i in 0..25u8 {
+                let data = vec![t, i, 7];
+                let id = writer
+                    .put(data.clone(), format!("{}-{}", t, i), None)
+                    .await
+                    .unwrap();
+                ids.push((id, data));
+            }
+            ids
+        }));
+    }
+    let mut ids = Vec::new();
+    for task in tasks {
+        ids.extend(task.await.unwrap());
+    }
+    writer.close().await.unwrap();
+    assert_eq!(writer.stats().records, 200);
+
+    // every slot fills its own stack.
+    let stacks: HashSet<u64> = ids.iter().map(|(id, _)| stack_of(id)).collect();
+    assert!(!stacks.is_empty() && stacks.len() <= 4, "{:?}", stacks);
+    let reader = new_reader(&op);
+    let mut listed = 0;
+    for stack_id in &stacks {
+        // puts sharing a slot never interleave, every record follows the one before it in
+        // both data and meta file.
+        let irs = reader.list_stack(*stack_id).await.unwrap();
+        assert!(irs.windows(2).all(|w| w[0].offset_data < w[1].offset_data
+            && w[0].offset_meta + w[0].size_meta as u64 == w[1].offset_meta));
+        listed += irs.len();
+    }
+    assert_eq!(listed, 200);
+    for (id, data) in ids {
+        assert_eq!(reader.fetch(&id, true).await.unwrap(), data);
+    }
+}
+
+#[tokio::test]
+async fn test_rollover() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use std::sync::Mutex as StdMutex;
+
+    let (op, dir) = temp_operator("rollover");
+    let sealed = Arc::new(StdMutex::new(Vec::new()));
+    let on_seal = {
+        let sealed = sealed.clone();
+        Arc::new(move |stack: SealedStack| sealed.lock().unwrap().push(stack))
+    };
+    // at most 3 records, or 2 records of 4 KiB after the header block.
+    for (max_records, max_data_bytes, per_stack) in [(Some(3), u64::MAX, 3), (None, 3 * 4096, 2)] {
+        sealed.lock().unwrap().clear();
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                max_records,
+                max_data_bytes,
+                on_seal: Some(on_seal.clone()),
+                ..Default::default()
+            },
+        );
+        let mut ids = Vec::new();
+        for i in 0..10u8 {
+            ids.push(writer.put(vec![i; 100], i.to_string(), None).await.unwrap());
+        }
+        writer.close().await.unwrap();
+
+        let sealed = sealed.lock().unwrap().clone();
+        let stacks = (10 + per_stack - 1) / per_stack;
+        assert_eq!(sealed.len(), stacks as usize);
+        let reader = new_reader(&op);
+        for (n, stack) in sealed.iter().enumerate() {
+            let expected = if n as u64 == stacks - 1 {
+                10 - per_stack * (stacks - 1)
+            } else {
+                per_stack
+            };
+            assert_eq!(stack.record_count, expected);
+            let irs = reader.list_stack(stack.stack_id).await.unwrap();
+            assert_eq!(irs.len() as u64, expected);
+            // sizes reported on seal are the sizes of sealed files.
+            let data_path = dir
+                .path()
+                .join(utils::get_data_file_path("", stack.stack_id));
+            let index_path = dir
+                .path()
+                .join(utils::get_index_file_path("", stack.stack_id));
+            let meta_path = dir
+                .path()
+                .join(utils::get_meta_file_path("", stack.stack_id));
+            assert_eq!(
+                std::fs::metadata(data_path).unwrap().len(),
+                stack.data_bytes
+            );
+            assert_eq!(
+                std::fs::metadata(index_path).unwrap().len(),
+                stack.index_bytes
+            );
+            assert_eq!(
+                std::fs::metadata(meta_path).unwrap().len(),
+                stack.meta_bytes
+            );
+        }
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(stack_of(id), sealed[i / per_stack as usize].stack_id);
+            assert_eq!(reader.fetch(id, true).await.unwrap(), vec![i as u8; 100]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_put_reader() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, _dir) = temp_operator("put_reader");
+    let writer = new_writer(&op, Default::default());
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    let a = writer
+        .put_reader(&data[..], data.len() as u64, String::from("a"), None)
+        .await
+        .unwrap();
+    // a reader ending early is rejected, nothing of it is reachable and its stack is sealed.
+    assert!(matches!(
+        writer
+            .put_reader(&data[..100], 200, String::from("short"), None)
+            .await,
+        Err(ErrorKind::InvalidArgument(_))
+    ));
+    let b = writer
+        .put_reader(&b"xyz"[..], 3, String::from("b"), Some(b"m".to_vec()))
+        .await
+        .unwrap();
+    assert_ne!(stack_of(&a), stack_of(&b));
+    writer.close().await.unwrap();
+
+    let reader = new_reader(&op);
+    assert_eq!(reader.fetch(&a, true).await.unwrap(), data);
+    let (_, mr, bs) = reader.fetch_with_meta(&b, true).await.unwrap();
+    assert_eq!(
+        (mr.filename.as_str(), &mr.extra[..], &bs[..]),
+        ("b", &b"m"[..], &b"xyz"[..])
+    );
+    assert_eq!(reader.list_stack(stack_of(&a)).await.unwrap().len(), 1);
+    assert_eq!(reader.list_stack(stack_of(&b)).await.unwrap().len(), 1);
+    assert!(matches!(
+        reader.stat_by_name("short").await,
+        Err(ErrorKind::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_put_after_close() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, dir) = temp_operator("put_after_close");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            format_version: FormatVersion::V2,
+            ..Default::default()
+        },
+    );
+    let id = writer
+        .put(vec![1; 10], String::from("a"), None)
+        .await
+        .unwrap();
+    writer.close().await.unwrap();
+    assert!(matches!(
+        writer.put(vec![2; 10], String::from("b"), None).await,
+        Err(ErrorKind::CloseError(_))
+    ));
+    assert!(matches!(
+        writer
+            .put_reader(&b"xyz"[..], 3, String::from("c"), None)
+            .await,
+        Err(ErrorKind::CloseError(_))
+    ));
+    // a large record is rejected before anything is read from reader.
+    assert!(matches!(
+        writer
+            .put_reader(&b""[..], LARGE_RECORD_SIZE as u64, String::from("d"), None)
+            .await,
+        Err(ErrorKind::CloseError(_))
+    ));
+    assert_eq!(writer.stats().records, 1);
+
+    // no stack is opened after close.
+    let stacks: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".idx"))
+        .collect();
+    assert_eq!(stacks.len(), 1, "{:?}", stacks);
+    let reader = new_reader(&op);
+    assert_eq!(reader.list_stack(stack_of(&id)).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_put_reader_rollover_with_checksum() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, dir) = temp_operator("put_reader_rollover");
+    // room for two blocks of records, the second record fits a block only without its trailer.
+    let size = DEFAULT_ALIGNMENT_SIZE - DataRecordHeader::size() - 20;
+    let data = vec![7u8; size];
+    for streamed in [false, true] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version: FormatVersion::V2,
+                checksum: Checksum::Blake3,
+                max_data_bytes: (DATA_HEADER_BLOCK_SIZE + 2 * DEFAULT_ALIGNMENT_SIZE) as u64,
+                ..Default::default()
+            },
+        );
+        let a = writer
+            .put(vec![1; 100], String::from("a"), None)
+            .await
+            .unwrap();
+        let b = if streamed {
+            writer
+                .put_reader(&data[..], size as u64, String::from("b"), None)
+                .await
+                .unwrap()
+        } else {
+            writer
+                .put(data.clone(), String::from("b"), None)
+                .await
+                .unwrap()
+        };
+        writer.close().await.unwrap();
+        assert_ne!(stack_of(&a), stack_of(&b), "streamed: {}", streamed);
+
+        let reader = new_reader(&op);
+        assert_eq!(reader.fetch(&b, true).await.unwrap(), data);
+        let data_path = dir.path().join(utils::get_data_file_path("", stack_of(&a)));
+        assert_eq!(
+            std::fs::metadata(data_path).unwrap().len(),
+            (DATA_HEADER_BLOCK_SIZE + DEFAULT_ALIGNMENT_SIZE + DATA_SEAL_BLOCK_SIZE) as u64
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_recover_after_failed_put_reader() {
+    use super::bs_opendal_recovery::recover_stack;
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, _dir) = temp_operator("recover_after_failed_put_reader");
+    let writer = new_writer(&op, Default::default());
+    let a = writer
+        .put(vec![1; 100], String::from("a"), None)
+        .await
+        .unwrap();
+    assert!(writer
+        .put_reader(&[2; 100][..], 5000, String::from("short"), None)
+        .await
+        .is_err());
+    let mut ids = vec![];
+    for i in 0..3u8 {
+        ids.push(writer.put(vec![i; 100], i.to_string(), None).await.unwrap());
+    }
+    // the writer crashes, the stack after the failed put is never sealed.
+    drop(writer);
+    let crashed = stack_of(&ids[0]);
+    assert_ne!(stack_of(&a), crashed);
+    let reader = new_reader(&op);
+    assert!(matches!(
+        reader.list_stack(crashed).await,
+        Err(ErrorKind::UnsealedStack(_))
+    ));
+
+    // no valid record is behind the unfinished one, all of them are recovered.
+    let report = recover_stack(&op, "", crashed).await.unwrap();
+    assert_eq!((report.recovered_records, report.meta_placeholders), (3, 0));
+    for (i, id) in ids.iter().enumerate() {
+        let (_, mr, data) = reader.fetch_with_meta(id, true).await.unwrap();
+        assert_eq!((mr.filename, data), (i.to_string(), vec![i as u8; 100]));
+    }
+    assert_eq!(reader.list_stack(stack_of(&a)).await.unwrap().len(), 1);
+    assert_eq!(reader.fetch(&a, true).await.unwrap(), vec![1; 100]);
+}
+
+#[tokio::test]
+async fn test_dedup() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, dir) = temp_operator("dedup");
+    let writer = new_writer(
+        &op,
+        WriterOptions {
+            dedup: true,
+            ..Default::default()
+        },
+    );
+    let big = vec![3; 10000];
+    let mut ids = vec![];
+    for (i, data) in [
+        big.clone(),
+        vec![1; 10],
+        big.clone(),
+        vec![1; 10],
+        big.clone(),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        ids.push((
+            writer.put(data.clone(), i.to_string(), None).await.unwrap(),
+            data,
+        ));
+    }
+    writer.close().await.unwrap();
+
+    // copies of big are links, a small record is written again since a link saves nothing.
+    let stats = writer.stats();
+    assert_eq!(stats.records, 5);
+    assert_eq!(stats.deduplicated_records, 2);
+    assert_eq!(stats.saved_bytes, 2 * 2 * 4096);
+    let stack_id = stack_of(&ids[0].0);
+    let data_path = dir.path().join(utils::get_data_file_path("", stack_id));
+    assert_eq!(
+        std::fs::metadata(&data_path).unwrap().len(),
+        4096 + 3 * 4096 + 4 * 4096 + 4096
+    );
+    let reader = new_reader(&op);
+    for (id, data) in &ids {
+        assert_eq!(&reader.fetch(id, true).await.unwrap(), data);
+        assert_eq!(
+            &reader.fetch_range(id, 5..8, true).await.unwrap(),
+            &data[5..8]
+        );
+    }
+    // the second and third copy of big are saved as links.
+    let bs = std::fs::read(data_path).unwrap();
+    let links: Vec<bool> = ids
+        .iter()
+        .map(|(id, _)| {
+            let offset = utils::parse_index_id(id).unwrap().offset_data as usize;
+            let drh: DataRecordHeader =
+                bincode::deserialize(&bs[offset..offset + DataRecordHeader::size()]).unwrap();
+            drh.is_link()
+        })
+        .collect();
+    assert_eq!(links, [false, false, true, false, true]);
+}
+
+#[tokio::test]
+async fn test_compression() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use super::CompressionPolicy;
+
+    let (op, dir) = temp_operator("compression");
+    let text: Vec<u8> = (0..20000u32)
+        .flat_map(|i| format!("{{\"id\":{}}}", i % 7).into_bytes())
+        .collect();
+    let mut noise = vec![0; 20000];
+    StdRng::seed_from_u64(7).fill(&mut noise[..]);
+    for compression in [Compression::Zstd, Compression::Lz4] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                compression: CompressionPolicy {
+                    compression,
+                    min_size: 100,
+                    content_types: Some(vec![String::from("application/json")]),
+                },
+                ..Default::default()
+            },
+        );
+        // only a.json is compressed, others are not allowed by content type, too small, or do not
+        // get smaller.
+        let puts = [
+            (&text[..], "a.json"),
+            (&text[..], "a.jpg"),
+            (&text[..50], "b.json"),
+            (&noise[..], "c.json"),
+        ];
+        let mut ids = vec![];
+        for (data, filename) in puts {
+            let id = writer
+                .put(data.to_vec(), filename.to_string(), None)
+                .await
+                .unwrap();
+            ids.push((id, data));
+        }
+        writer.close().await.unwrap();
+
+        let reader = new_reader(&op);
+        let stack_id = stack_of(&ids[0].0);
+        let irs = reader.list_stack(stack_id).await.unwrap();
+        let data_path = dir.path().join(utils::get_data_file_path("", stack_id));
+        let data_end = std::fs::metadata(&data_path).unwrap().len() - 4096;
+        let mut offsets: Vec<u64> = irs.iter().map(|ir| ir.offset_data).collect();
+        offsets.push(data_end);
+        let blocks: Vec<u64> = offsets.windows(2).map(|w| (w[1] - w[0]) / 4096).collect();
+        assert_eq!(blocks[1], (text.len() as u64 + 20) / 4096 + 1);
+        assert!(blocks[0] * 4 < blocks[1], "{:?} {:?}", compression, blocks);
+        assert_eq!(blocks[2], 1);
+        assert_eq!(blocks[3], (noise.len() as u64 + 20) / 4096 + 1);
+        for (id, data) in &ids {
+            assert_eq!(&reader.fetch(id, true).await.unwrap(), data);
+            assert_eq!(
+                reader.fetch_range(id, 10..20, true).await.unwrap(),
+                data[10..20].to_vec()
+            );
+        }
+        // the codec is told by the header of the record, so it is decoded by any reader.
+        let bs = std::fs::read(&data_path).unwrap();
+        let codecs: Vec<Compression> = irs
+            .iter()
+            .map(|ir| {
+                let offset = ir.offset_data as usize;
+                let drh: DataRecordHeader =
+                    bincode::deserialize(&bs[offset..offset + DataRecordHeader::size()]).unwrap();
+                drh.compression()
+            })
+            .collect();
+        assert_eq!(
+            codecs,
+            [
+                compression,
+                Compression::None,
+                Compression::None,
+                Compression::None
+            ]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_mixed_format_versions() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, dir) = temp_operator("mixed_format_versions");
+    let mut ids = vec![];
+    for format_version in [FormatVersion::V1, FormatVersion::V2] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version,
+                ..Default::default()
+            },
+        );
+        for i in 0..3u8 {
+            let id = writer
+                .put(vec![i; 1000], format!("{:?}{}", format_version, i), None)
+                .await
+                .unwrap();
+            ids.push((id, format_version, i));
+        }
+        writer.close().await.unwrap();
+    }
+
+    // stacks of both versions are read and verified by the same reader.
+    let reader = new_reader(&op);
+    for (id, format_version, i) in &ids {
+        let (_, mr, data) = reader.fetch_with_meta(id, true).await.unwrap();
+        assert_eq!(mr.filename, format!("{:?}{}", format_version, i));
+        assert_eq!(data, vec![*i; 1000]);
+    }
+    let v1 = stack_of(&ids[0].0);
+    let v2 = stack_of(&ids[3].0);
+    assert_ne!(v1, v2);
+    for (stack_id, format_version) in [(v1, FormatVersion::V1), (v2, FormatVersion::V2)] {
+        assert_eq!(reader.list_stack(stack_id).await.unwrap().len(), 3);
+        // every stack keeps the version of the writer which created it.
+        let bs = std::fs::read(dir.path().join(utils::get_data_file_path("", stack_id))).unwrap();
+        let dh: DataMagicHeader = bincode::deserialize(&bs[..DataMagicHeader::size()]).unwrap();
+        assert_eq!(dh.version(), format_version);
+    }
+
+    // a V1 writer rejects options only V2 stacks support.
+    let v2_only = [
+        WriterOptions {
+            meta_encoding: MetaEncoding::Bincode,
+            ..Default::default()
+        },
+        WriterOptions {
+            alignment: 512,
+            ..Default::default()
+        },
+        WriterOptions {
+            checksum: Checksum::Xxh3,
+            ..Default::default()
+        },
+    ];
+    let files = std::fs::read_dir(dir.path()).unwrap().count();
+    for options in v2_only {
+        let writer = new_writer(&op, options);
+        let res = writer.put(vec![1; 10], String::from("a"), None).await;
+        assert!(
+            matches!(res, Err(ErrorKind::InvalidArgument(_))),
+            "{:?}",
+            res
+        );
+    }
+    assert!(matches!(
+        new_writer(&op, Default::default()).check_size(u32::MAX as u64),
+        Err(ErrorKind::InvalidArgument(_))
+    ));
+    // no file is left by a rejected stack.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files);
+}
+
+#[tokio::test]
+async fn test_bincode_meta() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use futures::StreamExt;
+
+    let (op, dir) = temp_operator("bincode_meta");
+    let mut meta_sizes = vec![];
+    for meta_encoding in [MetaEncoding::Json, MetaEncoding::Bincode] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version: FormatVersion::V2,
+                meta_encoding,
+                ..Default::default()
+            },
+        );
+        let mut ids = vec![];
+        for i in 0..20u8 {
+            let extra = if i % 3 == 0 {
+                None
+            } else {
+                Some(vec![i; i as usize * 10])
+            };
+            let filename = format!("{:?}/{}", meta_encoding, i);
+            let id = writer
+                .put(vec![i; 100], filename.clone(), extra.clone())
+                .await
+                .unwrap();
+            ids.push((id, filename, extra.unwrap_or_default()));
+        }
+        writer.close().await.unwrap();
+
+        let reader = new_reader(&op);
+        let stack_id = stack_of(&ids[0].0);
+        for (id, filename, extra) in &ids {
+            let (_, mr) = reader.stat(id).await.unwrap();
+            assert_eq!((&mr.filename, &mr.extra), (filename, extra));
+            let (found, _, _) = reader.stat_by_name(filename).await.unwrap();
+            assert_eq!(&found, id);
+        }
+        let mut iter = reader.list_stack_al_iter(stack_id).await.unwrap();
+        let mut listed = vec![];
+        while let Some(res) = iter.next().await {
+            let (_, mr) = res.unwrap();
+            listed.push((mr.filename, mr.extra));
+        }
+        let expected: Vec<(String, Vec<u8>)> =
+            ids.iter().map(|(_, f, e)| (f.clone(), e.clone())).collect();
+        assert_eq!(listed, expected);
+        let meta_path = dir.path().join(utils::get_meta_file_path("", stack_id));
+        let meta = std::fs::read(meta_path).unwrap();
+        // field names are saved only in json.
+        assert_eq!(
+            meta.windows(10).any(|w| w == b"\"filename\""),
+            meta_encoding == MetaEncoding::Json
+        );
+        meta_sizes.push(meta.len() as u64);
+    }
+    // extra is an array of numbers in json, raw bytes in bincode.
+    assert!(meta_sizes[1] < meta_sizes[0], "{:?}", meta_sizes);
+}
+
+#[tokio::test]
+async fn test_alignment() {
+    use super::bs_opendal_recovery::repair_stack;
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+
+    let (op, dir) = temp_operator("alignment");
+    let mut data_sizes = vec![];
+    for alignment in [1, 8, 512, 4096] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version: FormatVersion::V2,
+                alignment,
+                ..Default::default()
+            },
+        );
+        let mut ids = vec![];
+        for i in 0..10u8 {
+            let data = vec![i; 33 * i as usize + 1];
+            ids.push((
+                writer.put(data.clone(), i.to_string(), None).await.unwrap(),
+                data,
+            ));
+        }
+        writer.close().await.unwrap();
+
+        let reader = new_reader(&op);
+        let stack_id = stack_of(&ids[0].0);
+        let irs = reader.list_stack(stack_id).await.unwrap();
+        assert!(irs.iter().all(|ir| ir.offset_data % alignment as u64 == 0));
+        for (id, data) in &ids {
+            assert_eq!(&reader.fetch(id, true).await.unwrap(), data);
+        }
+        // every record starts at the first boundary after the one before it.
+        let record_size = |size: u32| {
+            (DataRecordHeader::size() + padding_data_size(size as usize, alignment)) as u64
+        };
+        assert!(irs
+            .windows(2)
+            .all(|w| w[1].offset_data == w[0].offset_data + record_size(w[0].size_data)));
+        // records are found again by scanning data file at the alignment.
+        let report = repair_stack(&op, "", stack_id, None, true).await.unwrap();
+        assert_eq!(report.recovered_records, 10);
+        assert!(!report.index_changed && !report.meta_changed);
+        let data_path = dir.path().join(utils::get_data_file_path("", stack_id));
+        data_sizes.push(std::fs::metadata(data_path).unwrap().len());
+    }
+    assert!(
+        data_sizes.windows(2).all(|w| w[0] <= w[1]),
+        "{:?}",
+        data_sizes
+    );
+
+    for alignment in [0, 3, 8192] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version: FormatVersion::V2,
+                alignment,
+                ..Default::default()
+            },
+        );
+        let res = writer.put(vec![1; 10], String::from("a"), None).await;
+        assert!(
+            matches!(res, Err(ErrorKind::InvalidArgument(_))),
+            "{:?}",
+            res
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_checksums() {
+    use super::bs_opendal_testing::{new_reader, new_writer, stack_of, temp_operator};
+    use super::VerifyLevel;
+
+    let (op, dir) = temp_operator("checksums");
+    for checksum in [Checksum::Crc32c, Checksum::Xxh3, Checksum::Blake3] {
+        let writer = new_writer(
+            &op,
+            WriterOptions {
+                format_version: FormatVersion::V2,
+                checksum,
+                ..Default::default()
+            },
+        );
+        let mut ids = vec![];
+        for i in 0..3u8 {
+            ids.push(
+                writer
+                    .put(vec![i; 5000], i.to_string(), None)
+                    .await
+                    .unwrap(),
+            );
+        }
+        writer.close().await.unwrap();
+
+        let reader = new_reader(&op);
+        let stack_id = stack_of(&ids[0]);
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(reader.fetch(id, true).await.unwrap(), vec![i as u8; 5000]);
+        }
+        let report = reader
+            .verify_stack(stack_id, VerifyLevel::Full)
+            .await
+            .unwrap();
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        assert_eq!(report.checksum, checksum);
+
+        // a flipped byte of data is caught by the checksum only.
+        let data_path = dir.path().join(utils::get_data_file_path("", stack_id));
+        let mut bs = std::fs::read(&data_path).unwrap();
+        let offset = utils::parse_index_id(&ids[1]).unwrap().offset_data;
+        bs[offset as usize + 1000] ^= 0x01;
+        std::fs::write(&data_path, bs).unwrap();
+        assert!(matches!(
+            reader.fetch(&ids[1], true).await,
+            Err(ErrorKind::IOError(_))
+        ));
+        assert_eq!(reader.fetch(&ids[1], false).await.unwrap().len(), 5000);
+        let report = reader
+            .verify_stack(stack_id, VerifyLevel::Records)
+            .await
+            .unwrap();
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        let report = reader
+            .verify_stack(stack_id, VerifyLevel::Full)
+            .await
+            .unwrap();
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert_eq!(report.problems[0].offset, offset);
+    }
+}
@@ -0,0 +1,155 @@
+//! bs_opendal_writer_options provides options for BytestackOpendalWriter
+
+use super::{EncryptionOptions, StackIdAllocator};
+pub use crate::types::data::{Checksum, Compression, DEFAULT_ALIGNMENT_SIZE};
+pub use crate::types::{FormatVersion, MetaEncoding};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// DEFAULT_MAX_DATA_BYTES is the default size of data file before rolling over to a new stack.
+pub const DEFAULT_MAX_DATA_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+
+/// SealedStack is reported every time a writer seals a stack.
+#[derive(Debug, Clone)]
+pub struct SealedStack {
+    /// stack_id of the sealed stack
+    pub stack_id: u64,
+    /// record_count is how many records are written into the stack
+    pub record_count: u64,
+    /// data_bytes is the full size of data file
+    pub data_bytes: u64,
+    /// meta_bytes is the full size of meta file
+    pub meta_bytes: u64,
+    /// index_bytes is the full size of index file
+    pub index_bytes: u64,
+}
+
+/// SealCallback is called with SealedStack right after a stack is sealed.
+/// A `std::sync::mpsc::Sender` or `tokio::sync::mpsc::UnboundedSender` can be moved into
+/// the callback if the event should be handled somewhere else.
+pub type SealCallback = Arc<dyn Fn(SealedStack) + Send + Sync>;
+
+/// CompressionPolicy decides which records are compressed by BytestackOpendalWriter::put.
+/// Compressed data is kept only if it is smaller than original data, put_reader never compresses.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionPolicy {
+    /// compression is the codec used, Compression::None disables compression
+    pub compression: Compression,
+    /// min_size is the smallest data to compress, small data seldom saves a 4K block
+    pub min_size: usize,
+    /// content_types is an allow-list of content types guessed from filename extension, like
+    /// `application/json` or `text/*`, None means every content type is allowed.
+    pub content_types: Option<Vec<String>>,
+}
+
+impl CompressionPolicy {
+    /// choose return the codec for a record of size bytes named filename.
+    pub fn choose(&self, filename: &str, size: usize) -> Compression {
+        if self.compression == Compression::None || size < self.min_size {
+            return Compression::None;
+        }
+        let content_types = match &self.content_types {
+            Some(content_types) => content_types,
+            None => return self.compression,
+        };
+        let guessed = match mime_guess::from_path(Path::new(filename)).first_raw() {
+            Some(guessed) => guessed,
+            None => return Compression::None,
+        };
+        let allowed = content_types.iter().any(|t| match t.strip_suffix("/*") {
+            Some(top) => guessed.split('/').next() == Some(top),
+            None => t == guessed,
+        });
+        if allowed {
+            self.compression
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// WriterOptions controls how BytestackOpendalWriter fills and rolls over stacks.
+/// A stack is sealed and a new one is created once any of the limits is reached.
+pub struct WriterOptions {
+    /// concurrency is how many stacks are filled in parallel
+    pub concurrency: usize,
+    /// max_data_bytes limits the size of data file
+    pub max_data_bytes: u64,
+    /// max_records limits how many records can be written into one stack
+    pub max_records: Option<u64>,
+    /// max_meta_bytes limits the size of meta file
+    pub max_meta_bytes: Option<u64>,
+    /// max_age limits how long a stack stays open, it is checked on every put,
+    /// so an idle stack is sealed by the next put or close.
+    pub max_age: Option<Duration>,
+    /// on_seal is called every time a stack is sealed
+    pub on_seal: Option<SealCallback>,
+    /// dedup enables content-hash deduplication inside every stack: data identical to one already
+    /// written into current stack is saved as a small link record pointing to it.
+    /// Only put is deduplicated, put_reader always writes data.
+    pub dedup: bool,
+    /// compression decides which records are compressed
+    pub compression: CompressionPolicy,
+    /// encryption encrypts every record of new stacks, None means stacks are not encrypted
+    pub encryption: Option<EncryptionOptions>,
+    /// stack_id_allocator gives new stacks stack_id, None means stack_id is asked from controller
+    pub stack_id_allocator: Option<Arc<dyn StackIdAllocator>>,
+    /// format_version is the on-disk format of new stacks, V1 by default keeps stacks readable by
+    /// readers released before V2. Set it to FormatVersion::V2 to use the options below.
+    pub format_version: FormatVersion,
+    /// meta_encoding is how MetaRecords of new stacks are encoded, MetaEncoding::Bincode needs
+    /// FormatVersion::V2.
+    pub meta_encoding: MetaEncoding,
+    /// alignment is what every record of new stacks is padded to, a power of two up to
+    /// DEFAULT_ALIGNMENT_SIZE like 1, 8 or 512 wastes less space on small records.
+    /// Alignment other than DEFAULT_ALIGNMENT_SIZE needs FormatVersion::V2.
+    pub alignment: usize,
+    /// checksum is the algorithm records of new stacks are checksummed by, Checksum::Xxh3 is faster
+    /// and Checksum::Blake3 tells tampered data. Checksum other than Checksum::Crc32c needs
+    /// FormatVersion::V2.
+    pub checksum: Checksum,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        WriterOptions {
+            concurrency: 1,
+            max_data_bytes: DEFAULT_MAX_DATA_BYTES,
+            max_records: None,
+            max_meta_bytes: None,
+            max_age: None,
+            on_seal: None,
+            dedup: false,
+            compression: CompressionPolicy::default(),
+            encryption: None,
+            stack_id_allocator: None,
+            format_version: FormatVersion::default(),
+            meta_encoding: MetaEncoding::default(),
+            alignment: DEFAULT_ALIGNMENT_SIZE,
+            checksum: Checksum::default(),
+        }
+    }
+}
+
+impl fmt::Debug for WriterOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriterOptions")
+            .field("concurrency", &self.concurrency)
+            .field("max_data_bytes", &self.max_data_bytes)
+            .field("max_records", &self.max_records)
+            .field("max_meta_bytes", &self.max_meta_bytes)
+            .field("max_age", &self.max_age)
+            .field("on_seal", &self.on_seal.is_some())
+            .field("dedup", &self.dedup)
+            .field("compression", &self.compression)
+            .field("encryption", &self.encryption)
+            .field("stack_id_allocator", &self.stack_id_allocator.is_some())
+            .field("format_version", &self.format_version)
+            .field("meta_encoding", &self.meta_encoding)
+            .field("alignment", &self.alignment)
+            .field("checksum", &self.checksum)
+            .finish()
+    }
+}
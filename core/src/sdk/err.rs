@@ -1,5 +1,5 @@
 //! err contains all errors given by sdk mod.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// CustomError: I’m not quite sure what rust’s error handling should do, so I make them a String for laziness.
 pub struct CustomError {
     pub origin: String,
@@ -12,10 +12,18 @@ impl CustomError {
 
 /// ErrorKind defined some simple error type.
 /// TODO(dashjay): Makes the error more specific
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ErrorKind {
     IOError(CustomError),
     ControllerError(CustomError),
     CloseError(CustomError),
     InvalidArgument(CustomError),
+    /// UnsealedStack means the stack is still being written or its writer crashed before close.
+    UnsealedStack(CustomError),
+    /// CorruptedStack means the stack is truncated or its content mismatch the seal.
+    CorruptedStack(CustomError),
+    /// AuthenticationFailed means an encrypted record or meta is tampered or decrypted with a wrong key.
+    AuthenticationFailed(CustomError),
+    /// NotFound means the record does not exist or is deleted.
+    NotFound(CustomError),
 }
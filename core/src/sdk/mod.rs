@@ -2,8 +2,43 @@
 pub mod bs_opendal_reader;
 pub use bs_opendal_reader::BytestackOpendalReader;
 
+pub mod bs_opendal_reader_options;
+pub use bs_opendal_reader_options::ReaderOptions;
+
+pub mod bs_opendal_crypto;
+pub use bs_opendal_crypto::{Cipher, EncryptionOptions, Keyring};
+
+pub mod bs_opendal_record;
+
+pub mod bs_opendal_batch;
+
+pub mod bs_opendal_iterator;
+
+pub mod bs_opendal_shuffle;
+
+pub mod bs_opendal_index_cache;
+pub use bs_opendal_index_cache::{IndexCache, IndexCacheStats};
+
+pub mod bs_opendal_deletion;
+
+pub mod bs_opendal_names;
+
+pub mod bs_opendal_stack_id;
+pub use bs_opendal_stack_id::{
+    ControllerAllocator, LocalCounterAllocator, RandomAllocator, StackIdAllocator,
+};
+
 pub mod bs_opendal_writer;
-pub use bs_opendal_writer::BytestackOpendalWriter;
+pub use bs_opendal_writer::{BytestackOpendalWriter, WriterStats};
+
+pub mod bs_opendal_writer_options;
+pub use bs_opendal_writer_options::*;
+
+pub mod bs_opendal_recovery;
+pub use bs_opendal_recovery::{RecoveryReport, RepairReport};
+
+pub mod bs_opendal_verify;
+pub use bs_opendal_verify::{VerifyLevel, VerifyProblem, VerifyReport};
 
 pub mod bs_opendal;
 pub use bs_opendal::BytestackOpendalHandler as Handler;
@@ -12,3 +47,6 @@ pub mod bs_opendal_config;
 pub use bs_opendal_config::*;
 
 pub mod err;
+
+#[cfg(test)]
+mod bs_opendal_testing;
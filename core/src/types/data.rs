@@ -1,11 +1,28 @@
 //! data will provide all data struct about data file.
+use super::format::FormatVersion;
+use crate::utils;
 use bincode;
 use serde::{Deserialize, Serialize};
 
-const ALIGNMENT_SIZE: usize = 4096;
+/// DEFAULT_ALIGNMENT_SIZE is the alignment of records in V1 stacks and in V2 stacks without a
+/// DataLayoutHeader.
+pub const DEFAULT_ALIGNMENT_SIZE: usize = 4096;
+
+/// DATA_HEADER_BLOCK_SIZE is the size of the header block of every data file, the first record
+/// starts right after it.
+pub const DATA_HEADER_BLOCK_SIZE: usize = 4096;
+
+/// DATA_SEAL_BLOCK_SIZE is the size of the block holding StackSeal at the end of a sealed data file.
+/// The seal is always padded to 4 KiB whatever the alignment of the stack is, so a sealed data file
+/// has exactly seal.data_bytes + DATA_SEAL_BLOCK_SIZE bytes.
+pub const DATA_SEAL_BLOCK_SIZE: usize = 4096;
 
 /// _DATA_HEADER_MAGIC is a magic number respects to GIF file header, and identify this is a data file.
 const _DATA_HEADER_MAGIC: u64 = 47494638;
+/// _DATA_HEADER_MAGIC_V2 identify this is a V2 data file, a FormatHeader is at DATA_FORMAT_HEADER_OFFSET.
+const _DATA_HEADER_MAGIC_V2: u64 = 47494639;
+/// DATA_FORMAT_HEADER_OFFSET is where FormatHeader is in a V2 data file, right after DataEncryptionHeader.
+pub const DATA_FORMAT_HEADER_OFFSET: usize = 32;
 
 /// DataMagicHeader will be serialized with bincode and save to file header in every data file, which is used to
 /// identification this is an data file, this struct SHOULD NOT BE MODIFIED!!!
@@ -26,14 +43,35 @@ impl DataMagicHeader {
         }
     }
 
+    /// new_with_version return a DataMagicHeader of version by stack_id
+    pub fn new_with_version(stack_id: u64, version: FormatVersion) -> Self {
+        DataMagicHeader {
+            data_magic_number: match version {
+                FormatVersion::V1 => _DATA_HEADER_MAGIC,
+                FormatVersion::V2 => _DATA_HEADER_MAGIC_V2,
+            },
+            stack_id,
+        }
+    }
+
     /// size return the size of DataMagicHeader
     pub fn size() -> usize {
         16
     }
 
-    /// valid check if data_magic_number is _DATA_HEADER_MAGIC
+    /// valid check if data_magic_number is _DATA_HEADER_MAGIC or _DATA_HEADER_MAGIC_V2
     pub fn valid(&self) -> bool {
-        return self.data_magic_number == _DATA_HEADER_MAGIC;
+        self.data_magic_number == _DATA_HEADER_MAGIC
+            || self.data_magic_number == _DATA_HEADER_MAGIC_V2
+    }
+
+    /// version return the format version told by data_magic_number
+    pub fn version(&self) -> FormatVersion {
+        if self.data_magic_number == _DATA_HEADER_MAGIC_V2 {
+            FormatVersion::V2
+        } else {
+            FormatVersion::V1
+        }
     }
 }
 
@@ -41,17 +79,561 @@ impl DataMagicHeader {
 fn test_data_magic_header_size() {
     let temp = DataMagicHeader::new(0);
     assert!(DataMagicHeader::size() == bincode::serialized_size(&temp).unwrap() as usize);
+    let v2 = DataMagicHeader::new_with_version(0, FormatVersion::V2);
+    assert!(temp.valid() && v2.valid() && v2.version() == FormatVersion::V2);
+}
+
+/// _DATA_ENCRYPTION_HEADER_MAGIC is "ENCR" in little endian, and identify the stack is encrypted.
+const _DATA_ENCRYPTION_HEADER_MAGIC: u32 = 0x5243_4e45;
+/// DATA_ENCRYPTION_FLAG_META means filename and extra of MetaRecords are encrypted too.
+pub const DATA_ENCRYPTION_FLAG_META: u32 = 1;
+
+/// Cipher is the AEAD used to encrypt records of a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// Aes256Gcm is AES-256-GCM
+    Aes256Gcm,
+    /// ChaCha20Poly1305 is ChaCha20-Poly1305
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// id of cipher saved in DataEncryptionHeader
+    pub fn id(&self) -> u32 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    /// from_id return the cipher by id, None if it is unknown
+    pub fn from_id(id: u32) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => Err(format!("unknown cipher: {}", s)),
+        }
+    }
+}
+
+/// DataEncryptionHeader is saved right after DataMagicHeader in data file of an encrypted stack,
+/// a stack without it has zeros there.
+/// `| encryption_magic: u32 | cipher: u32 | key_id: u32 | flags: u32 | (16 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DataEncryptionHeader {
+    /// encryption_magic should always be _DATA_ENCRYPTION_HEADER_MAGIC
+    encryption_magic: u32,
+    /// cipher is the id of Cipher
+    pub cipher: u32,
+    /// key_id identify which key encrypts this stack, so keys can be rotated
+    pub key_id: u32,
+    /// flags like DATA_ENCRYPTION_FLAG_META
+    pub flags: u32,
+}
+
+impl DataEncryptionHeader {
+    /// new return a DataEncryptionHeader
+    pub fn new(cipher: Cipher, key_id: u32, flags: u32) -> Self {
+        DataEncryptionHeader {
+            encryption_magic: _DATA_ENCRYPTION_HEADER_MAGIC,
+            cipher: cipher.id(),
+            key_id,
+            flags,
+        }
+    }
+
+    /// size of DataEncryptionHeader is 16 now
+    pub fn size() -> usize {
+        16
+    }
+
+    /// valid check if encryption_magic is _DATA_ENCRYPTION_HEADER_MAGIC
+    pub fn valid(&self) -> bool {
+        self.encryption_magic == _DATA_ENCRYPTION_HEADER_MAGIC
+    }
+}
+
+/// _DATA_LAYOUT_HEADER_MAGIC is "LAYO" in little endian, and identify a DataLayoutHeader.
+const _DATA_LAYOUT_HEADER_MAGIC: u32 = 0x4f59_414c;
+/// DATA_LAYOUT_HEADER_OFFSET is where DataLayoutHeader is in a V2 data file, right after FormatHeader.
+pub const DATA_LAYOUT_HEADER_OFFSET: usize = 64;
+
+/// valid_alignment check if alignment can be used by a stack: a power of two no larger than
+/// DEFAULT_ALIGNMENT_SIZE, so the first record right after the 4K header block is always aligned.
+pub fn valid_alignment(alignment: usize) -> bool {
+    alignment.is_power_of_two() && alignment <= DEFAULT_ALIGNMENT_SIZE
+}
+
+/// DataLayoutHeader is saved at DATA_LAYOUT_HEADER_OFFSET in a V2 data file, it tells how records
+/// are laid out, zeros there mean DEFAULT_ALIGNMENT_SIZE.
+/// `| layout_magic: u32 | alignment: u32 | (8 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DataLayoutHeader {
+    /// layout_magic should always be _DATA_LAYOUT_HEADER_MAGIC
+    layout_magic: u32,
+    /// alignment of every record, header + data + padding is a multiple of it
+    pub alignment: u32,
+}
+
+impl DataLayoutHeader {
+    /// new return a DataLayoutHeader by alignment
+    pub fn new(alignment: usize) -> Self {
+        DataLayoutHeader {
+            layout_magic: _DATA_LAYOUT_HEADER_MAGIC,
+            alignment: alignment as u32,
+        }
+    }
+
+    /// size of DataLayoutHeader is 8 now
+    pub fn size() -> usize {
+        8
+    }
+
+    /// valid check if layout_magic is _DATA_LAYOUT_HEADER_MAGIC and alignment is usable
+    pub fn valid(&self) -> bool {
+        self.layout_magic == _DATA_LAYOUT_HEADER_MAGIC && valid_alignment(self.alignment as usize)
+    }
+
+    /// alignment_from_bytes return the alignment told by the bytes at DATA_LAYOUT_HEADER_OFFSET,
+    /// zeros mean DEFAULT_ALIGNMENT_SIZE, None if the header is broken.
+    pub fn alignment_from_bytes(data: &[u8]) -> Option<usize> {
+        assert!(data.len() == Self::size());
+        if data.iter().all(|b| *b == 0) {
+            return Some(DEFAULT_ALIGNMENT_SIZE);
+        }
+        match bincode::deserialize::<DataLayoutHeader>(data) {
+            Ok(dlh) if dlh.valid() => Some(dlh.alignment as usize),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_data_layout_header_size() {
+    let temp = DataLayoutHeader::new(8);
+    let bs = bincode::serialize(&temp).unwrap();
+    assert!(DataLayoutHeader::size() == bs.len());
+    assert_eq!(DataLayoutHeader::alignment_from_bytes(&bs), Some(8));
+    assert_eq!(DataLayoutHeader::alignment_from_bytes(&[0; 8]), Some(4096));
+    let broken = bincode::serialize(&DataLayoutHeader::new(100)).unwrap();
+    assert_eq!(DataLayoutHeader::alignment_from_bytes(&broken), None);
+    assert!(valid_alignment(1) && valid_alignment(512) && !valid_alignment(8192));
+}
+
+/// Checksum is the algorithm used to checksum records of a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Checksum {
+    /// Crc32c is CRC-32C (Castagnoli), saved in DataRecordHeader, the only one of V1 stacks
+    #[default]
+    Crc32c,
+    /// Xxh3 is 64 bits XXH3, faster than Crc32c on large data
+    Xxh3,
+    /// Blake3 is 256 bits BLAKE3, a cryptographic hash which tells tampered data
+    Blake3,
+}
+
+impl Checksum {
+    /// id of checksum saved in DataChecksumHeader
+    pub fn id(&self) -> u32 {
+        match self {
+            Checksum::Crc32c => 1,
+            Checksum::Xxh3 => 2,
+            Checksum::Blake3 => 3,
+        }
+    }
+
+    /// from_id return the checksum by id, None if it is unknown
+    pub fn from_id(id: u32) -> Option<Checksum> {
+        match id {
+            1 => Some(Checksum::Crc32c),
+            2 => Some(Checksum::Xxh3),
+            3 => Some(Checksum::Blake3),
+            _ => None,
+        }
+    }
+
+    /// size of a checksum in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Checksum::Crc32c => 4,
+            Checksum::Xxh3 => 8,
+            Checksum::Blake3 => 32,
+        }
+    }
+
+    /// flag return the record flag of this checksum, records checksummed by Crc32c have none.
+    pub fn flag(&self) -> u16 {
+        match self {
+            Checksum::Crc32c => 0,
+            Checksum::Xxh3 => DATA_RECORD_FLAG_XXH3,
+            Checksum::Blake3 => DATA_RECORD_FLAG_BLAKE3,
+        }
+    }
+
+    /// name of checksum, it is parsed back by from_str
+    pub fn name(&self) -> &'static str {
+        match self {
+            Checksum::Crc32c => "crc32c",
+            Checksum::Xxh3 => "xxh3-64",
+            Checksum::Blake3 => "blake3",
+        }
+    }
+}
+
+impl std::str::FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crc32c" => Ok(Checksum::Crc32c),
+            "xxh3-64" => Ok(Checksum::Xxh3),
+            "blake3" => Ok(Checksum::Blake3),
+            _ => Err(format!("unknown checksum: {}", s)),
+        }
+    }
+}
+
+/// _DATA_CHECKSUM_HEADER_MAGIC is "CKSM" in little endian, and identify a DataChecksumHeader.
+const _DATA_CHECKSUM_HEADER_MAGIC: u32 = 0x4d53_4b43;
+/// DATA_CHECKSUM_HEADER_OFFSET is where DataChecksumHeader is in a V2 data file, right after
+/// DataLayoutHeader.
+pub const DATA_CHECKSUM_HEADER_OFFSET: usize = 72;
+
+/// DataChecksumHeader is saved at DATA_CHECKSUM_HEADER_OFFSET in a V2 data file, it tells the checksum
+/// of records written into the stack, zeros there mean Checksum::Crc32c.
+/// `| checksum_magic: u32 | checksum: u32 | (8 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DataChecksumHeader {
+    /// checksum_magic should always be _DATA_CHECKSUM_HEADER_MAGIC
+    checksum_magic: u32,
+    /// checksum is the id of Checksum
+    pub checksum: u32,
+}
+
+impl DataChecksumHeader {
+    /// new return a DataChecksumHeader by checksum
+    pub fn new(checksum: Checksum) -> Self {
+        DataChecksumHeader {
+            checksum_magic: _DATA_CHECKSUM_HEADER_MAGIC,
+            checksum: checksum.id(),
+        }
+    }
+
+    /// size of DataChecksumHeader is 8 now
+    pub fn size() -> usize {
+        8
+    }
+
+    /// checksum_from_bytes return the checksum told by the bytes at DATA_CHECKSUM_HEADER_OFFSET,
+    /// zeros mean Checksum::Crc32c, None if the header is broken or the checksum is unknown.
+    pub fn checksum_from_bytes(data: &[u8]) -> Option<Checksum> {
+        assert!(data.len() == Self::size());
+        if data.iter().all(|b| *b == 0) {
+            return Some(Checksum::Crc32c);
+        }
+        match bincode::deserialize::<DataChecksumHeader>(data) {
+            Ok(dch) if dch.checksum_magic == _DATA_CHECKSUM_HEADER_MAGIC => {
+                Checksum::from_id(dch.checksum)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_data_checksum_header_size() {
+    for checksum in [Checksum::Crc32c, Checksum::Xxh3, Checksum::Blake3] {
+        let bs = bincode::serialize(&DataChecksumHeader::new(checksum)).unwrap();
+        assert!(DataChecksumHeader::size() == bs.len());
+        assert_eq!(DataChecksumHeader::checksum_from_bytes(&bs), Some(checksum));
+        assert_eq!(checksum.name().parse::<Checksum>(), Ok(checksum));
+    }
+    assert_eq!(
+        DataChecksumHeader::checksum_from_bytes(&[0; 8]),
+        Some(Checksum::Crc32c)
+    );
+    let mut broken = bincode::serialize(&DataChecksumHeader::new(Checksum::Xxh3)).unwrap();
+    broken[4] = 9;
+    assert_eq!(DataChecksumHeader::checksum_from_bytes(&broken), None);
+}
+
+/// _DATA_SEALED_HEADER_MAGIC is "SEAL" in little endian, and identify a DataSealedHeader.
+const _DATA_SEALED_HEADER_MAGIC: u32 = 0x4c41_4553;
+/// DATA_SEALED_HEADER_OFFSET is where DataSealedHeader is in a data file, right after DataChecksumHeader.
+pub const DATA_SEALED_HEADER_OFFSET: usize = 80;
+
+/// DataSealedHeader is saved at DATA_SEALED_HEADER_OFFSET in data files of stacks which are sealed when
+/// they are closed. A V1 stack without it was written before seals, its index file only holds
+/// IndexRecords; a stack with it whose index file has no seal is unsealed or truncated.
+/// `| sealed_magic: u32 | reserved: u32 | (8 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DataSealedHeader {
+    /// sealed_magic should always be _DATA_SEALED_HEADER_MAGIC
+    sealed_magic: u32,
+    /// reserved is always 0 now
+    reserved: u32,
+}
+
+impl DataSealedHeader {
+    /// new return a DataSealedHeader
+    pub fn new() -> Self {
+        DataSealedHeader {
+            sealed_magic: _DATA_SEALED_HEADER_MAGIC,
+            reserved: 0,
+        }
+    }
+
+    /// size of DataSealedHeader is 8 now
+    pub fn size() -> usize {
+        8
+    }
+
+    /// has_magic check if data starts with a bincode serialized DataSealedHeader
+    pub fn has_magic(data: &[u8]) -> bool {
+        data.len() >= 4
+            && u32::from_le_bytes(data[..4].try_into().unwrap()) == _DATA_SEALED_HEADER_MAGIC
+    }
+}
+
+impl Default for DataSealedHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_data_sealed_header_size() {
+    let bs = bincode::serialize(&DataSealedHeader::new()).unwrap();
+    assert!(DataSealedHeader::size() == bs.len());
+    assert!(DataSealedHeader::has_magic(&bs));
+    assert!(!DataSealedHeader::has_magic(&[0; 8]));
+    assert!(DATA_CHECKSUM_HEADER_OFFSET + DataChecksumHeader::size() <= DATA_SEALED_HEADER_OFFSET);
+}
+
+#[test]
+fn test_data_encryption_header_size() {
+    let temp = DataEncryptionHeader::new(Cipher::Aes256Gcm, 1, DATA_ENCRYPTION_FLAG_META);
+    assert!(DataEncryptionHeader::size() == bincode::serialized_size(&temp).unwrap() as usize);
+    let zeros = bincode::deserialize::<DataEncryptionHeader>(&[0; 16]).unwrap();
+    assert!(!zeros.valid());
 }
 
 /// _DATA_RECORD_HEADER_MAGIC_START is a magic number used by data_record
 pub const _DATA_RECORD_HEADER_MAGIC_START: u32 = 257758;
 /// _DATA_RECORD_HEADER_MAGIC_END is a magic number used by data_record
 pub const _DATA_RECORD_HEADER_MAGIC_END: u32 = 857752;
+/// _DATA_RECORD_HEADER_MAGIC_END_FLAGGED is used by data_record which carries flags,
+/// the flags are saved in lower 16 bits of data_magic_record_end.
+pub const _DATA_RECORD_HEADER_MAGIC_END_FLAGGED: u32 = 0x4253_0000;
+const _DATA_RECORD_FLAGS_MASK: u32 = 0xffff;
+
+/// DATA_RECORD_FLAG_CRC_TRAILER means crc is unknown when header is written (e.g. data is streamed),
+/// the crc field in header is 0 and the real crc is saved as 4 bytes right after data.
+pub const DATA_RECORD_FLAG_CRC_TRAILER: u16 = 1;
+/// DATA_RECORD_FLAG_LINK means this record holds no data but points to an identical record written
+/// before in the same data file, a DataRecordLink is saved in place of data, size and crc in header
+/// are copied from the target record.
+pub const DATA_RECORD_FLAG_LINK: u16 = 2;
+/// DATA_RECORD_FLAG_ZSTD means data is compressed by zstd, see DataRecord::new_compressed.
+pub const DATA_RECORD_FLAG_ZSTD: u16 = 4;
+/// DATA_RECORD_FLAG_LZ4 means data is compressed by lz4, see DataRecord::new_compressed.
+pub const DATA_RECORD_FLAG_LZ4: u16 = 8;
+/// DATA_RECORD_FLAG_ENCRYPTED means data is encrypted by the cipher of stack, see DataRecord::new_encoded,
+/// crc in header is computed over the stored bytes instead of original data.
+pub const DATA_RECORD_FLAG_ENCRYPTED: u16 = 16;
+/// DATA_RECORD_FLAG_LARGE means data is larger than a record can hold, a DataRecordLarge is saved
+/// in place of data and data is saved in chunk records right after this one. Only V2 stacks have it.
+/// size in header is LARGE_RECORD_SIZE and crc covers the DataRecordLarge.
+pub const DATA_RECORD_FLAG_LARGE: u16 = 32;
+/// DATA_RECORD_FLAG_CHUNK means this record is a chunk of the large record before it, it has the
+/// cookie of the large record and no index entry of its own.
+pub const DATA_RECORD_FLAG_CHUNK: u16 = 64;
+/// DATA_RECORD_FLAG_XXH3 means data is checksummed by Checksum::Xxh3 instead of crc, the checksum is
+/// saved as 8 bytes right after data and crc in header is its first 4 bytes, or 0 with
+/// DATA_RECORD_FLAG_CRC_TRAILER. A large record with it only tells its chunk records have it.
+pub const DATA_RECORD_FLAG_XXH3: u16 = 128;
+/// DATA_RECORD_FLAG_BLAKE3 is like DATA_RECORD_FLAG_XXH3 for Checksum::Blake3, whose checksum is 32 bytes.
+pub const DATA_RECORD_FLAG_BLAKE3: u16 = 256;
+/// LARGE_RECORD_SIZE is size_data of a large record in IndexRecord and MetaRecord, the size of
+/// its data is in DataRecordLarge.
+pub const LARGE_RECORD_SIZE: u32 = u32::MAX;
+/// LARGE_RECORD_CHUNK_SIZE is the size of every chunk but the last one of large records written now.
+pub const LARGE_RECORD_CHUNK_SIZE: u32 = 256 * 1024 * 1024;
+
+/// Compression is the codec used to compress data of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// None means data is saved as is
+    #[default]
+    None,
+    /// Zstd compress data with zstd
+    Zstd,
+    /// Lz4 compress data with lz4 block format
+    Lz4,
+}
+
+impl Compression {
+    /// flag return the record flag of this codec
+    pub fn flag(&self) -> u16 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => DATA_RECORD_FLAG_ZSTD,
+            Compression::Lz4 => DATA_RECORD_FLAG_LZ4,
+        }
+    }
+}
+
+/// DataRecordLink is saved as data of a link record, it locates the target record.
+/// `| offset_data: u64 | cookie: u32 | (12 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DataRecordLink {
+    /// offset_data of target record in data file
+    pub offset_data: u64,
+    /// cookie of target record
+    pub cookie: u32,
+}
+
+impl DataRecordLink {
+    /// size of DataRecordLink is 12 now
+    pub fn size() -> usize {
+        12
+    }
+
+    /// new_from_bytes help deserialize DataRecordLink from &[u8]
+    pub fn new_from_bytes(data: &[u8]) -> Result<DataRecordLink, Box<bincode::ErrorKind>> {
+        assert!(data.len() == Self::size());
+        bincode::deserialize::<DataRecordLink>(data)
+    }
+}
+
+#[test]
+fn test_data_record_link_size() {
+    let link = DataRecordLink {
+        offset_data: 4096,
+        cookie: 1,
+    };
+    assert!(bincode::serialized_size(&link).unwrap() as usize == DataRecordLink::size());
+}
+
+/// DataRecordLarge is saved as data of a large record, it tells where its chunk records are:
+/// the first one starts right after the large record, every chunk record is padded to alignment.
+/// Chunk records are checksummed by the Checksum told by flags of the large record.
+/// `| size: u64 | chunk_size: u32 | alignment: u32 | (16 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DataRecordLarge {
+    /// size of data
+    pub size: u64,
+    /// chunk_size is the size of data in every chunk record but the last one
+    pub chunk_size: u32,
+    /// alignment of the stack when the record is written
+    pub alignment: u32,
+}
+
+impl DataRecordLarge {
+    /// new return a DataRecordLarge of size bytes in chunks of LARGE_RECORD_CHUNK_SIZE
+    pub fn new(size: u64, alignment: usize) -> Self {
+        DataRecordLarge {
+            size,
+            chunk_size: LARGE_RECORD_CHUNK_SIZE,
+            alignment: alignment as u32,
+        }
+    }
+
+    /// size of DataRecordLarge is 16 now
+    pub fn size() -> usize {
+        16
+    }
+
+    /// valid check if chunk_size and alignment can locate the chunk records
+    pub fn valid(&self) -> bool {
+        self.chunk_size > 0 && valid_alignment(self.alignment as usize)
+    }
+
+    /// chunks return (offset_data, size of data) of every chunk record of the large record at offset_data,
+    /// whose chunk records are checksummed by checksum.
+    pub fn chunks(&self, offset_data: u64, checksum: Checksum) -> Vec<(u64, u32)> {
+        let alignment = self.alignment as usize;
+        let mut offset = offset_data
+            + (DataRecordHeader::size() + padding_data_size(Self::size(), alignment)) as u64;
+        let mut remaining = self.size;
+        let mut chunks = Vec::with_capacity((self.size / self.chunk_size as u64 + 1) as usize);
+        while remaining > 0 {
+            let size = remaining.min(self.chunk_size as u64) as u32;
+            chunks.push((offset, size));
+            offset += self.chunk_record_size(size, checksum);
+            remaining -= size as u64;
+        }
+        chunks
+    }
+
+    /// end return where the last chunk record of the large record at offset_data ends
+    pub fn end(&self, offset_data: u64, checksum: Checksum) -> u64 {
+        match self.chunks(offset_data, checksum).last() {
+            Some((offset, size)) => offset + self.chunk_record_size(*size, checksum),
+            None => {
+                offset_data
+                    + (DataRecordHeader::size()
+                        + padding_data_size(Self::size(), self.alignment as usize))
+                        as u64
+            }
+        }
+    }
+
+    /// chunk_record_size return the full size of a chunk record of size bytes, every chunk record
+    /// carries its checksum as a trailer.
+    fn chunk_record_size(&self, size: u32, checksum: Checksum) -> u64 {
+        (DataRecordHeader::size()
+            + padding_data_size(size as usize + checksum.size(), self.alignment as usize))
+            as u64
+    }
+
+    /// new_from_bytes help deserialize DataRecordLarge from &[u8]
+    pub fn new_from_bytes(data: &[u8]) -> Result<DataRecordLarge, Box<bincode::ErrorKind>> {
+        assert!(data.len() == Self::size());
+        bincode::deserialize::<DataRecordLarge>(data)
+    }
+}
+
+#[test]
+fn test_data_record_large_chunks() {
+    let large = DataRecordLarge::new(u32::MAX as u64 + 2, 8);
+    assert!(bincode::serialized_size(&large).unwrap() as usize == DataRecordLarge::size());
+    assert!(large.valid());
+    let chunks = large.chunks(4096, Checksum::Crc32c);
+    assert_eq!(chunks.len(), 17);
+    assert_eq!(chunks[0], (4096 + 40, LARGE_RECORD_CHUNK_SIZE));
+    assert_eq!(chunks[16].1, 1);
+    let stride = (DataRecordHeader::size()
+        + padding_data_size(LARGE_RECORD_CHUNK_SIZE as usize + 4, 8)) as u64;
+    assert_eq!(chunks[1].0 - chunks[0].0, stride);
+    assert_eq!(large.end(4096, Checksum::Crc32c), chunks[16].0 + 32);
+    let chunks = large.chunks(4096, Checksum::Blake3);
+    assert_eq!(chunks[0].0, 4096 + 40);
+    assert_eq!(large.end(4096, Checksum::Blake3), chunks[16].0 + 56);
+    assert_eq!(
+        chunks.iter().map(|(_, size)| *size as u64).sum::<u64>(),
+        large.size
+    );
+}
 
 /// DataRecordHeader carries cookie, size and crc info of this data record
 /// # Note
 /// Every data item start with this DataRecordHeader like this:
 /// `| data_magic_record_start: u32 | cookie: u32 | size: u32 | crc: u32 | data_magic_record_end: u32 | (20 bytes)`
+/// data_magic_record_end is _DATA_RECORD_HEADER_MAGIC_END for a plain record, or
+/// `_DATA_RECORD_HEADER_MAGIC_END_FLAGGED | flags` for a record with flags.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DataRecordHeader {
     /// data_magic_record_start is used to recognize this is data_record_header start, which is always _DATA_RECORD_HEADER_MAGIC_START
@@ -76,12 +658,23 @@ impl PartialEq<DataRecordHeader> for DataRecordHeader {
 impl DataRecordHeader {
     /// new received cookie, data_size, and crc
     fn new(cookie: u32, size: u32, crc: u32) -> Self {
+        Self::new_with_flags(cookie, size, crc, 0)
+    }
+
+    /// new_with_flags received cookie, data_size, crc and flags,
+    /// a header without flags is the same as the one created by new.
+    pub fn new_with_flags(cookie: u32, size: u32, crc: u32, flags: u16) -> Self {
+        let data_magic_record_end = if flags == 0 {
+            _DATA_RECORD_HEADER_MAGIC_END
+        } else {
+            _DATA_RECORD_HEADER_MAGIC_END_FLAGGED | flags as u32
+        };
         DataRecordHeader {
             data_magic_record_start: _DATA_RECORD_HEADER_MAGIC_START,
             cookie,
             size,
             crc,
-            data_magic_record_end: _DATA_RECORD_HEADER_MAGIC_END,
+            data_magic_record_end,
         }
     }
 
@@ -90,7 +683,133 @@ impl DataRecordHeader {
     /// just provides a simple crc.
     pub fn validate_magic(&self) -> bool {
         self.data_magic_record_start == _DATA_RECORD_HEADER_MAGIC_START
-            && self.data_magic_record_end == self.data_magic_record_end
+            && (self.data_magic_record_end == _DATA_RECORD_HEADER_MAGIC_END
+                || self.data_magic_record_end & !_DATA_RECORD_FLAGS_MASK
+                    == _DATA_RECORD_HEADER_MAGIC_END_FLAGGED)
+    }
+
+    /// flags of this data record, 0 for a plain record.
+    pub fn flags(&self) -> u16 {
+        if self.data_magic_record_end == _DATA_RECORD_HEADER_MAGIC_END {
+            return 0;
+        }
+        (self.data_magic_record_end & _DATA_RECORD_FLAGS_MASK) as u16
+    }
+
+    /// trailer_size is the size of bytes saved right after data, e.g. crc of a streamed record.
+    pub fn trailer_size(&self) -> usize {
+        let checksum = self.checksum();
+        if checksum != Checksum::Crc32c {
+            checksum.size()
+        } else if self.flags() & DATA_RECORD_FLAG_CRC_TRAILER != 0 {
+            4
+        } else {
+            0
+        }
+    }
+
+    /// checksum return the algorithm data of this record is checksummed by.
+    pub fn checksum(&self) -> Checksum {
+        let flags = self.flags();
+        if flags & DATA_RECORD_FLAG_BLAKE3 != 0 {
+            Checksum::Blake3
+        } else if flags & DATA_RECORD_FLAG_XXH3 != 0 {
+            Checksum::Xxh3
+        } else {
+            Checksum::Crc32c
+        }
+    }
+
+    /// is_link check if this is a link record
+    pub fn is_link(&self) -> bool {
+        self.flags() & DATA_RECORD_FLAG_LINK != 0
+    }
+
+    /// is_large check if this is a large record whose data is in chunk records after it
+    pub fn is_large(&self) -> bool {
+        self.flags() & DATA_RECORD_FLAG_LARGE != 0
+    }
+
+    /// is_chunk check if this is a chunk record of a large record
+    pub fn is_chunk(&self) -> bool {
+        self.flags() & DATA_RECORD_FLAG_CHUNK != 0
+    }
+
+    /// compression return the codec used by data of this record.
+    pub fn compression(&self) -> Compression {
+        let flags = self.flags();
+        if flags & DATA_RECORD_FLAG_ZSTD != 0 {
+            Compression::Zstd
+        } else if flags & DATA_RECORD_FLAG_LZ4 != 0 {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+
+    /// is_encrypted check if data of this record is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.flags() & DATA_RECORD_FLAG_ENCRYPTED != 0
+    }
+
+    /// is_encoded check if data is compressed or encrypted, the stored bytes of such record
+    /// start with the size of original data.
+    pub fn is_encoded(&self) -> bool {
+        !self.is_link() && (self.is_encrypted() || self.compression() != Compression::None)
+    }
+
+    /// data_size return the size of original data, it differs from size only if data is encoded,
+    /// body should start right after header and hold at least stored_size bytes.
+    /// None is returned if data is encoded but stored bytes are too short to hold the size.
+    pub fn data_size(&self, body: &[u8]) -> Option<u32> {
+        if !self.is_encoded() {
+            return Some(self.size);
+        }
+        if self.size < 4 || body.len() < 4 {
+            return None;
+        }
+        Some(u32::from_le_bytes(body[..4].try_into().unwrap()))
+    }
+
+    /// stored_size is the size of bytes saved right after header before padding,
+    /// data and trailer for a normal record, DataRecordLink for a link record and DataRecordLarge
+    /// for a large record.
+    pub fn stored_size(&self) -> usize {
+        if self.is_link() {
+            DataRecordLink::size()
+        } else if self.is_large() {
+            DataRecordLarge::size()
+        } else {
+            self.size as usize + self.trailer_size()
+        }
+    }
+
+    /// body_size is the size of everything after header: stored bytes and padding to alignment.
+    pub fn body_size(&self, alignment: usize) -> usize {
+        padding_data_size(self.stored_size(), alignment)
+    }
+
+    /// crc_from_body return the crc of data, or the first 4 bytes of checksum if data is not checksummed
+    /// by Checksum::Crc32c, body should start right after header and hold at least stored_size bytes.
+    pub fn crc_from_body(&self, body: &[u8]) -> u32 {
+        if !self.is_link() && !self.is_large() && self.trailer_size() > 0 {
+            let start = self.size as usize;
+            u32::from_le_bytes(body[start..start + 4].try_into().unwrap())
+        } else {
+            self.crc
+        }
+    }
+
+    /// checksum_from_body return the checksum of data saved in record, it is crc in little endian
+    /// for Checksum::Crc32c, body should start right after header and hold at least stored_size bytes.
+    pub fn checksum_from_body(&self, body: &[u8]) -> Vec<u8> {
+        match self.checksum() {
+            Checksum::Crc32c => self.crc_from_body(body).to_le_bytes().to_vec(),
+            checksum => {
+                let start = self.size as usize;
+                body[start..start + checksum.size()].to_vec()
+            }
+        }
     }
 
     /// new_from_bytes help deserialize DataRecordHeader from &[u8]
@@ -114,8 +833,35 @@ fn test_data_record_header_size() {
     );
 }
 
+#[test]
+fn test_data_record_header_validate_magic() {
+    let drh = DataRecordHeader::new(1, 2, 3);
+    assert!(drh.validate_magic());
+    assert_eq!(drh.flags(), 0);
+    let drh = DataRecordHeader::new_with_flags(1, 2, 0, DATA_RECORD_FLAG_CRC_TRAILER);
+    assert!(drh.validate_magic());
+    assert_eq!(drh.flags(), DATA_RECORD_FLAG_CRC_TRAILER);
+    assert_eq!(drh.crc_from_body(&[0, 0, 3, 0, 0, 0]), 3);
+    let drh = DataRecordHeader::new_with_flags(1, 6, 3, DATA_RECORD_FLAG_LZ4);
+    assert_eq!(drh.compression(), Compression::Lz4);
+    assert_eq!(drh.data_size(&[100, 0, 0, 0, 1, 2]), Some(100));
+    let drh = DataRecordHeader::new_with_flags(1, 3, 3, DATA_RECORD_FLAG_LZ4);
+    assert_eq!(drh.data_size(&[100, 0, 0, 0]), None);
+    let dr = DataRecord::new(1, 3, 0, vec![1, 2, 3], 8).with_checksum(Checksum::Xxh3, &[7; 8], 8);
+    assert_eq!(dr.header.checksum(), Checksum::Xxh3);
+    assert_eq!(dr.header.stored_size(), 11);
+    assert_eq!(dr.size() % 8, 0);
+    assert_eq!(dr.header.checksum_from_body(&dr.data), vec![7; 8]);
+    assert_eq!(dr.header.crc_from_body(&dr.data), dr.header.crc);
+    let mut bs = bincode::serialize(&drh).unwrap();
+    bs[DataRecordHeader::size() - 1] ^= 0xff;
+    assert!(!DataRecordHeader::new_from_bytes(&bs)
+        .unwrap()
+        .validate_magic());
+}
+
 /// DataRecord is a dummy struct not on disk, records arrange like this:
-/// | header (20 bytes) | data | padding | <- this item padding to 4K
+/// | header (20 bytes) | data | padding | <- this item padding to alignment of stack, 4K by default
 #[derive(Debug)]
 pub struct DataRecord {
     /// header is DataRecordHeader, hold some metadata.
@@ -129,29 +875,37 @@ pub struct DataRecord {
 /// padding_data_size help to calculate the padding size
 /// | data_record_header | data | padding |
 /// ⬆️ DataRecord start here
-/// (data_record_header(20 Byte) + data + padding) should padding to 4K, so we need to calculate the padding size by data size
-pub fn padding_data_size(data_size: usize) -> usize {
+/// (data_record_header(20 Byte) + data + padding) should padding to alignment, so we need to calculate the padding size by data size
+pub fn padding_data_size(data_size: usize, alignment: usize) -> usize {
     let data_with_header_size = data_size + DataRecordHeader::size();
-    if data_with_header_size % ALIGNMENT_SIZE == 0 {
-        return data_size;
+    if data_with_header_size.is_multiple_of(alignment) {
+        data_size
     } else {
-        return (data_size + ALIGNMENT_SIZE) - (data_with_header_size % ALIGNMENT_SIZE);
+        (data_size + alignment) - (data_with_header_size % alignment)
     }
 }
 
 #[test]
 fn test_padding_data_size() {
-    let ok = |size: usize| -> bool { (size + DataRecordHeader::size()) % ALIGNMENT_SIZE == 0 };
-    assert!(ok(padding_data_size(0)));
-    assert!(ok(padding_data_size(1234)));
-    assert!(ok(padding_data_size(4321)));
+    for alignment in [1, 8, 512, DEFAULT_ALIGNMENT_SIZE] {
+        let ok = |size: usize| -> bool {
+            let padded = padding_data_size(size, alignment);
+            padded >= size
+                && padded - size < alignment
+                && (padded + DataRecordHeader::size()) % alignment == 0
+        };
+        assert!(ok(0));
+        assert!(ok(1234));
+        assert!(ok(4321));
+    }
+    assert_eq!(padding_data_size(200, 1), 200);
 }
 
 impl DataRecord {
     /// new create a DataRecord
-    pub fn new(cookie: u32, size: u32, crc: u32, data: Vec<u8>) -> Self {
+    pub fn new(cookie: u32, size: u32, crc: u32, data: Vec<u8>, alignment: usize) -> Self {
         let data_size = data.len();
-        let padding_size = padding_data_size(data_size) - data_size;
+        let padding_size = padding_data_size(data_size, alignment) - data_size;
         let zero_padding: Vec<u8> = vec![0; padding_size];
         assert!(zero_padding.len() == padding_size);
         DataRecord {
@@ -161,11 +915,98 @@ impl DataRecord {
         }
     }
 
-    /// size calculate the full data size
-    /// MUST PADDING to 4k.
+    /// new_compressed create a record whose data is compressed, size and crc are of the original data.
+    /// data is saved as `| size: u32 | compressed |`, so size in header is the length of compressed plus 4.
+    pub fn new_compressed(
+        cookie: u32,
+        size: u32,
+        crc: u32,
+        compression: Compression,
+        compressed: Vec<u8>,
+        alignment: usize,
+    ) -> Self {
+        Self::new_encoded(cookie, size, crc, compression.flag(), compressed, alignment)
+    }
+
+    /// new_encoded create a record whose data is compressed or encrypted as flags says,
+    /// data is saved as `| size: u32 | encoded |`, so size in header is the length of encoded plus 4.
+    pub fn new_encoded(
+        cookie: u32,
+        size: u32,
+        crc: u32,
+        flags: u16,
+        encoded: Vec<u8>,
+        alignment: usize,
+    ) -> Self {
+        let mut data = Vec::with_capacity(encoded.len() + 4);
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend(encoded);
+        let padding_size = padding_data_size(data.len(), alignment) - data.len();
+        DataRecord {
+            header: DataRecordHeader::new_with_flags(cookie, data.len() as u32, crc, flags),
+            data,
+            padding: vec![0; padding_size],
+        }
+    }
+
+    /// new_link create a link record which points to target, size and crc are copied from target.
+    pub fn new_link(
+        cookie: u32,
+        size: u32,
+        crc: u32,
+        target: &DataRecordLink,
+        alignment: usize,
+    ) -> Self {
+        let data = bincode::serialize(target).unwrap();
+        let padding_size = padding_data_size(data.len(), alignment) - data.len();
+        DataRecord {
+            header: DataRecordHeader::new_with_flags(cookie, size, crc, DATA_RECORD_FLAG_LINK),
+            data,
+            padding: vec![0; padding_size],
+        }
+    }
+
+    /// new_large create a large record, its data is saved in chunk records after it as large says,
+    /// which are checksummed by checksum. crc in header always covers DataRecordLarge.
+    pub fn new_large(
+        cookie: u32,
+        large: &DataRecordLarge,
+        checksum: Checksum,
+        alignment: usize,
+    ) -> Self {
+        let data = bincode::serialize(large).unwrap();
+        let crc = utils::CASTAGNOLI.checksum(&data);
+        let padding_size = padding_data_size(data.len(), alignment) - data.len();
+        DataRecord {
+            header: DataRecordHeader::new_with_flags(
+                cookie,
+                LARGE_RECORD_SIZE,
+                crc,
+                DATA_RECORD_FLAG_LARGE | checksum.flag(),
+            ),
+            data,
+            padding: vec![0; padding_size],
+        }
+    }
+
+    /// with_checksum set checksum of data computed by checksum algorithm, crc in header is set for
+    /// Checksum::Crc32c, otherwise checksum is saved right after data and padding is adjusted.
+    pub fn with_checksum(mut self, checksum: Checksum, sum: &[u8], alignment: usize) -> Self {
+        assert!(sum.len() == checksum.size());
+        let crc = u32::from_le_bytes(sum[..4].try_into().unwrap());
+        let flags = self.header.flags() | checksum.flag();
+        self.header =
+            DataRecordHeader::new_with_flags(self.header.cookie, self.header.size, crc, flags);
+        if checksum != Checksum::Crc32c {
+            self.data.extend_from_slice(sum);
+            let padding_size = padding_data_size(self.data.len(), alignment) - self.data.len();
+            self.padding = vec![0; padding_size];
+        }
+        self
+    }
+
+    /// size calculate the full data size, it is padded to the alignment given when created.
     pub fn size(&self) -> usize {
-        let size = DataRecordHeader::size() + self.data.len() + self.padding.len();
-        assert!(size % ALIGNMENT_SIZE == 0);
-        size
+        DataRecordHeader::size() + self.data.len() + self.padding.len()
     }
 }
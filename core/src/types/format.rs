@@ -0,0 +1,108 @@
+//! format will provide the format version of stack files.
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// FormatVersion is the on-disk format of a stack, index, meta and data file of a stack always
+/// have the same version. It is told by the magic number of every file header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FormatVersion {
+    /// V1 files only have magic number and stack_id in their headers, any version of bytestack
+    /// reads them. New stacks are V1 unless V2 is asked for.
+    #[default]
+    V1,
+    /// V2 files have a FormatHeader after magic number and stack_id, readers before V2 reject them.
+    V2,
+}
+
+impl FormatVersion {
+    /// id of version saved in FormatHeader
+    pub fn id(&self) -> u32 {
+        match self {
+            FormatVersion::V1 => 1,
+            FormatVersion::V2 => 2,
+        }
+    }
+
+    /// from_id return the version by id, None if it is unknown
+    pub fn from_id(id: u32) -> Option<FormatVersion> {
+        match id {
+            1 => Some(FormatVersion::V1),
+            2 => Some(FormatVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// FORMAT_FLAG_META_BINCODE means MetaRecords of the stack are encoded with bincode instead of json.
+pub const FORMAT_FLAG_META_BINCODE: u32 = 1;
+
+/// FormatHeader follows magic number and stack_id in headers of V2 files, it is serialized with
+/// bincode into index and data file, and with json into meta file.
+/// # Note
+/// `| version: u32 | flags: u32 | create_time: u64 | creator: [u8; 16] | (32 bytes)`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FormatHeader {
+    /// version is the id of FormatVersion
+    pub version: u32,
+    /// flags of the stack like FORMAT_FLAG_META_BINCODE, they are the same in index, meta and data file
+    pub flags: u32,
+    /// create_time is the unix timestamp when the stack was created
+    pub create_time: u64,
+    /// creator is the name and version of the writer which created the stack, padded with zeros
+    pub creator: [u8; 16],
+}
+
+impl FormatHeader {
+    /// new return a V2 FormatHeader created now by this version of bytestack
+    pub fn new(flags: u32) -> Self {
+        let create_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut creator = [0; 16];
+        let name = format!("bytestack {}", env!("CARGO_PKG_VERSION"));
+        let len = name.len().min(creator.len());
+        creator[..len].copy_from_slice(&name.as_bytes()[..len]);
+        FormatHeader {
+            version: FormatVersion::V2.id(),
+            flags,
+            create_time,
+            creator,
+        }
+    }
+
+    /// size return the size of FormatHeader serialized with bincode
+    pub fn size() -> usize {
+        32
+    }
+
+    /// valid check if version is V2
+    pub fn valid(&self) -> bool {
+        self.version == FormatVersion::V2.id()
+    }
+
+    /// creator_str return creator without padding zeros
+    pub fn creator_str(&self) -> String {
+        let end = self
+            .creator
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.creator.len());
+        String::from_utf8_lossy(&self.creator[..end]).into_owned()
+    }
+
+    /// new_from_bytes help deserialize FormatHeader from &[u8]
+    pub fn new_from_bytes(data: &[u8]) -> Result<FormatHeader, Box<bincode::ErrorKind>> {
+        assert!(data.len() == Self::size());
+        bincode::deserialize::<FormatHeader>(data)
+    }
+}
+
+#[test]
+fn test_format_header_size() {
+    let fh = FormatHeader::new(3);
+    let bs = bincode::serialize(&fh).unwrap();
+    assert!(bs.len() == FormatHeader::size());
+    assert_eq!(FormatHeader::new_from_bytes(&bs).unwrap(), fh);
+    assert!(fh.valid() && fh.creator_str().starts_with("bytestack "));
+    assert_eq!(FormatVersion::from_id(fh.version), Some(FormatVersion::V2));
+}
@@ -1,9 +1,12 @@
 //! index will provide all data struct about index file.
+use super::format::{FormatHeader, FormatVersion};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
 /// _INDEX_HEADER_MAGIC is a magic number which identify this is a index file.
 const _INDEX_HEADER_MAGIC: u64 = 5201314;
+/// _INDEX_HEADER_MAGIC_V2 identify this is a V2 index file, a FormatHeader follows IndexMagicHeader.
+const _INDEX_HEADER_MAGIC_V2: u64 = 5201315;
 
 /// IndexMagicHeader will be serialized with bincode and save to file header in every index file, which is used to
 /// identification this is an index file, this struct SHOULD NOT BE MODIFIED!!!
@@ -24,21 +27,51 @@ impl IndexMagicHeader {
         }
     }
 
+    /// new_with_version return a IndexMagicHeader of version by stack_id
+    pub fn new_with_version(stack_id: u64, version: FormatVersion) -> Self {
+        IndexMagicHeader {
+            index_header_magic: match version {
+                FormatVersion::V1 => _INDEX_HEADER_MAGIC,
+                FormatVersion::V2 => _INDEX_HEADER_MAGIC_V2,
+            },
+            stack_id,
+        }
+    }
+
     /// size return the size of IndexMagicHeader
     pub fn size() -> usize {
         16
     }
 
-    /// valid check if index_header_magic is _INDEX_HEADER_MAGIC
+    /// valid check if index_header_magic is _INDEX_HEADER_MAGIC or _INDEX_HEADER_MAGIC_V2
     pub fn valid(&self) -> bool {
-        return self.index_header_magic == _INDEX_HEADER_MAGIC;
+        self.index_header_magic == _INDEX_HEADER_MAGIC
+            || self.index_header_magic == _INDEX_HEADER_MAGIC_V2
+    }
+
+    /// version return the format version told by index_header_magic
+    pub fn version(&self) -> FormatVersion {
+        if self.index_header_magic == _INDEX_HEADER_MAGIC_V2 {
+            FormatVersion::V2
+        } else {
+            FormatVersion::V1
+        }
+    }
+
+    /// header_size return where IndexRecords start in index file, it is the size of IndexMagicHeader
+    /// and the FormatHeader following it in a V2 index file.
+    pub fn header_size(&self) -> usize {
+        match self.version() {
+            FormatVersion::V1 => Self::size(),
+            FormatVersion::V2 => Self::size() + FormatHeader::size(),
+        }
     }
 }
 
 /// IndexRecord carries cookie, offset_data, size_data, offset_meta, size_meta of the data
 /// # Note
 /// Every index item will be like this: `| cookie: u32 | offset_data: u64 | size_data: u64 | offset_meta: u64 | size_meta: u32 | (30 bytes)`
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IndexRecord {
     /// cookie is a random u32 that forbid user to guess the offset, duplicate in DataRecordHeader
     pub cookie: u32,
@@ -99,6 +132,16 @@ impl IndexRecord {
     }
 }
 
+#[test]
+fn test_index_magic_header_version() {
+    let v1 = IndexMagicHeader::new(1);
+    let v2 = IndexMagicHeader::new_with_version(1, FormatVersion::V2);
+    assert!(v1.valid() && v2.valid());
+    assert_eq!(v1.header_size(), 16);
+    assert_eq!(v2.header_size(), 48);
+    assert!(bincode::serialized_size(&v2).unwrap() as usize == IndexMagicHeader::size());
+}
+
 #[test]
 fn test_index_record_size() {
     let ir = IndexRecord::new(0, 0, 0, 0, 0);
@@ -1,9 +1,12 @@
 //! meta will provide all data struct about meta file.
 
+use super::format::{FormatHeader, FormatVersion, FORMAT_FLAG_META_BINCODE};
 use serde::{Deserialize, Serialize};
 
 /// _META_HEADER_MAGIC is a magic number which identify this is a meta file.
 const _META_HEADER_MAGIC: u64 = 1314920;
+/// _META_HEADER_MAGIC_V2 identify this is a V2 meta file, whose MetaMagicHeader carries a FormatHeader.
+const _META_HEADER_MAGIC_V2: u64 = 1314921;
 
 /// MetaMagicHeader will be serialized with bincode and save to file header in every meta file, which is used to
 /// identification this is an meta file, this struct SHOULD NOT BE MODIFIED!!!
@@ -13,6 +16,9 @@ pub struct MetaMagicHeader {
     pub meta_magic_number: u64,
     /// stack_id is used to identify which index or data are associated with this file
     pub stack_id: u64,
+    /// format is the FormatHeader of a V2 meta file, None in a V1 meta file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<FormatHeader>,
 }
 
 impl MetaMagicHeader {
@@ -21,27 +27,110 @@ impl MetaMagicHeader {
         MetaMagicHeader {
             meta_magic_number: _META_HEADER_MAGIC,
             stack_id: stack_id,
+            format: None,
         }
     }
 
-    /// size return the size of MetaMagicHeader
-    /// WARNING: because of marshaling by json, size differ from each other.
-    pub fn size(&self) -> usize {
-        serde_json::to_vec(&self).unwrap().len() + 1
+    /// new_with_format return a V2 MetaMagicHeader by stack_id carrying format
+    pub fn new_with_format(stack_id: u64, format: FormatHeader) -> Self {
+        MetaMagicHeader {
+            meta_magic_number: _META_HEADER_MAGIC_V2,
+            stack_id,
+            format: Some(format),
+        }
+    }
+
+    /// valid check if meta_magic_number is _META_HEADER_MAGIC, or _META_HEADER_MAGIC_V2 with a FormatHeader
+    pub fn valid(&self) -> bool {
+        match &self.format {
+            None => self.meta_magic_number == _META_HEADER_MAGIC,
+            Some(format) => self.meta_magic_number == _META_HEADER_MAGIC_V2 && format.valid(),
+        }
+    }
+
+    /// version return the format version told by meta_magic_number
+    pub fn version(&self) -> FormatVersion {
+        if self.meta_magic_number == _META_HEADER_MAGIC_V2 {
+            FormatVersion::V2
+        } else {
+            FormatVersion::V1
+        }
+    }
+
+    /// encoding return how MetaRecords of this meta file are encoded, V1 meta files are always json.
+    pub fn encoding(&self) -> MetaEncoding {
+        match &self.format {
+            Some(format) if format.flags & FORMAT_FLAG_META_BINCODE != 0 => MetaEncoding::Bincode,
+            _ => MetaEncoding::Json,
+        }
+    }
+
+    /// to_bytes return MetaMagicHeader marshaled with json and a '\n', it is the first line of meta
+    /// file whatever MetaRecords are encoded with.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bs = serde_json::to_vec(&self).unwrap();
+        bs.push(b'\n');
+        bs
+    }
+
+    /// new_from_bytes read MetaMagicHeader from the beginning of meta file, return it with the size
+    /// of the first line, which is where MetaRecords start.
+    pub fn new_from_bytes(data: &[u8]) -> Result<(MetaMagicHeader, usize), serde_json::Error> {
+        let line_end = data.iter().position(|b| *b == b'\n');
+        let line = &data[..line_end.unwrap_or(data.len())];
+        let mh = serde_json::from_slice::<MetaMagicHeader>(line)?;
+        Ok((mh, line_end.map_or(data.len(), |i| i + 1)))
     }
 }
 
+/// MetaEncoding is how MetaRecords of a meta file are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaEncoding {
+    /// Json encodes every MetaRecord as a json line, extra becomes an array of numbers.
+    #[default]
+    Json,
+    /// Bincode encodes every MetaRecord with bincode after a small frame header, it is smaller and
+    /// faster to parse. Only V2 stacks can use it, and the seal of meta file is saved with bincode too.
+    Bincode,
+}
+
+/// _META_RECORD_MAGIC starts every MetaRecord encoded with bincode, its first byte is never '{',
+/// so a MetaRecord can be decoded without knowing the encoding of its meta file.
+const _META_RECORD_MAGIC: u32 = 0x524d_4201;
+
+/// MetaRecordFrame is the header of a MetaRecord encoded with bincode, body_size bytes of bincode
+/// follow it.
+/// `| meta_record_magic: u32 | body_size: u32 | (8 bytes)`
+#[derive(Serialize, Deserialize, Debug)]
+struct MetaRecordFrame {
+    meta_record_magic: u32,
+    body_size: u32,
+}
+
+/// MetaRecordBody is a MetaRecord serialized with bincode, which needs every field.
+type MetaRecordBody = (u64, u64, u32, u32, String, Vec<u8>, Vec<u8>);
+
 /// MetaRecord carries create_time, offset_data, size_data, cookie, filename and extra_info of data
 /// # Note
 /// MetaRecord will be marshaled to json
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MetaRecord {
-    create_time: u64,
-    offset_data: u64,
-    size_data: u32,
-    cookie: u32,
-    filename: String,
-    extra: Vec<u8>,
+    /// create_time is the unix timestamp when data was put
+    pub create_time: u64,
+    /// offset_data is offset of corresponding data in data file
+    pub offset_data: u64,
+    /// size_data is size of corresponding data in data file
+    pub size_data: u32,
+    /// cookie is the same as the one in IndexRecord and DataRecordHeader
+    pub cookie: u32,
+    /// filename given by user when put
+    pub filename: String,
+    /// extra is meta info given by user when put
+    pub extra: Vec<u8>,
+    /// sealed holds filename and extra encrypted by the cipher of stack, filename and extra are
+    /// empty if it is not empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sealed: Vec<u8>,
 }
 
 impl PartialEq<MetaRecord> for MetaRecord {
@@ -71,6 +160,7 @@ impl MetaRecord {
             size_data,
             filename,
             extra,
+            sealed: Vec::new(),
         }
     }
 
@@ -79,8 +169,124 @@ impl MetaRecord {
         return serde_json::from_slice::<MetaRecord>(data);
     }
 
+    /// is_sealed check if filename and extra are encrypted
+    pub fn is_sealed(&self) -> bool {
+        !self.sealed.is_empty()
+    }
+
     /// size return the size of this instance
     pub fn size(&self) -> usize {
         serde_json::to_vec(&self).unwrap().len() + 1
     }
+
+    /// encode return this instance encoded with encoding, a json line ends with '\n'.
+    pub fn encode(&self, encoding: MetaEncoding) -> Vec<u8> {
+        match encoding {
+            MetaEncoding::Json => {
+                let mut bs = serde_json::to_vec(&self).unwrap();
+                bs.push(b'\n');
+                bs
+            }
+            MetaEncoding::Bincode => {
+                let body = bincode::serialize(&(
+                    self.create_time,
+                    self.offset_data,
+                    self.size_data,
+                    self.cookie,
+                    &self.filename,
+                    &self.extra,
+                    &self.sealed,
+                ))
+                .unwrap();
+                let frame = MetaRecordFrame {
+                    meta_record_magic: _META_RECORD_MAGIC,
+                    body_size: body.len() as u32,
+                };
+                let mut bs = bincode::serialize(&frame).unwrap();
+                bs.extend(body);
+                bs
+            }
+        }
+    }
+
+    /// bincode_size return the size of the MetaRecord encoded with bincode at the beginning of data,
+    /// None if data does not start with one.
+    pub fn bincode_size(data: &[u8]) -> Option<usize> {
+        if data.len() < 8 {
+            return None;
+        }
+        match bincode::deserialize::<MetaRecordFrame>(&data[..8]) {
+            Ok(frame) if frame.meta_record_magic == _META_RECORD_MAGIC => {
+                Some(8 + frame.body_size as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// new_from_bincode help read MetaRecord encoded with bincode from &[u8]
+    pub fn new_from_bincode(data: &[u8]) -> Result<MetaRecord, Box<bincode::ErrorKind>> {
+        if Self::bincode_size(data) != Some(data.len()) {
+            return Err(Box::new(bincode::ErrorKind::Custom(String::from(
+                "invalid frame of MetaRecord",
+            ))));
+        }
+        let body = bincode::deserialize::<MetaRecordBody>(&data[8..])?;
+        Ok(MetaRecord {
+            create_time: body.0,
+            offset_data: body.1,
+            size_data: body.2,
+            cookie: body.3,
+            filename: body.4,
+            extra: body.5,
+            sealed: body.6,
+        })
+    }
+
+    /// decode read MetaRecord from &[u8] whatever it is encoded with, a json line may have
+    /// whitespaces around it.
+    pub fn decode(data: &[u8]) -> Result<MetaRecord, Box<dyn std::error::Error + Send + Sync>> {
+        match data.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => Ok(Self::new_from_bytes(data)?),
+            _ => Ok(Self::new_from_bincode(data)?),
+        }
+    }
+}
+
+#[test]
+fn test_meta_magic_header_version() {
+    let v1: MetaMagicHeader =
+        serde_json::from_slice(&serde_json::to_vec(&MetaMagicHeader::new(1)).unwrap()).unwrap();
+    assert!(v1.valid() && v1.format.is_none() && v1.version() == FormatVersion::V1);
+    let bs =
+        serde_json::to_vec(&MetaMagicHeader::new_with_format(1, FormatHeader::new(0))).unwrap();
+    let v2: MetaMagicHeader = serde_json::from_slice(&bs).unwrap();
+    assert!(v2.valid() && v2.version() == FormatVersion::V2);
+    assert_eq!(v2.encoding(), MetaEncoding::Json);
+    let mut bs =
+        MetaMagicHeader::new_with_format(1, FormatHeader::new(FORMAT_FLAG_META_BINCODE)).to_bytes();
+    let header_size = bs.len();
+    bs.extend(b"\x01\n");
+    let (v2, size) = MetaMagicHeader::new_from_bytes(&bs).unwrap();
+    assert_eq!(size, header_size);
+    assert_eq!(v2.encoding(), MetaEncoding::Bincode);
+}
+
+#[test]
+fn test_meta_record_encoding() {
+    let mut mr = MetaRecord::new(1, 4096, 7, 100, String::from("a\n.jpg"), vec![b'{'; 3]);
+    mr.sealed = vec![9; 5];
+    for encoding in [MetaEncoding::Json, MetaEncoding::Bincode] {
+        let bs = mr.encode(encoding);
+        let decoded = MetaRecord::decode(&bs).unwrap();
+        assert_eq!(decoded, mr);
+        assert_eq!(decoded.filename, mr.filename);
+        assert_eq!(decoded.sealed, mr.sealed);
+    }
+    let bs = mr.encode(MetaEncoding::Bincode);
+    assert_eq!(MetaRecord::bincode_size(&bs), Some(bs.len()));
+    assert!(bs.len() < mr.encode(MetaEncoding::Json).len());
+    assert!(MetaRecord::decode(&bs[..bs.len() - 1]).is_err());
+    let mut bs = vec![b'\n'];
+    bs.extend(mr.encode(MetaEncoding::Json));
+    assert_eq!(MetaRecord::decode(&bs).unwrap(), mr);
 }
@@ -4,13 +4,25 @@ pub use data::DataMagicHeader;
 pub use data::DataRecordHeader;
 pub use data::DataRecord;
 
+pub mod format;
+pub use format::{FormatHeader, FormatVersion, FORMAT_FLAG_META_BINCODE};
+
 pub mod index;
 pub use index::IndexMagicHeader;
 pub use index::IndexRecord;
 
 pub mod meta;
-pub use meta::MetaMagicHeader;
 pub use meta::MetaRecord;
+pub use meta::{MetaEncoding, MetaMagicHeader};
+
+pub mod seal;
+pub use seal::StackSeal;
+
+pub mod names;
+pub use names::{NameEntry, NameIndexHeader};
+
+pub mod tombstone;
+pub use tombstone::Tombstone;
 
 pub mod stack;
 pub use stack::Stack;
\ No newline at end of file
@@ -0,0 +1,109 @@
+//! names will provide the filename index of a stack, which maps filename to IndexRecord.
+use serde::{Deserialize, Serialize};
+
+/// _NAME_INDEX_MAGIC is a magic number which identify this is a filename index file.
+const _NAME_INDEX_MAGIC: u64 = 0x4e41_4d45_5300;
+
+/// NAME_INDEX_FLAG_KEYED means name hashes are keyed by the stack key, it is set for stacks
+/// whose MetaRecords are encrypted so that the filename index does not leak filenames.
+pub const NAME_INDEX_FLAG_KEYED: u32 = 1;
+
+/// NameIndexHeader is at the beginning of filename index file, NameEntries sorted by name_hash follow it.
+/// # Note
+/// NameIndexHeader is serialized with bincode like this:
+/// `| name_index_magic: u64 | stack_id: u64 | entry_count: u64 | flags: u32 | entries_crc: u32 | (32 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct NameIndexHeader {
+    /// name_index_magic should always be _NAME_INDEX_MAGIC
+    name_index_magic: u64,
+    /// stack_id of the indexed stack
+    pub stack_id: u64,
+    /// entry_count is how many NameEntries follow the header
+    pub entry_count: u64,
+    /// flags of the filename index, see NAME_INDEX_FLAG_KEYED
+    pub flags: u32,
+    /// entries_crc is the crc of all NameEntries
+    pub entries_crc: u32,
+}
+
+impl NameIndexHeader {
+    /// new a NameIndexHeader
+    pub fn new(stack_id: u64, entry_count: u64, flags: u32, entries_crc: u32) -> Self {
+        NameIndexHeader {
+            name_index_magic: _NAME_INDEX_MAGIC,
+            stack_id,
+            entry_count,
+            flags,
+            entries_crc,
+        }
+    }
+
+    /// valid check if name_index_magic is _NAME_INDEX_MAGIC
+    pub fn valid(&self) -> bool {
+        self.name_index_magic == _NAME_INDEX_MAGIC
+    }
+
+    /// is_keyed check if name hashes are keyed
+    pub fn is_keyed(&self) -> bool {
+        self.flags & NAME_INDEX_FLAG_KEYED != 0
+    }
+
+    /// size return the size of NameIndexHeader serialized with bincode
+    pub fn size() -> usize {
+        32
+    }
+}
+
+/// NameEntry maps the hash of a filename to the position of its IndexRecord in index file.
+/// # Note
+/// Every entry is serialized with bincode like this: `| name_hash: [u8; 16] | position: u32 | (20 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct NameEntry {
+    /// name_hash is the first 16 bytes of blake3 hash of filename
+    pub name_hash: [u8; 16],
+    /// position is the index of IndexRecord in index file, 0 is the first record
+    pub position: u32,
+}
+
+impl NameEntry {
+    /// new a NameEntry
+    pub fn new(name_hash: [u8; 16], position: u32) -> Self {
+        NameEntry {
+            name_hash,
+            position,
+        }
+    }
+
+    /// size return the size of NameEntry serialized with bincode
+    pub fn size() -> usize {
+        20
+    }
+
+    /// new_from_bytes help deserialize NameEntry from &[u8]
+    pub fn new_from_bytes(data: &[u8]) -> Result<NameEntry, Box<bincode::ErrorKind>> {
+        assert!(data.len() == Self::size());
+        bincode::deserialize::<NameEntry>(data)
+    }
+}
+
+/// name_hash return the hash of filename saved in NameEntry, keyed by key if there is.
+pub fn name_hash(filename: &str, key: Option<&[u8; 32]>) -> [u8; 16] {
+    let hash = match key {
+        Some(key) => blake3::keyed_hash(key, filename.as_bytes()),
+        None => blake3::hash(filename.as_bytes()),
+    };
+    let mut out = [0; 16];
+    out.copy_from_slice(&hash.as_bytes()[..16]);
+    out
+}
+
+#[test]
+fn test_name_index_size() {
+    let header = NameIndexHeader::new(1, 2, NAME_INDEX_FLAG_KEYED, 3);
+    assert!(bincode::serialized_size(&header).unwrap() as usize == NameIndexHeader::size());
+    let entry = NameEntry::new(name_hash("a.jpg", None), 7);
+    let bs = bincode::serialize(&entry).unwrap();
+    assert!(bs.len() == NameEntry::size());
+    assert_eq!(NameEntry::new_from_bytes(&bs).unwrap(), entry);
+    assert_ne!(name_hash("a.jpg", None), name_hash("a.jpg", Some(&[1; 32])));
+}
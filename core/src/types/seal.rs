@@ -0,0 +1,77 @@
+//! seal will provide the seal record which marks a stack as complete.
+use serde::{Deserialize, Serialize};
+
+/// _STACK_SEAL_MAGIC is a magic number which identify this is a seal record.
+const _STACK_SEAL_MAGIC: u64 = 0x5345_414c_4544;
+
+/// StackSeal is written at the end of index, meta and data file when a stack is closed,
+/// a stack without it is still being written, crashed or truncated.
+/// # Note
+/// In index file StackSeal is serialized with bincode like this:
+/// `| seal_magic: u64 | stack_id: u64 | record_count: u64 | data_bytes: u64 | index_crc: u32 | meta_crc: u32 | (40 bytes)`
+/// In data file it is serialized the same way and padding to 4K, in meta file it is marshaled to json.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StackSeal {
+    /// seal_magic should always be _STACK_SEAL_MAGIC
+    seal_magic: u64,
+    /// stack_id of the sealed stack
+    pub stack_id: u64,
+    /// record_count is how many IndexRecords are in index file
+    pub record_count: u64,
+    /// data_bytes is the size of data file before the seal
+    pub data_bytes: u64,
+    /// index_crc is the crc of index file before the seal, magic header included
+    pub index_crc: u32,
+    /// meta_crc is the crc of meta file before the seal, magic header included
+    pub meta_crc: u32,
+}
+
+impl StackSeal {
+    /// new a StackSeal
+    pub fn new(
+        stack_id: u64,
+        record_count: u64,
+        data_bytes: u64,
+        index_crc: u32,
+        meta_crc: u32,
+    ) -> Self {
+        StackSeal {
+            seal_magic: _STACK_SEAL_MAGIC,
+            stack_id,
+            record_count,
+            data_bytes,
+            index_crc,
+            meta_crc,
+        }
+    }
+
+    /// valid check if seal_magic is _STACK_SEAL_MAGIC
+    pub fn valid(&self) -> bool {
+        self.seal_magic == _STACK_SEAL_MAGIC
+    }
+
+    /// has_magic check if data starts with a bincode serialized StackSeal
+    pub fn has_magic(data: &[u8]) -> bool {
+        data.len() >= 8 && u64::from_le_bytes(data[..8].try_into().unwrap()) == _STACK_SEAL_MAGIC
+    }
+
+    /// size return the size of StackSeal serialized with bincode
+    pub fn size() -> usize {
+        40
+    }
+
+    /// new_from_bytes help deserialize StackSeal from &[u8]
+    pub fn new_from_bytes(data: &[u8]) -> Result<StackSeal, Box<bincode::ErrorKind>> {
+        assert!(data.len() == Self::size());
+        bincode::deserialize::<StackSeal>(data)
+    }
+}
+
+#[test]
+fn test_stack_seal_size() {
+    let seal = StackSeal::new(1, 2, 3, 4, 5);
+    assert!(bincode::serialized_size(&seal).unwrap() as usize == StackSeal::size());
+    assert!(StackSeal::has_magic(&bincode::serialize(&seal).unwrap()));
+    // a sealed index file never looks like an index file only holding IndexRecords
+    assert!(StackSeal::size() % super::IndexRecord::size() != 0);
+}
@@ -0,0 +1,61 @@
+//! tombstone will provide the record which marks a record as deleted.
+use serde::{Deserialize, Serialize};
+
+/// _TOMBSTONE_MAGIC is a magic number which identify this is a tombstone.
+const _TOMBSTONE_MAGIC: u64 = 0x544f_4d42_5354;
+
+/// Tombstone is saved in the deletion log of a stack for every deleted record, a record
+/// with a tombstone is skipped by readers while its data stays in data file.
+/// # Note
+/// Tombstone is serialized with bincode like this:
+/// `| tombstone_magic: u64 | stack_id: u64 | offset_data: u64 | cookie: u32 | deleted_at: u64 | (36 bytes)`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Tombstone {
+    /// tombstone_magic should always be _TOMBSTONE_MAGIC
+    tombstone_magic: u64,
+    /// stack_id of the deleted record
+    pub stack_id: u64,
+    /// offset_data of the deleted record
+    pub offset_data: u64,
+    /// cookie of the deleted record
+    pub cookie: u32,
+    /// deleted_at is the unix timestamp when the record was deleted
+    pub deleted_at: u64,
+}
+
+impl Tombstone {
+    /// new a Tombstone
+    pub fn new(stack_id: u64, offset_data: u64, cookie: u32, deleted_at: u64) -> Self {
+        Tombstone {
+            tombstone_magic: _TOMBSTONE_MAGIC,
+            stack_id,
+            offset_data,
+            cookie,
+            deleted_at,
+        }
+    }
+
+    /// valid check if tombstone_magic is _TOMBSTONE_MAGIC
+    pub fn valid(&self) -> bool {
+        self.tombstone_magic == _TOMBSTONE_MAGIC
+    }
+
+    /// size return the size of Tombstone serialized with bincode
+    pub fn size() -> usize {
+        36
+    }
+
+    /// new_from_bytes help deserialize Tombstone from &[u8]
+    pub fn new_from_bytes(data: &[u8]) -> Result<Tombstone, Box<bincode::ErrorKind>> {
+        assert!(data.len() == Self::size());
+        bincode::deserialize::<Tombstone>(data)
+    }
+}
+
+#[test]
+fn test_tombstone_size() {
+    let tombstone = Tombstone::new(1, 4096, 2, 3);
+    let bs = bincode::serialize(&tombstone).unwrap();
+    assert!(bs.len() == Tombstone::size());
+    assert_eq!(Tombstone::new_from_bytes(&bs).unwrap(), tombstone);
+}
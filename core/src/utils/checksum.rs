@@ -0,0 +1,70 @@
+//! checksum provides utils to checksum data of records by the Checksum of stack
+use super::crc::CASTAGNOLI;
+use crate::types::data::Checksum;
+use std::hash::Hasher;
+use twox_hash::XxHash3_64;
+
+/// sum return checksum of data computed by checksum algorithm, crc is in little endian and xxh3 is
+/// in big endian like xxhsum prints.
+pub fn sum(checksum: Checksum, data: &[u8]) -> Vec<u8> {
+    match checksum {
+        Checksum::Crc32c => CASTAGNOLI.checksum(data).to_le_bytes().to_vec(),
+        Checksum::Xxh3 => XxHash3_64::oneshot(data).to_be_bytes().to_vec(),
+        Checksum::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+    }
+}
+
+/// Digest computes a checksum incrementally, its result is the same as sum of all updated bytes.
+pub enum Digest {
+    /// Crc32c digest
+    Crc32c(crc::Digest<'static, u32>),
+    /// Xxh3 hasher
+    Xxh3(Box<XxHash3_64>),
+    /// Blake3 hasher
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Digest {
+    /// new return an empty Digest of checksum algorithm
+    pub fn new(checksum: Checksum) -> Self {
+        match checksum {
+            Checksum::Crc32c => Digest::Crc32c(CASTAGNOLI.digest()),
+            Checksum::Xxh3 => Digest::Xxh3(Box::new(XxHash3_64::new())),
+            Checksum::Blake3 => Digest::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// update feed data into digest
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Digest::Crc32c(digest) => digest.update(data),
+            Digest::Xxh3(hasher) => hasher.write(data),
+            Digest::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// finalize return the checksum of all updated bytes
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Digest::Crc32c(digest) => digest.finalize().to_le_bytes().to_vec(),
+            Digest::Xxh3(hasher) => hasher.finish().to_be_bytes().to_vec(),
+            Digest::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+#[test]
+fn test_digest_matches_sum() {
+    let data: Vec<u8> = (0..10000).map(|i| (i * 7) as u8).collect();
+    for checksum in [Checksum::Crc32c, Checksum::Xxh3, Checksum::Blake3] {
+        let mut digest = Digest::new(checksum);
+        for part in data.chunks(999) {
+            digest.update(part);
+        }
+        let expected = sum(checksum, &data);
+        assert_eq!(expected.len(), checksum.size());
+        assert_eq!(digest.finalize(), expected);
+    }
+}
@@ -0,0 +1,38 @@
+//! compress provides utils to compress and decompress data of records
+use crate::types::data::Compression;
+
+/// compress data with codec, Compression::None return a copy of data.
+pub fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, String> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => zstd::bulk::compress(data, 0).map_err(|e| e.to_string()),
+        Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+    }
+}
+
+/// decompress data with codec, size is the size of original data.
+pub fn decompress(compression: Compression, data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let out = match compression {
+        Compression::None => data.to_vec(),
+        Compression::Zstd => zstd::bulk::decompress(data, size).map_err(|e| e.to_string())?,
+        Compression::Lz4 => lz4_flex::block::decompress(data, size).map_err(|e| e.to_string())?,
+    };
+    if out.len() != size {
+        return Err(format!(
+            "decompressed size {} mismatch, expected {}",
+            out.len(),
+            size
+        ));
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_compress_roundtrip() {
+    let data = "bytestack ".repeat(1000).into_bytes();
+    for c in [Compression::None, Compression::Zstd, Compression::Lz4] {
+        let compressed = compress(c, &data).unwrap();
+        assert_eq!(decompress(c, &compressed, data.len()).unwrap(), data);
+    }
+    assert!(decompress(Compression::Lz4, &[1, 2, 3], 10).is_err());
+}
@@ -14,17 +14,17 @@ pub struct IndexID {
 /// parse_index_id can help parse the index_id to struct IndexID
 pub fn parse_index_id(id: &str) -> Option<IndexID> {
     if let Some((stack_id, index_id)) = id.split_once(",") {
-        let stack_id = u64::from_str_radix(stack_id, 10).unwrap();
+        let stack_id = u64::from_str_radix(stack_id, 10).ok()?;
         let index_id_length = index_id.len();
-        if index_id_length < 8 || index_id_length > 24 {
+        if index_id_length < 8 || index_id_length > 24 || !index_id.is_ascii() {
             return None;
         }
         let cookie_str = &index_id[index_id_length - 8..];
         assert!(cookie_str.len() == 8);
         let file_offset_str = &index_id[..index_id_length - 8];
 
-        let cookie = u32::from_str_radix(cookie_str, 16).unwrap();
-        let offset_data = u64::from_str_radix(file_offset_str, 16).unwrap();
+        let cookie = u32::from_str_radix(cookie_str, 16).ok()?;
+        let offset_data = u64::from_str_radix(file_offset_str, 16).ok()?;
         return Some(IndexID {
             stack_id,
             offset_data,
@@ -37,6 +37,7 @@ pub fn parse_index_id(id: &str) -> Option<IndexID> {
 #[test]
 fn test_create_and_parse() {
     use crate::types::IndexRecord;
+    assert!(parse_index_id("100,zz0000000000").is_none());
     let ir = IndexRecord::new(12345, 2004, 3, 4, 5);
     let index_id = format!("{},{}", 100, ir.index_id());
     let parse_index_id = parse_index_id(&index_id).unwrap();
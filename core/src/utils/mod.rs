@@ -11,5 +11,9 @@ pub use time::*;
 pub mod crc;
 pub use self::crc::CASTAGNOLI;
 
+pub mod checksum;
+
+pub mod compress;
+
 mod log;
 pub use self::log::init_logger;
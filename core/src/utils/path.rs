@@ -14,6 +14,35 @@ pub fn get_index_file_path(prefix: &str, stack_id: u64) -> String {
 pub fn get_meta_file_path(prefix: &str, stack_id: u64) -> String {
     format!("{}0x{:04x}.meta", prefix, stack_id)
 }
+/// get_names_file_path return the filename index path of giving prefix and stack_id
+pub fn get_names_file_path(prefix: &str, stack_id: u64) -> String {
+    format!("{}0x{:04x}.names", prefix, stack_id)
+}
+/// get_deletion_log_path return the deletion log of giving prefix and stack_id, it is a directory
+/// with one tombstone file for every deleted record.
+pub fn get_deletion_log_path(prefix: &str, stack_id: u64) -> String {
+    format!("{}0x{:04x}.del/", prefix, stack_id)
+}
+/// get_tombstone_file_path return the tombstone path of record with offset_data and cookie in stack_id,
+/// the file name is the same as the index_id without stack_id.
+pub fn get_tombstone_file_path(
+    prefix: &str,
+    stack_id: u64,
+    offset_data: u64,
+    cookie: u32,
+) -> String {
+    format!(
+        "{}{:x}{:08x}",
+        get_deletion_log_path(prefix, stack_id),
+        offset_data,
+        cookie
+    )
+}
+
+/// parse_deletion_log_stack_id return stack_id of a deletion log, dir_name ends with `.del/`.
+pub fn parse_deletion_log_stack_id(dir_name: &str) -> Option<u64> {
+    parse_file_stack_id(dir_name, ".del/")
+}
 
 pub fn parse_index_stack_id(file_name: &str) -> Option<u64> {
     return parse_file_stack_id(file_name, ".idx");
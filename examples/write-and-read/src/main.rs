@@ -1,4 +1,5 @@
 use bytestack::{config, sdk};
+use futures::StreamExt;
 #[tokio::main]
 async fn main() {
     let config = sdk::Config {
@@ -9,10 +10,13 @@ async fn main() {
             endpoint: "http://localhost:9000".to_string(),
             region: "default".to_string(),
         },
+        ..Default::default()
     };
     let handler = sdk::Handler::new(config).await;
 
-    let mut bw = handler.open_writer("s3://test/dadadad.bs/").unwrap();
+    let bw = handler
+        .open_writer("s3://test/dadadad.bs/", sdk::WriterOptions::default())
+        .unwrap();
     let mut idx: i32 = 0;
     while idx < 2000 {
         let content = vec![(idx % 124) as u8; 4096];
@@ -34,7 +38,8 @@ async fn main() {
     }
     for s in &stack_list {
         let mut iter = br.list_stack_al_iter(s.stack_id).await.unwrap();
-        while let Some((ir, _mr)) = iter.next().await {
+        while let Some(res) = iter.next().await {
+            let (ir, _mr) = res.expect("read meta");
             let index_id = format!("{},{}", s.stack_id, ir.index_id());
             let _data = match br.fetch(&index_id, true).await {
                 Ok(data) => data,
@@ -48,7 +53,8 @@ async fn main() {
 
     for s in &stack_list {
         let mut iter = br.list_stack_al_with_data_iter(s.stack_id).await.unwrap();
-        while let Some((ir, _mr, data)) = iter.next().await {
+        while let Some(res) = iter.next().await {
+            let (ir, _mr, data) = res.expect("read data");
             assert!(ir.size_data as usize == data.len())
         }
     }